@@ -0,0 +1,44 @@
+//! Debugs an assembly program from the terminal:
+//! `cargo run --example debugger -- program.s`, or from GDB with
+//! `cargo run --example debugger -- program.s --gdb 1234` and
+//! `target remote :1234`
+
+use smpl_core_common::{
+    asm::assemble_named,
+    debug::Debugger,
+    emu::{Machine, Memory},
+    utils::Result,
+};
+
+/// Prints how to run the example and exits
+fn usage() -> ! {
+    eprintln!("usage: debugger <program.s> [--gdb <port>]");
+    std::process::exit(2)
+}
+
+fn main() -> Result<()> {
+    let args = std::env::args().collect::<Vec<_>>();
+    let Some(path) = args.get(1) else {
+        usage();
+    };
+    let source = std::fs::read_to_string(path)?;
+    let assembly = assemble_named(path, &source)?;
+
+    // The stack starts at the middle of memory
+    let mut machine = Machine::new(Memory::with(0, &assembly.image));
+    machine.cpu.rsb = 0x8000;
+    machine.cpu.rsh = 0x8000;
+
+    let mut debugger = Debugger::new(machine).with_symbols(assembly.symbols);
+    match args.get(2..) {
+        Some([flag, port]) if flag == "--gdb" => {
+            let Ok(port) = port.parse::<u16>() else {
+                usage();
+            };
+            let listener = std::net::TcpListener::bind(("127.0.0.1", port))?;
+            debugger.serve_gdb(listener.accept()?.0)
+        },
+        Some([]) => debugger.repl(std::io::stdin().lock(), std::io::stdout()),
+        _ => usage(),
+    }
+}
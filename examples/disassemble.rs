@@ -0,0 +1,48 @@
+//! Prints the listing of a flat image, loaded at address 0:
+//! `cargo run --example disassemble -- image.bin [symbols.map] [--traverse | --dot]`,
+//! `--traverse` telling code from data by following execution from the
+//! reset vector, and `--dot` printing its control flow graph for Graphviz
+
+use smpl_core_common::{
+    disasm::{read_symbols, Disassembler},
+    utils::Result,
+};
+
+/// Prints how to run the example, after what was wrong, and exits
+fn usage(problem : Option<&str>) -> ! {
+    if let Some(problem) = problem {
+        eprintln!("{problem}");
+    }
+    eprintln!("usage: disassemble <image.bin> [symbols.map] [--traverse | --dot]");
+    std::process::exit(2)
+}
+
+fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>();
+    let flag = args.iter().find(|arg| arg.starts_with("--")).cloned();
+    args.retain(|arg| !arg.starts_with("--"));
+
+    let Some(path) = args.first() else {
+        usage(None);
+    };
+    let image = std::fs::read(path)?;
+    let symbols = match args.get(1) {
+        Some(path) => read_symbols(&std::fs::read_to_string(path)?)?,
+        None => vec![],
+    };
+
+    let disassembler = Disassembler::new(&image).with_symbols(symbols);
+    match flag.as_deref() {
+        Some("--traverse") => {
+            let traversal = disassembler.traverse();
+            print!("{}", disassembler.render(&traversal.lines));
+            for ambiguity in traversal.ambiguities {
+                eprintln!("warning: {ambiguity}");
+            }
+        },
+        Some("--dot") => print!("{}", disassembler.dot(&disassembler.cfg())),
+        Some(flag) => usage(Some(&format!("unknown option {flag}"))),
+        None => print!("{}", disassembler.listing()),
+    }
+    Ok(())
+}
@@ -0,0 +1,161 @@
+use crate::utils::Error;
+
+use super::line::is_identifier;
+
+/// An assembly error together with where it happened
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub error : Error,
+    pub file : String,
+
+    /// Line number, starting at 1
+    pub line : usize,
+
+    /// Column of the offending part of the line in characters, starting at 1
+    pub column : usize,
+
+    /// Length of the offending part of the line in characters
+    pub len : usize,
+
+    /// Text of the line, after macro expansion
+    pub snippet : String,
+}
+
+fn is_ident_char(c : u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_'
+}
+
+/// Finds `needle` in `haystack` ignoring case, as a whole word if it's an identifier
+fn find(haystack : &str, needle : &str) -> Option<usize> {
+    let haystack = haystack.to_ascii_lowercase();
+    let needle = needle.to_ascii_lowercase();
+    let bytes = haystack.as_bytes();
+
+    haystack.match_indices(&needle).map(|(idx, _)| idx).find(|idx| {
+        let end = idx + needle.len();
+        !is_identifier(&needle)
+            || (*idx == 0 || !is_ident_char(bytes[idx - 1])) && (end == bytes.len() || !is_ident_char(bytes[end]))
+    })
+}
+
+impl Diagnostic {
+    /// Builds the diagnostic for `error` at the line with index `line`,
+    /// pointing to the part of `snippet` the error refers to, or to the whole
+    /// line if it can't be told
+    pub fn new(error : Error, file : &str, line : usize, snippet : &str) -> Self {
+        use Error::*;
+
+        let part = match &error {
+            InvalidRegister(s) | InvalidValue(s) | InvalidExpression(s) | InvalidString(s) |
+            UnknownMnemonic(s) | UnknownDirective(s) |
+            InvalidLabel(s) | DuplicateSymbol(s) | UndefinedSymbol(s) | NotRelocatable(s) | InvalidSection(s) |
+            InvalidMacro(s) | UndefinedParameter(s) | MacroRecursion(s) | UnterminatedBlock(s) | UnexpectedEnd(s)
+                => find(snippet, s).map(|idx| (idx, s.len())),
+            _ => None,
+        };
+        let (start, len) = part.unwrap_or_else(|| {
+            let trimmed = snippet.trim();
+            (snippet.len() - snippet.trim_start().len(), trimmed.len())
+        });
+        let column = snippet[..start].chars().count() + 1;
+        let len = snippet[start..start + len].chars().count();
+
+        Self { error, file: file.to_string(), line: line + 1, column, len, snippet: snippet.to_string() }
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let gutter = " ".repeat(self.line.to_string().len());
+        // Keep tabs so the carets line up with the snippet
+        let indent = self.snippet.chars().take(self.column - 1).map(|c| if c == '\t' { '\t' } else { ' ' }).collect::<String>();
+
+        writeln!(f, "error: {}", self.error)?;
+        writeln!(f, "{gutter}--> {}:{}:{}", self.file, self.line, self.column)?;
+        writeln!(f, "{gutter} |")?;
+        writeln!(f, "{} | {}", self.line, self.snippet)?;
+        write!(f, "{gutter} | {indent}{}", "^".repeat(self.len.max(1)))
+    }
+}
+
+/// Every error of an assembly run
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostics(pub Vec<Diagnostic>);
+
+impl std::fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (idx, diagnostic) in self.0.iter().enumerate() {
+            if idx > 0 {
+                writeln!(f)?;
+                writeln!(f)?;
+            }
+            write!(f, "{diagnostic}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn location() {
+        let d = Diagnostic::new(Error::UndefinedSymbol("end".to_string()), "main.s", 2, "    mov end, r0 ; the end");
+        assert_eq!((d.line, d.column, d.len), (3, 9, 3));
+
+        let d = Diagnostic::new(Error::UndefinedSymbol("a".to_string()), "main.s", 0, "ab: mov a, r0");
+        assert_eq!((d.column, d.len), (9, 1));
+
+        let d = Diagnostic::new(Error::UnknownMnemonic("foo".to_string()), "main.s", 0, "  FOO r0");
+        assert_eq!((d.column, d.len), (3, 3));
+
+        let d = Diagnostic::new(Error::InvalidValue("256".to_string()), "main.s", 0, "  .db 0x100  ");
+        assert_eq!((d.column, d.len), (3, 9));
+
+        let d = Diagnostic::new(Error::UndefinedSymbol("end".to_string()), "main.s", 0, "  .db 'ü', 'é', end");
+        assert_eq!((d.column, d.len), (17, 3));
+
+        let d = Diagnostic::new(Error::InvalidValue("x".to_string()), "main.s", 0, " .db 'é'");
+        assert_eq!((d.column, d.len), (2, 7));
+    }
+
+    #[test]
+    fn render() {
+        let d = Diagnostic::new(Error::UndefinedSymbol("nowhere".to_string()), "main.s", 9, "\tcall nowhere");
+        assert_eq!(d.to_string(), "\
+error: undefined symbol: \"nowhere\"
+  --> main.s:10:7
+   |
+10 | \tcall nowhere
+   | \t     ^^^^^^^");
+
+        let d = Diagnostic::new(Error::UndefinedSymbol("end".to_string()), "main.s", 0, ".db 'é', end");
+        assert_eq!(d.to_string(), "\
+error: undefined symbol: \"end\"
+ --> main.s:1:10
+  |
+1 | .db 'é', end
+  |          ^^^");
+    }
+
+    #[test]
+    fn render_many() {
+        let diagnostics = Diagnostics(vec![
+            Diagnostic::new(Error::UnknownMnemonic("foo".to_string()), "a.s", 0, "foo"),
+            Diagnostic::new(Error::UnknownMnemonic("bar".to_string()), "a.s", 1, "bar"),
+        ]);
+        assert_eq!(diagnostics.to_string(), "\
+error: unknown mnemonic: \"foo\"
+ --> a.s:1:1
+  |
+1 | foo
+  | ^^^
+
+error: unknown mnemonic: \"bar\"
+ --> a.s:2:1
+  |
+2 | bar
+  | ^^^");
+    }
+}
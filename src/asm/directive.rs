@@ -0,0 +1,134 @@
+use crate::{object::Section, utils::{Error, Result}};
+
+use super::{expr::Base, line::{parse_string, Line}, Context, Pending, Symbol};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Directive {
+    Org, Db, Dw, Ascii, Asciz, Fill, Align, Equ,
+    Text, Data, Bss, Global, Extern,
+}
+
+impl Directive {
+    pub fn from_name(name : &str) -> Option<Self> {
+        match &*name.to_lowercase() {
+            ".org" => Some(Self::Org),
+            ".db" => Some(Self::Db),
+            ".dw" => Some(Self::Dw),
+            ".ascii" => Some(Self::Ascii),
+            ".asciz" => Some(Self::Asciz),
+            ".fill" => Some(Self::Fill),
+            ".align" => Some(Self::Align),
+            ".equ" => Some(Self::Equ),
+            ".text" => Some(Self::Text),
+            ".data" => Some(Self::Data),
+            ".bss" => Some(Self::Bss),
+            ".global" => Some(Self::Global),
+            ".extern" => Some(Self::Extern),
+            _ => None,
+        }
+    }
+
+    /// Section a section directive switches to
+    pub fn section(&self) -> Option<Section> {
+        match self {
+            Self::Text => Some(Section::Text),
+            Self::Data => Some(Section::Data),
+            Self::Bss => Some(Section::Bss),
+            _ => None,
+        }
+    }
+
+    /// Bytes emitted by a data directive at the current offset of `context`,
+    /// adding the fields that must be relocated to `relocations`
+    pub fn data(&self, line : &Line, source : &str, context : &Context, relocations : &mut Vec<Pending>) -> Result<Vec<u8>> {
+        let invalid = || Error::InvalidOperands(source.trim().to_string());
+
+        match (self, &line.operands[..]) {
+            (Self::Db, operands) if !operands.is_empty() => {
+                let mut res = vec![];
+                for operand in operands {
+                    if operand.starts_with('"') {
+                        res.extend(parse_string(operand, '"')?);
+                    } else {
+                        res.push(byte(context.constant(operand)?.0)?);
+                    }
+                }
+                Ok(res)
+            },
+            (Self::Dw, operands) if !operands.is_empty() => {
+                let mut res = vec![];
+                for operand in operands {
+                    let expr = context.value(operand)?;
+                    match context.relocation(&expr, context.offset + res.len() as u16, operand)? {
+                        Some(relocation) => {
+                            relocations.push(relocation);
+                            res.extend([0, 0]);
+                        },
+                        None => res.extend(word(context.constant(operand)?.0)?.to_le_bytes()),
+                    }
+                }
+                Ok(res)
+            },
+            (Self::Ascii, [s]) => parse_string(s, '"'),
+            (Self::Asciz, [s]) => parse_string(s, '"').map(|mut s| {
+                s.push(0);
+                s
+            }),
+            (Self::Fill, [count]) => Ok(vec![0; count_of(context.exact(count)?)?]),
+            (Self::Fill, [count, value]) => Ok(vec![byte(context.constant(value)?.0)?; count_of(context.exact(count)?)?]),
+            (Self::Align, [n]) => match context.exact(n)? {
+                n @ 1..=0x10000 => Ok(vec![0; (n as usize - context.offset as usize % n as usize) % n as usize]),
+                n => Err(Error::InvalidValue(n.to_string())),
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+fn byte(value : i32) -> Result<u8> {
+    (-0x80..=0xFF).contains(&value).then_some(value as u8).ok_or(Error::InvalidValue(value.to_string()))
+}
+
+fn word(value : i32) -> Result<u16> {
+    (-0x8000..=0xFFFF).contains(&value).then_some(value as u16).ok_or(Error::InvalidValue(value.to_string()))
+}
+
+fn count_of(value : i32) -> Result<usize> {
+    (0..=0x10000).contains(&value).then_some(value as usize).ok_or(Error::InvalidValue(value.to_string()))
+}
+
+fn offset_of(value : i32) -> Result<u32> {
+    (0..=0xFFFF).contains(&value).then_some(value as u32).ok_or(Error::InvalidValue(value.to_string()))
+}
+
+/// Value of an expression that must be known right away, as an offset into a
+/// section or absolute
+fn symbol(s : &str, context : &Context) -> Result<Symbol> {
+    let expr = context.value(s)?;
+    match expr.terms[..] {
+        [] => Ok(Symbol { section: None, value: expr.value }),
+        [(Base::Section(section), 1)] => Ok(Symbol { section: Some(section), value: expr.value }),
+        _ => Err(Error::NotRelocatable(s.to_string())),
+    }
+}
+
+/// Offset of an `.org` inside the current section
+pub(super) fn origin(line : &Line, source : &str, context : &Context) -> Result<u32> {
+    let context = Context { complete: true, ..*context };
+    match &line.operands[..] {
+        [offset] => match symbol(offset, &context)? {
+            Symbol { section: None, value } => offset_of(value),
+            Symbol { section: Some(section), value } if section == context.section => offset_of(value),
+            _ => Err(Error::NotRelocatable(offset.to_string())),
+        },
+        _ => Err(Error::InvalidOperands(source.trim().to_string())),
+    }
+}
+
+/// Name and value of an `.equ`
+pub(super) fn equ<'a>(line : &Line<'a>, source : &str, context : &Context) -> Result<(&'a str, Symbol)> {
+    match &line.operands[..] {
+        [name, value] => Ok((name, symbol(value, &Context { complete: true, ..*context })?)),
+        _ => Err(Error::InvalidOperands(source.trim().to_string())),
+    }
+}
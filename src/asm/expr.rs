@@ -0,0 +1,381 @@
+use crate::{instruction::parse_literal, object::Section, utils::{Error, Result}, Width};
+
+use super::{line::parse_string, Context};
+
+/// Address a value is relative to, not known until linking
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(super) enum Base {
+    Section(Section),
+    Extern(String),
+}
+
+/// Result of an expression, `value` plus multiples of addresses not known yet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Expr {
+    pub value : i32,
+
+    /// Width implied by how a literal was written, if any
+    pub hint : Option<Width>,
+    pub terms : Vec<(Base, i32)>,
+}
+
+impl Expr {
+    pub fn constant(value : i32, hint : Option<Width>) -> Self {
+        Self { value, hint, terms: vec![] }
+    }
+
+    pub fn relative(base : Base, value : i32) -> Self {
+        Self { value, hint: None, terms: vec![(base, 1)] }
+    }
+
+    pub fn is_constant(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    /// Adds `factor` times `other` to `self`
+    fn add(mut self, other : Expr, factor : i32) -> Option<Self> {
+        self.value = self.value.checked_add(other.value.checked_mul(factor)?)?;
+        for (base, coefficient) in other.terms {
+            match self.terms.iter_mut().find(|(b, _)| *b == base) {
+                Some((_, c)) => *c += coefficient * factor,
+                None => self.terms.push((base, coefficient * factor)),
+            }
+        }
+        self.terms.retain(|(_, c)| *c != 0);
+        self.hint = None;
+        Some(self)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token<'a> {
+    Number(i32, Option<Width>),
+    Ident(&'a str),
+    Dot,
+    Op(&'static str),
+    Open,
+    Close,
+}
+
+const OPS : &[&str] = &["<<", ">>", "+", "-", "*", "/", "%", "&", "|", "^", "~"];
+
+/// Binary operators from lowest to highest precedence
+const LEVELS : &[&[&str]] = &[&["|"], &["^"], &["&"], &["<<", ">>"], &["+", "-"], &["*", "/", "%"]];
+
+fn tokenize(s : &str) -> Result<Vec<Token<'_>>> {
+    let invalid = || Error::InvalidExpression(s.to_string());
+
+    let mut res = vec![];
+    let mut rest = s.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_ascii_digit() {
+            let len = rest.find(|c : char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len());
+            let (value, hint) = parse_literal(&rest[..len])?;
+            res.push(Token::Number(value, hint));
+            len
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let len = rest.find(|c : char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len());
+            res.push(Token::Ident(&rest[..len]));
+            len
+        } else if c == '\'' {
+            let mut escaped = false;
+            let len = rest.char_indices().skip(1).find(|(_, c)| {
+                let end = !escaped && *c == '\'';
+                escaped = !escaped && *c == '\\';
+                end
+            }).ok_or_else(invalid)?.0 + 1;
+            match parse_string(&rest[..len], '\'')?[..] {
+                [c] => res.push(Token::Number(c as i32, Some(Width::Byte))),
+                _ => return Err(Error::InvalidValue(rest[..len].to_string())),
+            }
+            len
+        } else if c == '.' {
+            res.push(Token::Dot);
+            1
+        } else if c == '(' {
+            res.push(Token::Open);
+            1
+        } else if c == ')' {
+            res.push(Token::Close);
+            1
+        } else {
+            let op = OPS.iter().find(|op| rest.starts_with(**op)).ok_or_else(invalid)?;
+            res.push(Token::Op(op));
+            op.len()
+        };
+        rest = rest[len..].trim_start();
+    }
+    Ok(res)
+}
+
+struct Parser<'a, 'c> {
+    source : &'a str,
+    tokens : Vec<Token<'a>>,
+    pos : usize,
+    context : &'a Context<'c>,
+
+    /// Whether a symbol that isn't defined yet was used, in which case the
+    /// result is meaningless and errors are deferred to the final pass
+    unresolved : bool,
+}
+
+impl<'a> Parser<'a, '_> {
+    fn invalid(&self) -> Error {
+        Error::InvalidExpression(self.source.to_string())
+    }
+
+    fn peek(&self) -> Option<&Token<'a>> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        let res = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        res
+    }
+
+    fn expect(&mut self, token : Token<'a>) -> Result<()> {
+        match self.next() {
+            Some(next) if next == token => Ok(()),
+            _ => Err(self.invalid()),
+        }
+    }
+
+    fn apply(&self, op : &str, lhs : Expr, rhs : Expr) -> Result<Expr> {
+        let res = match op {
+            "+" => lhs.add(rhs, 1),
+            "-" => lhs.add(rhs, -1),
+            // Addresses that aren't known yet can only be added or subtracted
+            _ if !lhs.is_constant() || !rhs.is_constant() => match self.unresolved || !self.context.complete {
+                true => Some(Expr::constant(0, None)),
+                false => return Err(Error::NotRelocatable(self.source.to_string())),
+            },
+            _ => Self::arithmetic(op, lhs.value, rhs.value).map(|value| Expr::constant(value, None)),
+        };
+
+        match res {
+            Some(res) => Ok(res),
+            None if self.unresolved => Ok(Expr::constant(0, None)),
+            None => Err(self.invalid()),
+        }
+    }
+
+    fn arithmetic(op : &str, lhs : i32, rhs : i32) -> Option<i32> {
+        match op {
+            "+" => lhs.checked_add(rhs),
+            "-" => lhs.checked_sub(rhs),
+            "*" => lhs.checked_mul(rhs),
+            "/" => lhs.checked_div(rhs),
+            "%" => lhs.checked_rem(rhs),
+            "<<" => u32::try_from(rhs).ok().and_then(|rhs| lhs.checked_shl(rhs)),
+            ">>" => u32::try_from(rhs).ok().and_then(|rhs| lhs.checked_shr(rhs)),
+            "&" => Some(lhs & rhs),
+            "|" => Some(lhs | rhs),
+            "^" => Some(lhs ^ rhs),
+            _ => None,
+        }
+    }
+
+    /// Constant operand of a unary operator or function
+    fn constant(&self, expr : Expr) -> Result<i32> {
+        match expr.is_constant() || self.unresolved || !self.context.complete {
+            true => Ok(if expr.is_constant() { expr.value } else { 0 }),
+            false => Err(Error::NotRelocatable(self.source.to_string())),
+        }
+    }
+
+    fn binary(&mut self, level : usize) -> Result<Expr> {
+        let Some(ops) = LEVELS.get(level) else {
+            return self.unary();
+        };
+
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(Token::Op(op)) = self.peek().cloned() {
+            if !ops.contains(&op) {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.binary(level + 1)?;
+            lhs = self.apply(op, lhs, rhs)?;
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        match self.peek() {
+            Some(Token::Op("-")) => {
+                self.pos += 1;
+                let expr = self.unary()?;
+                let hint = expr.hint;
+                Ok(Expr { hint, ..self.apply("-", Expr::constant(0, None), expr)? })
+            },
+            Some(Token::Op("+")) => {
+                self.pos += 1;
+                self.unary()
+            },
+            Some(Token::Op("~")) => {
+                self.pos += 1;
+                let expr = self.unary()?;
+                Ok(Expr::constant(!self.constant(expr)?, None))
+            },
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(Token::Number(value, hint)) => Ok(Expr::constant(value, hint)),
+            Some(Token::Dot) => Ok(self.context.label(Some(self.context.section), self.context.offset as i32)),
+            Some(Token::Open) => {
+                let res = self.binary(0)?;
+                self.expect(Token::Close)?;
+                Ok(res)
+            },
+            Some(Token::Ident(name @ ("lo" | "hi"))) if self.peek() == Some(&Token::Open) => {
+                self.pos += 1;
+                let expr = self.binary(0)?;
+                self.expect(Token::Close)?;
+                let value = self.constant(expr)?;
+                let byte = if name == "lo" { value } else { value >> 8 };
+                Ok(Expr::constant(byte & 0xFF, Some(Width::Byte)))
+            },
+            Some(Token::Ident(name)) => match self.context.symbol(name) {
+                Some(expr) => Ok(expr),
+                None if !self.context.complete => {
+                    self.unresolved = true;
+                    Ok(Expr::constant(0, None))
+                },
+                None => Err(Error::UndefinedSymbol(name.to_string())),
+            },
+            _ => Err(self.invalid()),
+        }
+    }
+}
+
+/// Evaluates an expression, which is 0 if it uses symbols not defined yet
+pub(super) fn eval(s : &str, context : &Context) -> Result<Expr> {
+    let mut parser = Parser { source: s, tokens: tokenize(s)?, pos: 0, context, unresolved: false };
+    let res = parser.binary(0)?;
+    if parser.pos != parser.tokens.len() {
+        return Err(parser.invalid());
+    }
+
+    Ok(if parser.unresolved { Expr::constant(0, None) } else { res })
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeMap, BTreeSet};
+
+    use crate::asm::Symbol;
+
+    use super::*;
+
+    fn symbols() -> BTreeMap<String, Symbol> {
+        let absolute = |value| Symbol { section: None, value };
+        let text = |value| Symbol { section: Some(Section::Text), value };
+        let data = |value| Symbol { section: Some(Section::Data), value };
+        BTreeMap::from([
+            ("TABLE".to_string(), absolute(0x1234)),
+            ("WIDTH".to_string(), absolute(16)),
+            ("start".to_string(), text(0x10)),
+            ("end".to_string(), text(0x30)),
+            ("buffer".to_string(), data(0x04)),
+        ])
+    }
+
+    /// Evaluates `s` at offset 0x0100 of the text section, placed at `base` if known
+    fn eval_at(s : &str, complete : bool, base : Option<u16>) -> Result<Expr> {
+        let symbols = symbols();
+        let externs = BTreeSet::from(["putc".to_string()]);
+        let context = Context { symbols: &symbols, externs: &externs, bases: [base, None, None], complete, section: Section::Text, offset: 0x0100 };
+        eval(s, &context)
+    }
+
+    fn eval_with(s : &str, complete : bool) -> Result<(i32, Option<Width>)> {
+        let expr = eval_at(s, complete, Some(0))?;
+        assert!(expr.is_constant(), "{s}");
+        Ok((expr.value, expr.hint))
+    }
+
+    fn value(s : &str) -> Result<i32> {
+        eval_with(s, true).map(|(value, _)| value)
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(eval_with("0x12", true), Ok((0x12, Some(Width::Byte))));
+        assert_eq!(eval_with("0x0012", true), Ok((0x12, Some(Width::Word))));
+        assert_eq!(eval_with("-0x0001", true), Ok((-1, Some(Width::Word))));
+        assert_eq!(eval_with("18", true), Ok((18, None)));
+        assert_eq!(eval_with("'A'", true), Ok((0x41, Some(Width::Byte))));
+        assert_eq!(eval_with("'\\''", true), Ok((0x27, Some(Width::Byte))));
+        assert_eq!(eval_with("(0x0012)", true), Ok((0x12, Some(Width::Word))));
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(value("1 + 2 * 3"), Ok(7));
+        assert_eq!(value("(1 + 2) * 3"), Ok(9));
+        assert_eq!(value("10 - 4 - 3"), Ok(3));
+        assert_eq!(value("17 / 5"), Ok(3));
+        assert_eq!(value("17 % 5"), Ok(2));
+        assert_eq!(value("-3 + +4"), Ok(1));
+    }
+
+    #[test]
+    fn bitwise() {
+        assert_eq!(value("(TABLE + 4) & 0xFF"), Ok(0x38));
+        assert_eq!(value("0x0F | 0xF0 ^ 0xFF"), Ok(0x0F));
+        assert_eq!(value("1 << 4 + 1"), Ok(32));
+        assert_eq!(value("-16 >> 2"), Ok(-4));
+        assert_eq!(value("~0 & 0xFFFF"), Ok(0xFFFF));
+        assert_eq!(value("1 | 2 & 3"), Ok(3));
+    }
+
+    #[test]
+    fn symbols_and_functions() {
+        assert_eq!(value("WIDTH-1"), Ok(15));
+        assert_eq!(value("end - start"), Ok(0x20));
+        assert_eq!(value(". + 2"), Ok(0x0102));
+        assert_eq!(eval_with("lo(TABLE)", true), Ok((0x34, Some(Width::Byte))));
+        assert_eq!(eval_with("hi(TABLE)", true), Ok((0x12, Some(Width::Byte))));
+        assert_eq!(value("hi(TABLE) + lo(TABLE)"), Ok(0x46));
+    }
+
+    #[test]
+    fn unresolved() {
+        assert_eq!(eval_with("later * 2", false), Ok((0, None)));
+        assert_eq!(eval_with("TABLE / later", false), Ok((0, None)));
+        assert_eq!(eval_with("later", true), Err(Error::UndefinedSymbol("later".to_string())));
+    }
+
+    #[test]
+    fn relocatable() {
+        let text = |value| Expr::relative(Base::Section(Section::Text), value);
+        assert_eq!(eval_at("start + 2", true, None), Ok(text(0x12)));
+        assert_eq!(eval_at("end - start", true, None), Ok(Expr::constant(0x20, None)));
+        assert_eq!(eval_at(". - 4", true, None), Ok(text(0xFC)));
+        assert_eq!(eval_at("putc", true, None), Ok(Expr::relative(Base::Extern("putc".to_string()), 0)));
+        assert_eq!(
+            eval_at("buffer - .", true, None),
+            Ok(Expr { value: -0xFC, hint: None, terms: vec![(Base::Section(Section::Data), 1), (Base::Section(Section::Text), -1)] })
+        );
+        assert_eq!(eval_at("buffer - .", true, Some(0x8000)).map(|expr| expr.terms.len()), Ok(1));
+
+        assert_eq!(eval_at("start * 2", true, None), Err(Error::NotRelocatable("start * 2".to_string())));
+        assert_eq!(eval_at("hi(putc)", true, None), Err(Error::NotRelocatable("hi(putc)".to_string())));
+        assert_eq!(eval_at("start * 2", false, None), Ok(Expr::constant(0, None)));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(value("1 +"), Err(Error::InvalidExpression("1 +".to_string())));
+        assert_eq!(value("(1"), Err(Error::InvalidExpression("(1".to_string())));
+        assert_eq!(value("1 2"), Err(Error::InvalidExpression("1 2".to_string())));
+        assert_eq!(value("1 / 0"), Err(Error::InvalidExpression("1 / 0".to_string())));
+        assert_eq!(value("1 << 40"), Err(Error::InvalidExpression("1 << 40".to_string())));
+        assert_eq!(value("1 $ 2"), Err(Error::InvalidExpression("1 $ 2".to_string())));
+        assert_eq!(value("0xZZ"), Err(Error::InvalidValue("0xZZ".to_string())));
+    }
+}
@@ -0,0 +1,167 @@
+use crate::utils::{Error, Result};
+
+/// A source line split into its parts, without comments
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Line<'a> {
+    pub labels : Vec<&'a str>,
+    pub mnemonic : Option<&'a str>,
+    pub operands : Vec<&'a str>,
+}
+
+pub(crate) fn is_identifier(s : &str) -> bool {
+    let mut chars = s.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Calls `f` with the byte index and character of everything outside of
+/// string and character literals
+fn unquoted(s : &str, mut f : impl FnMut(usize, char) -> bool) {
+    let mut quote = None;
+    let mut escaped = false;
+    for (idx, c) in s.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => (),
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None => if !f(idx, c) {
+                return;
+            },
+        }
+    }
+}
+
+fn strip_comment(s : &str) -> &str {
+    let mut end = s.len();
+    unquoted(s, |idx, c| {
+        if c == ';' {
+            end = idx;
+            false
+        } else {
+            true
+        }
+    });
+    &s[..end]
+}
+
+/// Splits at the commas that aren't inside literals or parentheses
+pub(crate) fn split_operands(s : &str) -> Vec<&str> {
+    if s.trim().is_empty() {
+        return vec![];
+    }
+
+    let mut res = vec![];
+    let mut depth = 0i32;
+    let mut start = 0;
+    unquoted(s, |idx, c| {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                res.push(s[start..idx].trim());
+                start = idx + 1;
+            },
+            _ => (),
+        }
+        true
+    });
+    res.push(s[start..].trim());
+    res
+}
+
+/// Parses the contents of a string or character literal, `quote` included
+pub(crate) fn parse_string(s : &str, quote : char) -> Result<Vec<u8>> {
+    let invalid = || Error::InvalidString(s.to_string());
+    let inner = s.strip_prefix(quote).and_then(|s| s.strip_suffix(quote)).filter(|_| s.len() >= 2).ok_or_else(invalid)?;
+
+    let mut res = vec![];
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next().ok_or_else(invalid)? {
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                '0' => '\0',
+                'x' => {
+                    let hex = chars.next().zip(chars.next()).ok_or_else(invalid)?;
+                    let hex = [hex.0, hex.1].iter().collect::<String>();
+                    res.push(u8::from_str_radix(&hex, 16).map_err(|_| invalid())?);
+                    continue;
+                },
+                c @ ('\\' | '"' | '\'') => c,
+                _ => return Err(invalid()),
+            },
+            c if c == quote => return Err(invalid()),
+            c if c.is_ascii() => c,
+            _ => return Err(invalid()),
+        };
+        res.push(c as u8);
+    }
+    Ok(res)
+}
+
+impl<'a> Line<'a> {
+    pub fn parse(s : &'a str) -> Result<Self> {
+        let mut rest = strip_comment(s).trim();
+
+        let mut labels = vec![];
+        while let Some((label, after)) = rest.split_once(':') {
+            let label = label.trim();
+            if label.contains(char::is_whitespace) || label.contains(['"', '\'']) {
+                break;
+            }
+            if !is_identifier(label) {
+                return Err(Error::InvalidLabel(label.to_string()));
+            }
+            labels.push(label);
+            rest = after.trim();
+        }
+
+        let (mnemonic, operands) = match rest.split_once(char::is_whitespace) {
+            _ if rest.is_empty() => (None, vec![]),
+            Some((mnemonic, operands)) => (Some(mnemonic), split_operands(operands)),
+            None => (Some(rest), vec![]),
+        };
+
+        Ok(Self { labels, mnemonic, operands })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(Line::parse("  ; only a comment"), Ok(Line { labels: vec![], mnemonic: None, operands: vec![] }));
+        assert_eq!(Line::parse("start: loop:"), Ok(Line { labels: vec!["start", "loop"], mnemonic: None, operands: vec![] }));
+        assert_eq!(
+            Line::parse("main: mov 0x1234 , r0 ; load"),
+            Ok(Line { labels: vec!["main"], mnemonic: Some("mov"), operands: vec!["0x1234", "r0"] })
+        );
+        assert_eq!(Line::parse("ret"), Ok(Line { labels: vec![], mnemonic: Some("ret"), operands: vec![] }));
+        assert_eq!(Line::parse("1abc: ret"), Err(Error::InvalidLabel("1abc".to_string())));
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(
+            Line::parse(".ascii \"a;b, c:\", ';' ; comment"),
+            Ok(Line { labels: vec![], mnemonic: Some(".ascii"), operands: vec!["\"a;b, c:\"", "';'"] })
+        );
+        assert_eq!(split_operands("lo(a, b), \"\\\",\""), vec!["lo(a, b)", "\"\\\",\""]);
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(parse_string("\"hi\\n\\x41\\\"\"", '"'), Ok(b"hi\nA\"".to_vec()));
+        assert_eq!(parse_string("'\\0'", '\''), Ok(vec![0]));
+        assert_eq!(parse_string("\"\"", '"'), Ok(vec![]));
+        assert_eq!(parse_string("\"", '"'), Err(Error::InvalidString("\"".to_string())));
+        assert_eq!(parse_string("\"\\q\"", '"'), Err(Error::InvalidString("\"\\q\"".to_string())));
+        assert_eq!(parse_string("\"é\"", '"'), Err(Error::InvalidString("\"é\"".to_string())));
+    }
+}
@@ -0,0 +1,382 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{utils::{Error, Result}, Instruction};
+
+use super::{line::{is_identifier, Line}, Context, Symbol};
+
+/// How deep macro invocations and repetitions can nest
+const MAX_DEPTH : usize = 64;
+
+/// A line after macro expansion
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Expanded {
+    /// Index of the source line it comes from
+    pub line : usize,
+    pub text : String,
+}
+
+struct Macro {
+    params : Vec<(String, Option<String>)>,
+    body : Vec<(usize, String)>,
+}
+
+fn is_ident_char(c : char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Replaces every `\param` with its argument and every identifier in `labels`
+/// with its unique name, leaving literals untouched. Unknown parameters are
+/// kept for nested blocks unless `complete`.
+fn rewrite(text : &str, args : &HashMap<String, String>, labels : &HashMap<String, String>, complete : bool) -> Result<String> {
+    let mut res = String::new();
+    let mut quote = None;
+    let mut escaped = false;
+    let mut idx = 0;
+    while let Some(c) = text[idx..].chars().next() {
+        let ident_end = |start : usize| text[start..].find(|c| !is_ident_char(c)).map_or(text.len(), |end| start + end);
+
+        if let Some(q) = quote {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                c if c == q => quote = None,
+                _ => (),
+            }
+        } else if c == '"' || c == '\'' {
+            quote = Some(c);
+        } else if c == '\\' {
+            let end = ident_end(idx + 1);
+            let name = &text[idx + 1..end];
+            match args.get(name) {
+                Some(arg) => res += arg,
+                None if complete => return Err(Error::UndefinedParameter(name.to_string())),
+                None => res += &text[idx..end],
+            }
+            idx = end;
+            continue;
+        } else if c.is_ascii_alphanumeric() || c == '_' {
+            let end = ident_end(idx);
+            let word = &text[idx..end];
+            res += labels.get(word).map_or(word, String::as_str);
+            idx = end;
+            continue;
+        }
+
+        res.push(c);
+        idx += c.len_utf8();
+    }
+    Ok(res)
+}
+
+fn is_open(mnemonic : &str) -> bool {
+    matches!(mnemonic, ".macro" | ".rept" | ".irp")
+}
+
+fn is_close(mnemonic : &str) -> bool {
+    matches!(mnemonic, ".endm" | ".endr")
+}
+
+fn mnemonic(line : &Line) -> Option<String> {
+    line.mnemonic.map(str::to_lowercase)
+}
+
+struct Expander {
+    macros : HashMap<String, Macro>,
+
+    /// Constants defined so far, so `.rept` counts can use them
+    constants : BTreeMap<String, Symbol>,
+
+    /// Amount of expansions, to give their labels unique names
+    expansions : usize,
+
+    res : Vec<Expanded>,
+
+    /// Errors together with the line they happened at
+    errors : Vec<(Expanded, Error)>,
+}
+
+impl Expander {
+    /// Returns the lines between the opener at `lines[start]` and its closer,
+    /// together with the index of the closer
+    fn block(lines : &[(usize, String)], start : usize) -> Result<(&[(usize, String)], usize)> {
+        let opener = mnemonic(&Line::parse(&lines[start].1)?).unwrap_or_default();
+        let closer = if opener == ".macro" { ".endm" } else { ".endr" };
+
+        let mut depth = 0;
+        for (idx, (_, text)) in lines.iter().enumerate().skip(start + 1) {
+            match mnemonic(&Line::parse(text)?) {
+                Some(m) if is_open(&m) => depth += 1,
+                Some(m) if is_close(&m) && depth > 0 => depth -= 1,
+                Some(m) if m == closer => return Ok((&lines[start + 1..idx], idx)),
+                Some(m) if is_close(&m) => return Err(Error::UnexpectedEnd(m)),
+                _ => (),
+            }
+        }
+        Err(Error::UnterminatedBlock(opener))
+    }
+
+    /// Expands a copy of `body` with `args`, giving the labels it defines unique names
+    fn instantiate(&mut self, body : &[(usize, String)], args : &HashMap<String, String>, depth : usize) -> Result<()> {
+        self.expansions += 1;
+
+        let mut labels = HashMap::new();
+        for (_, text) in body {
+            for label in Line::parse(text)?.labels {
+                labels.insert(label.to_string(), format!("{label}__{}", self.expansions));
+            }
+        }
+
+        let body = body.iter()
+            .map(|(line, text)| Ok((*line, rewrite(text, args, &labels, false)?)))
+            .collect::<Result<Vec<_>>>()?;
+        self.expand(&body, depth + 1);
+        Ok(())
+    }
+
+    fn count(&self, s : &str) -> Result<usize> {
+        match Context::absolute(&self.constants).exact(s)? {
+            count @ 0..=0x10000 => Ok(count as usize),
+            count => Err(Error::InvalidValue(count.to_string())),
+        }
+    }
+
+    fn expand(&mut self, lines : &[(usize, String)], depth : usize) {
+        let mut idx = 0;
+        while idx < lines.len() {
+            match self.line(lines, idx, depth) {
+                Ok(end) => idx = end + 1,
+                Err(err) => {
+                    let (line, text) = lines[idx].clone();
+                    let fatal = matches!(err, Error::UnterminatedBlock(_));
+                    self.errors.push((Expanded { line, text }, err));
+                    if fatal {
+                        return;
+                    }
+
+                    // Skip the whole block of an opener that failed
+                    let opener = Line::parse(&lines[idx].1).ok().and_then(|line| mnemonic(&line)).is_some_and(|m| is_open(&m));
+                    if opener {
+                        idx = Self::block(lines, idx).map_or(idx, |(_, end)| end);
+                    }
+                    idx += 1;
+                },
+            }
+        }
+    }
+
+    /// Expands `lines[idx]`, returning the index of the last line it spans
+    fn line(&mut self, lines : &[(usize, String)], mut idx : usize, depth : usize) -> Result<usize> {
+        {
+            let (number, text) = &lines[idx];
+            let line = Line::parse(text)?;
+            let mnemonic = mnemonic(&line);
+            let invalid = || Error::InvalidOperands(text.trim().to_string());
+
+            if mnemonic.as_deref().is_some_and(is_open) && depth >= MAX_DEPTH {
+                return Err(Error::MacroRecursion(mnemonic.unwrap_or_default()));
+            }
+
+            match mnemonic.as_deref() {
+                Some(".macro") => {
+                    let (body, end) = Self::block(lines, idx)?;
+                    let (name, params) = line.operands.split_first().ok_or_else(invalid)?;
+                    // `.macro name a, b` puts the name and the first parameter in the same operand
+                    let (name, first) = name.split_once(char::is_whitespace).map_or((*name, None), |(n, p)| (n, Some(p.trim())));
+
+                    let name = name.to_lowercase();
+                    if !is_identifier(&name) || Instruction::is_mnemonic(&name) || self.macros.contains_key(&name) {
+                        return Err(Error::InvalidMacro(name));
+                    }
+
+                    let params = first.into_iter().chain(params.iter().copied()).map(|param| {
+                        let (param, default) = param.split_once('=').map_or((param, None), |(p, d)| (p.trim(), Some(d.trim().to_string())));
+                        is_identifier(param).then(|| (param.to_string(), default)).ok_or_else(invalid)
+                    }).collect::<Result<Vec<_>>>()?;
+
+                    self.macros.insert(name, Macro { params, body: body.to_vec() });
+                    idx = end;
+                },
+                Some(".rept") => {
+                    let (body, end) = Self::block(lines, idx)?;
+                    let [count] = line.operands[..] else {
+                        return Err(invalid());
+                    };
+                    for _ in 0..self.count(count)? {
+                        self.instantiate(body, &HashMap::new(), depth)?;
+                    }
+                    idx = end;
+                },
+                Some(".irp") => {
+                    let (body, end) = Self::block(lines, idx)?;
+                    let (param, values) = line.operands.split_first().ok_or_else(invalid)?;
+                    if !is_identifier(param) {
+                        return Err(invalid());
+                    }
+                    for value in values {
+                        self.instantiate(body, &HashMap::from([(param.to_string(), value.to_string())]), depth)?;
+                    }
+                    idx = end;
+                },
+                Some(m) if is_close(m) => return Err(Error::UnexpectedEnd(m.to_string())),
+                Some(name) if self.macros.contains_key(name) => {
+                    if depth >= MAX_DEPTH {
+                        return Err(Error::MacroRecursion(name.to_string()));
+                    }
+
+                    let labels = line.labels.iter().map(|label| format!("{label}:")).collect::<String>();
+                    if !labels.is_empty() {
+                        self.res.push(Expanded { line: *number, text: labels });
+                    }
+
+                    let mac = &self.macros[name];
+                    let args_text = &line.operands;
+                    if args_text.len() > mac.params.len() {
+                        return Err(invalid());
+                    }
+
+                    let args = mac.params.iter().enumerate().map(|(idx, (param, default))| {
+                        let arg = args_text.get(idx).map(|arg| arg.to_string()).or(default.clone()).ok_or_else(invalid)?;
+                        Ok((param.clone(), arg))
+                    }).collect::<Result<HashMap<_, _>>>()?;
+
+                    let body = mac.body.clone();
+                    self.instantiate(&body, &args, depth)?;
+                },
+                _ => {
+                    if let (Some(".equ"), [name, value]) = (mnemonic.as_deref(), &line.operands[..]) {
+                        if let Ok(value) = Context::absolute(&self.constants).exact(value) {
+                            self.constants.insert(name.to_string(), Symbol { section: None, value });
+                        }
+                    }
+                    let text = rewrite(text, &HashMap::new(), &HashMap::new(), true)?;
+                    self.res.push(Expanded { line: *number, text });
+                },
+            }
+        }
+        Ok(idx)
+    }
+}
+
+/// Expands every macro invocation and repetition of `source`, or returns
+/// every error found with the line it comes from
+pub(super) fn expand(source : &str) -> std::result::Result<Vec<Expanded>, Vec<(Expanded, Error)>> {
+    let lines = source.lines().map(str::to_string).enumerate().collect::<Vec<_>>();
+    let mut expander = Expander { macros: HashMap::new(), constants: BTreeMap::new(), expansions: 0, res: vec![], errors: vec![] };
+    expander.expand(&lines, 0);
+    match expander.errors.is_empty() {
+        true => Ok(expander.res),
+        false => Err(expander.errors),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn texts(source : &str) -> Result<Vec<String>> {
+        expand(source)
+            .map(|lines| lines.into_iter().map(|line| line.text.trim().to_string()).collect())
+            .map_err(|errors| errors[0].1.clone())
+    }
+
+    #[test]
+    fn plain() {
+        assert_eq!(expand("nop\n  ret"), Ok(vec![
+            Expanded { line: 0, text: "nop".to_string() },
+            Expanded { line: 1, text: "  ret".to_string() },
+        ]));
+    }
+
+    #[test]
+    fn parameters() {
+        assert_eq!(texts("
+            .macro load value, reg=r0
+                mov \\value, \\reg
+                push \\reg
+            .endm
+            load 0x1234, r1
+            load 1
+        "), Ok(vec![
+            "".to_string(),
+            "mov 0x1234, r1".to_string(), "push r1".to_string(),
+            "mov 1, r0".to_string(), "push r0".to_string(),
+            "".to_string(),
+        ]));
+    }
+
+    #[test]
+    fn local_labels() {
+        assert_eq!(texts("
+            .macro wait
+            loop: mov loop, r0
+                .db \"loop\"
+            .endm
+            start: wait
+            wait
+        "), Ok(vec![
+            "".to_string(),
+            "start:".to_string(),
+            "loop__1: mov loop__1, r0".to_string(), ".db \"loop\"".to_string(),
+            "loop__2: mov loop__2, r0".to_string(), ".db \"loop\"".to_string(),
+            "".to_string(),
+        ]));
+    }
+
+    #[test]
+    fn rept_and_irp() {
+        assert_eq!(texts("
+            .equ COUNT, 2
+            .rept COUNT
+                nop
+            .endr
+            .irp reg, r0, r1, r2
+                push \\reg
+            .endr
+        "), Ok(vec![
+            "".to_string(),
+            ".equ COUNT, 2".to_string(),
+            "nop".to_string(), "nop".to_string(),
+            "push r0".to_string(), "push r1".to_string(), "push r2".to_string(),
+            "".to_string(),
+        ]));
+    }
+
+    #[test]
+    fn nested() {
+        assert_eq!(texts("
+            .macro save_all
+                .irp n, 0, 1
+                    push r\\n
+                .endr
+            .endm
+            .macro prologue
+                save_all
+            .endm
+            prologue
+        "), Ok(vec!["".to_string(), "push r0".to_string(), "push r1".to_string(), "".to_string()]));
+    }
+
+    #[test]
+    fn multiple_errors() {
+        let errors = expand(".endr\nnop\n.macro mov\n.endm\n.rept 1\nnop").unwrap_err();
+        assert_eq!(errors, vec![
+            (Expanded { line: 0, text: ".endr".to_string() }, Error::UnexpectedEnd(".endr".to_string())),
+            (Expanded { line: 2, text: ".macro mov".to_string() }, Error::InvalidMacro("mov".to_string())),
+            (Expanded { line: 4, text: ".rept 1".to_string() }, Error::UnterminatedBlock(".rept".to_string())),
+        ]);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(texts(".macro forever\nforever\n.endm\nforever"), Err(Error::MacroRecursion("forever".to_string())));
+        assert_eq!(texts(".macro a\nnop"), Err(Error::UnterminatedBlock(".macro".to_string())));
+        assert_eq!(texts(".rept 2\nnop\n.endm"), Err(Error::UnexpectedEnd(".endm".to_string())));
+        assert_eq!(texts(".endr"), Err(Error::UnexpectedEnd(".endr".to_string())));
+        assert_eq!(texts(".macro mov\n.endm"), Err(Error::InvalidMacro("mov".to_string())));
+        assert_eq!(texts(".macro a\n.endm\n.macro a\n.endm"), Err(Error::InvalidMacro("a".to_string())));
+        assert_eq!(texts(".macro a x\nmov \\y, r0\n.endm\na 1"), Err(Error::UndefinedParameter("y".to_string())));
+        assert_eq!(texts(".macro a x\n.endm\na"), Err(Error::InvalidOperands("a".to_string())));
+        assert_eq!(texts(".macro a x\n.endm\na 1, 2"), Err(Error::InvalidOperands("a 1, 2".to_string())));
+        assert_eq!(texts(".rept COUNT\n.endr"), Err(Error::UndefinedSymbol("COUNT".to_string())));
+    }
+}
@@ -0,0 +1,527 @@
+//! Two pass assembler for SmplCore assembly.
+//!
+//! Each line holds any amount of `label:` definitions followed by an optional
+//! instruction in the syntax of [`Instruction`]'s `Display` or a directive, and
+//! `;` starts a comment. Values are constant expressions, with C-like operators and
+//! precedence (`+ - * / % << >> & | ^ ~`), parentheses, `lo(x)`/`hi(x)` to
+//! extract the low/high byte, character literals, `.` for the address of the
+//! current line, and labels, which can be used before or after being defined.
+//!
+//! Directives:
+//! - `.org offset`: continue at `offset` of the current section, which can't be behind the current one
+//! - `.db value, "string", ...`: literal bytes
+//! - `.dw value, ...`: literal little endian words
+//! - `.ascii "string"` and `.asciz "string"`: string, the latter zero terminated
+//! - `.fill count[, value]`: `count` times the byte `value` (0 by default)
+//! - `.align n`: pad with zeros up to a multiple of `n` from the start of the
+//!   section, which [`assemble`] places at a multiple of every such `n`
+//! - `.equ name, value`: define a constant
+//! - `.text`, `.data` and `.bss`: continue in that section, `.text` being the default
+//! - `.global name, ...`: make symbols visible to other objects
+//! - `.extern name, ...`: declare symbols defined by other objects
+//!
+//! [`assemble`] lays the sections out one after the other starting at address
+//! 0, while [`assemble_object`] keeps them apart in a relocatable
+//! [`Object`]. There, values that depend on the address of a section or an
+//! extern can only be added to or subtracted from constants, and end up as a
+//! 16-bit relocation: absolute for `symbol + constant`, or relative for
+//! `symbol - . + constant`.
+//!
+//! Every error found is reported, each one with the line and column it
+//! happened at.
+//!
+//! Before assembling, macros are expanded:
+//! - `.macro name a, b=default` ... `.endm`: define a macro, whose body refers
+//!   to its parameters as `\a`. Labels defined in the body are renamed on every
+//!   expansion so they don't clash.
+//! - `.rept count` ... `.endr`: repeat the body `count` times
+//! - `.irp param, a, b, ...` ... `.endr`: repeat the body once per value, as `\param`
+
+use std::{collections::{BTreeMap, BTreeSet}, str::FromStr};
+
+use crate::{
+    instruction::Operand,
+    object::{self, Binding, Object, RelocationKind, Section, Target},
+    utils::{Error, Result},
+    Instruction, Register, Width,
+};
+
+mod diagnostic;
+pub use diagnostic::{Diagnostic, Diagnostics};
+
+mod directive;
+use directive::Directive;
+
+mod expr;
+use expr::{Base, Expr};
+
+mod line;
+use line::{is_identifier, Line};
+
+mod macros;
+use macros::Expanded;
+
+/// Output of [`assemble`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assembly {
+    /// Program bytes, starting at address 0
+    pub image : Vec<u8>,
+
+    /// Value of every label and constant
+    pub symbols : BTreeMap<String, u16>,
+}
+
+/// Value of a symbol, an offset into `section` or absolute if `None`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Symbol {
+    section : Option<Section>,
+    value : i32,
+}
+
+/// 16-bit field that can't be filled until linking
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Pending {
+    section : Section,
+    offset : u16,
+    kind : RelocationKind,
+    base : Base,
+    addend : i32,
+}
+
+/// Symbols known at some point of the assembly
+struct Context<'a> {
+    symbols : &'a BTreeMap<String, Symbol>,
+    externs : &'a BTreeSet<String>,
+
+    /// Address of every section that already has one
+    bases : [Option<u16>; 3],
+
+    /// Whether every symbol must already be defined, otherwise unknown ones
+    /// evaluate to 0 so the first pass can size everything
+    complete : bool,
+
+    section : Section,
+
+    /// Offset of the current line inside `section`
+    offset : u16,
+}
+
+impl<'a> Context<'a> {
+    /// Context where only the absolute symbols in `symbols` are known
+    fn absolute(symbols : &'a BTreeMap<String, Symbol>) -> Self {
+        static NONE : BTreeSet<String> = BTreeSet::new();
+        Context { symbols, externs: &NONE, bases: [None; 3], complete: true, section: Section::Text, offset: 0 }
+    }
+
+    /// Value of an offset into `section`, or of an absolute value if `None`
+    fn label(&self, section : Option<Section>, value : i32) -> Expr {
+        match section.map(|section| (section, self.bases[section as usize])) {
+            None => Expr::constant(value, None),
+            Some((_, Some(base))) => Expr::constant(base as i32 + value, None),
+            Some((section, None)) => Expr::relative(Base::Section(section), value),
+        }
+    }
+
+    fn symbol(&self, name : &str) -> Option<Expr> {
+        match self.symbols.get(name) {
+            Some(symbol) => Some(self.label(symbol.section, symbol.value)),
+            None => self.externs.contains(name).then(|| Expr::relative(Base::Extern(name.to_string()), 0)),
+        }
+    }
+
+    /// Value that can be a forward reference
+    fn value(&self, s : &str) -> Result<Expr> {
+        expr::eval(s, self)
+    }
+
+    /// Value that can be a forward reference but not relocated
+    fn constant(&self, s : &str) -> Result<(i32, Option<Width>)> {
+        match self.value(s)? {
+            expr if expr.is_constant() => Ok((expr.value, expr.hint)),
+            _ if !self.complete => Ok((0, None)),
+            _ => Err(Error::NotRelocatable(s.to_string())),
+        }
+    }
+
+    /// Value that must be known right away, because it determines the layout
+    fn exact(&self, s : &str) -> Result<i32> {
+        Context { complete: true, ..*self }.constant(s).map(|(value, _)| value)
+    }
+
+    /// Relocation for a 16-bit field at `offset` of the current section holding `expr`
+    fn relocation(&self, expr : &Expr, offset : u16, source : &str) -> Result<Option<Pending>> {
+        if expr.is_constant() || !self.complete {
+            return Ok(None);
+        }
+
+        let current = Base::Section(self.section);
+        let (kind, base, addend) = match &expr.terms[..] {
+            [(base, 1)] => (RelocationKind::Absolute, base, expr.value),
+            [(base, 1), (other, -1)] | [(other, -1), (base, 1)] if *other == current
+                => (RelocationKind::Relative, base, expr.value + offset as i32),
+            _ => return Err(Error::NotRelocatable(source.to_string())),
+        };
+        Ok(Some(Pending { section: self.section, offset, kind, base: base.clone(), addend }))
+    }
+
+    fn operand(&self, s : &str, relocations : &mut Vec<Pending>) -> Result<Operand> {
+        if let Ok(reg) = Register::from_str(s) {
+            return Ok(Operand::Register(reg));
+        }
+
+        // Every immediate that can be relocated is a word right after the opcode and the registers
+        let expr = self.value(s)?;
+        match self.relocation(&expr, self.offset + 2, s)? {
+            Some(relocation) => {
+                relocations.push(relocation);
+                Ok(Operand::Immediate(0, Some(Width::Word)))
+            },
+            None if expr.is_constant() => Ok(Operand::Immediate(expr.value, expr.hint)),
+            None => Ok(Operand::Immediate(0, None)),
+        }
+    }
+
+    fn instruction(&self, mnemonic : &str, line : &Line, source : &str, relocations : &mut Vec<Pending>) -> Result<Instruction> {
+        use Instruction::*;
+
+        let mut pending = vec![];
+        let operands = line.operands.iter().map(|s| self.operand(s, &mut pending)).collect::<Result<Vec<_>>>()?;
+        let inst = Instruction::from_operands(mnemonic, &operands, source.trim())?;
+
+        if !pending.is_empty() {
+            match inst {
+                MovC2R(value, _) | AddC2R(value, _) | SubC2R(value, _) | AndC2R(value, _) | OrC2R(value, _) | CmpC2R(value, _) | CallC(value)
+                    if value.width() == Width::Word => relocations.extend(pending),
+                _ => return Err(Error::NotRelocatable(source.trim().to_string())),
+            }
+        }
+        Ok(inst)
+    }
+}
+
+/// A line that emits bytes
+struct Statement<'a> {
+    /// Index of the line in the expanded source
+    idx : usize,
+    section : Section,
+    offset : u16,
+    line : Line<'a>,
+}
+
+/// Sections with everything that can be resolved without knowing where they'll be
+struct Sections {
+    /// Bytes emitted, which can be shorter than the section if it ends with `.org`, `.fill` or `.align`
+    text : Vec<u8>,
+    data : Vec<u8>,
+    sizes : [u32; 3],
+
+    /// Address of every section, if they're already placed
+    bases : [Option<u16>; 3],
+    symbols : BTreeMap<String, Symbol>,
+    externs : BTreeSet<String>,
+    globals : BTreeSet<String>,
+    relocations : Vec<Pending>,
+}
+
+struct Assembler<'a> {
+    expanded : &'a [Expanded],
+    symbols : BTreeMap<String, Symbol>,
+    externs : BTreeSet<String>,
+    globals : Vec<(usize, &'a str)>,
+    section : Section,
+    offsets : [u32; 3],
+
+    /// What every section must start at a multiple of, so its `.align`s hold
+    aligns : [u64; 3],
+    statements : Vec<Statement<'a>>,
+    errors : Vec<(usize, Error)>,
+}
+
+impl<'a> Assembler<'a> {
+    fn define(&mut self, name : &str, section : Option<Section>, value : i32) -> Result<()> {
+        if !is_identifier(name) || Register::from_str(name).is_ok() || Instruction::is_mnemonic(name) {
+            return Err(Error::InvalidLabel(name.to_string()));
+        }
+        if self.externs.contains(name) || self.symbols.insert(name.to_string(), Symbol { section, value }).is_some() {
+            return Err(Error::DuplicateSymbol(name.to_string()));
+        }
+        Ok(())
+    }
+
+    fn context(&self, bases : [Option<u16>; 3], complete : bool) -> Context<'_> {
+        let offset = self.offsets[self.section as usize] as u16;
+        Context { symbols: &self.symbols, externs: &self.externs, bases, complete, section: self.section, offset }
+    }
+
+    /// First pass over a line, assigning an offset to its labels and what it emits
+    fn first(&mut self, idx : usize, bases : [Option<u16>; 3]) -> Result<()> {
+        let text = &self.expanded[idx].text;
+        let line = Line::parse(text)?;
+        for label in line.labels.iter() {
+            self.define(label, Some(self.section), self.offsets[self.section as usize] as i32)?;
+        }
+
+        let Some(mnemonic) = line.mnemonic else {
+            return Ok(());
+        };
+        let directive = Directive::from_name(mnemonic);
+        let context = self.context(bases, false);
+        let len = match directive {
+            Some(Directive::Org) => {
+                let offset = directive::origin(&line, text, &context)?;
+                if offset < self.offsets[self.section as usize] {
+                    return Err(Error::InvalidOrigin(offset));
+                }
+                self.offsets[self.section as usize] = offset;
+                0
+            },
+            Some(Directive::Equ) => {
+                let (name, symbol) = directive::equ(&line, text, &context)?;
+                self.define(name, symbol.section, symbol.value)?;
+                0
+            },
+            Some(directive @ (Directive::Text | Directive::Data | Directive::Bss)) => {
+                if !line.operands.is_empty() {
+                    return Err(Error::InvalidOperands(text.trim().to_string()));
+                }
+                self.section = directive.section().unwrap_or(Section::Text);
+                0
+            },
+            Some(Directive::Global | Directive::Extern) if line.operands.is_empty() => {
+                return Err(Error::InvalidOperands(text.trim().to_string()));
+            },
+            Some(Directive::Global) => {
+                self.globals.extend(line.operands.iter().map(|name| (idx, *name)));
+                0
+            },
+            Some(Directive::Extern) => {
+                for name in line.operands.iter() {
+                    if !is_identifier(name) || self.symbols.contains_key(*name) || !self.externs.insert(name.to_string()) {
+                        return Err(Error::DuplicateSymbol(name.to_string()));
+                    }
+                }
+                0
+            },
+            Some(directive) => {
+                if self.section == Section::Bss && !matches!(directive, Directive::Fill | Directive::Align) {
+                    return Err(Error::InvalidSection(mnemonic.to_lowercase()));
+                }
+                let len = directive.data(&line, text, &context, &mut vec![])?.len() as u32;
+                if let (Directive::Align, [n]) = (directive, &line.operands[..]) {
+                    let n = context.exact(n)? as u64;
+                    self.aligns[self.section as usize] = lcm(self.aligns[self.section as usize], n);
+                }
+                len
+            },
+            None if mnemonic.starts_with('.') => return Err(Error::UnknownDirective(mnemonic.to_string())),
+            None if self.section == Section::Bss => return Err(Error::InvalidSection(mnemonic.to_lowercase())),
+            None => context.instruction(mnemonic, &line, text, &mut vec![])?.len() as u32,
+        };
+
+        let offset = self.offsets[self.section as usize];
+        if len > 0 {
+            self.statements.push(Statement { idx, section: self.section, offset: offset as u16, line });
+        }
+        self.offsets[self.section as usize] += len;
+        match offset + len {
+            0..=0x10000 => Ok(()),
+            end => Err(Error::AddressOutOfRange(end)),
+        }
+    }
+
+    /// Runs the first pass, with the sections that have a known address at `bases`
+    fn first_pass(&mut self, bases : [Option<u16>; 3]) {
+        for idx in 0..self.expanded.len() {
+            match self.first(idx, bases) {
+                Ok(()) => (),
+                Err(err @ Error::AddressOutOfRange(_)) => {
+                    self.errors.push((idx, err));
+                    return;
+                },
+                Err(err) => self.errors.push((idx, err)),
+            }
+        }
+    }
+
+    /// Emits every statement, with every section that has a known address at `bases`
+    fn second_pass(&mut self, bases : [Option<u16>; 3]) -> Sections {
+        let mut sections = [vec![], vec![], vec![]];
+        let mut relocations = vec![];
+        for statement in self.statements.iter() {
+            let at = &self.expanded[statement.idx];
+            let context = Context {
+                symbols: &self.symbols,
+                externs: &self.externs,
+                bases,
+                complete: true,
+                section: statement.section,
+                offset: statement.offset,
+            };
+            let mnemonic = statement.line.mnemonic.unwrap_or_default();
+            let bytes = match Directive::from_name(mnemonic) {
+                Some(directive) => directive.data(&statement.line, &at.text, &context, &mut relocations),
+                None => context.instruction(mnemonic, &statement.line, &at.text, &mut relocations).map(|inst| inst.compile()),
+            };
+
+            match bytes {
+                Ok(bytes) if statement.section == Section::Bss && bytes.iter().any(|byte| *byte != 0) => {
+                    self.errors.push((statement.idx, Error::InvalidSection(mnemonic.to_lowercase())));
+                },
+                Ok(bytes) => {
+                    let section = &mut sections[statement.section as usize];
+                    section.resize(statement.offset as usize, 0);
+                    section.extend(bytes);
+                },
+                Err(err) => self.errors.push((statement.idx, err)),
+            }
+        }
+
+        let mut globals = BTreeSet::new();
+        for (idx, name) in self.globals.iter() {
+            if !self.symbols.contains_key(*name) {
+                self.errors.push((*idx, Error::UndefinedSymbol(name.to_string())));
+            }
+            globals.insert(name.to_string());
+        }
+
+        let [text, data, _] = sections;
+        Sections {
+            text,
+            data,
+            sizes: self.offsets,
+            bases,
+            symbols: self.symbols.clone(),
+            externs: self.externs.clone(),
+            globals,
+            relocations,
+        }
+    }
+}
+
+/// Least common multiple of two alignments, saturating past the address space
+/// since no section other than one at 0 could start at a multiple of it anyway
+fn lcm(a : u64, b : u64) -> u64 {
+    let (mut x, mut y) = (a, b);
+    while y != 0 {
+        (x, y) = (y, x % y);
+    }
+    (a / x * b).min(0x20000)
+}
+
+/// Runs both passes over `source`. Sections are placed one after the other
+/// from address 0 if `flat`, or left relocatable otherwise.
+fn sections(file : &str, source : &str, flat : bool) -> Result<Sections> {
+    let fail = |errors : Vec<(&Expanded, Error)>| Error::Assembly(Diagnostics(
+        errors.into_iter().map(|(at, err)| Diagnostic::new(err, file, at.line, &at.text)).collect()
+    ));
+
+    let expanded = macros::expand(source).map_err(|errors| fail(errors.iter().map(|(at, err)| (at, err.clone())).collect()))?;
+    let mut assembler = Assembler {
+        expanded: &expanded,
+        symbols: BTreeMap::new(),
+        externs: BTreeSet::new(),
+        globals: vec![],
+        section: Section::Text,
+        offsets: [0; 3],
+        aligns: [1; 3],
+        statements: vec![],
+        errors: vec![],
+    };
+
+    // The text section goes first, so its address is known from the start
+    assembler.first_pass(if flat { [Some(0), None, None] } else { [None; 3] });
+
+    let bases = if flat {
+        let [text, data, bss] = assembler.offsets.map(u64::from);
+        let [_, data_align, bss_align] = assembler.aligns;
+        let data_base = text.next_multiple_of(data_align);
+        let bss_base = (data_base + data).next_multiple_of(bss_align);
+        if bss_base + bss > 0x10000 {
+            let last = assembler.expanded.len().saturating_sub(1);
+            assembler.errors.push((last, Error::AddressOutOfRange((bss_base + bss) as u32)));
+        }
+        [Some(0), Some(data_base as u16), Some(bss_base as u16)]
+    } else {
+        [None; 3]
+    };
+    let sections = assembler.second_pass(bases);
+
+    let mut errors = assembler.errors;
+    if flat {
+        for relocation in sections.relocations.iter() {
+            if let Base::Extern(name) = &relocation.base {
+                let idx = assembler.statements.iter().rev()
+                    .find(|statement| statement.section == relocation.section && statement.offset <= relocation.offset)
+                    .map_or(0, |statement| statement.idx);
+                errors.push((idx, Error::UndefinedSymbol(name.clone())));
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        errors.sort_by_key(|(idx, _)| *idx);
+        return Err(fail(errors.into_iter().map(|(idx, err)| (&expanded[idx], err)).collect()));
+    }
+    Ok(sections)
+}
+
+pub fn assemble(source : &str) -> Result<Assembly> {
+    assemble_named("<input>", source)
+}
+
+/// Assembles `source`, naming it `file` in the diagnostics of every error found
+pub fn assemble_named(file : &str, source : &str) -> Result<Assembly> {
+    let sections = sections(file, source, true)?;
+
+    let bases = sections.bases.map(|base| base.unwrap_or_default() as i32);
+    let mut image = sections.text;
+    if !sections.data.is_empty() {
+        image.resize(bases[Section::Data as usize] as usize, 0);
+        image.extend(sections.data);
+    }
+
+    let symbols = sections.symbols.into_iter().map(|(name, symbol)| {
+        let base = symbol.section.map_or(0, |section| bases[section as usize]);
+        (name, (base + symbol.value) as u16)
+    }).collect();
+    Ok(Assembly { image, symbols })
+}
+
+/// Assembles `source` into a relocatable object, naming it `file` in the
+/// diagnostics of every error found
+pub fn assemble_object(file : &str, source : &str) -> Result<Object> {
+    let sections = sections(file, source, false)?;
+
+    let mut symbols = sections.symbols.iter().map(|(name, symbol)| object::Symbol {
+        name: name.clone(),
+        binding: if sections.globals.contains(name) { Binding::Global } else { Binding::Local },
+        section: symbol.section,
+        value: symbol.value as u16,
+    }).collect::<Vec<_>>();
+    symbols.extend(sections.externs.iter().map(|name| object::Symbol {
+        name: name.clone(),
+        binding: Binding::Extern,
+        section: None,
+        value: 0,
+    }));
+
+    let relocations = sections.relocations.into_iter().map(|pending| object::Relocation {
+        section: pending.section,
+        offset: pending.offset,
+        kind: pending.kind,
+        target: match pending.base {
+            Base::Section(section) => Target::Section(section),
+            Base::Extern(name) => Target::Symbol(symbols.iter().position(|symbol| symbol.name == name).unwrap_or_default() as u16),
+        },
+        addend: pending.addend,
+    }).collect();
+
+    let [text_len, data_len, bss] = sections.sizes;
+    let (mut text, mut data) = (sections.text, sections.data);
+    text.resize(text_len as usize, 0);
+    data.resize(data_len as usize, 0);
+    Ok(Object { text, data, bss: bss as u16, symbols, relocations })
+}
+
+#[cfg(test)]
+mod test;
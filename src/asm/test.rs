@@ -0,0 +1,385 @@
+use super::*;
+use crate::Value;
+
+fn compile(program : &[Instruction]) -> Vec<u8> {
+    program.iter().flat_map(Instruction::compile).collect()
+}
+
+/// First error found assembling `source`
+fn error(source : &str) -> Error {
+    match assemble(source) {
+        Err(Error::Assembly(diagnostics)) => diagnostics.0[0].error.clone(),
+        res => panic!("{:?}", res),
+    }
+}
+
+#[test]
+fn instructions() {
+    let assembly = assemble("
+        mov 0x1234, r0
+        movm r0, rb1  ; load
+        push r0
+        ret
+    ").unwrap();
+
+    assert_eq!(assembly.image, compile(&[
+        Instruction::movc2r(Value::word(0x1234), Register::r0()).unwrap(),
+        Instruction::movm2r(Register::r0(), Register::rb1()).unwrap(),
+        Instruction::push(Register::r0()).unwrap(),
+        Instruction::ret(),
+    ]));
+    assert!(assembly.symbols.is_empty());
+}
+
+#[test]
+fn labels() {
+    let assembly = assemble("
+        start:
+            call func
+            mov end, r1
+            ajmp r1
+        func: mov start, r0
+            ret
+        end:
+    ").unwrap();
+
+    assert_eq!(assembly.image, compile(&[
+        Instruction::callc(Value::word(0x000A)).unwrap(),
+        Instruction::movc2r(Value::word(0x0010), Register::r1()).unwrap(),
+        Instruction::ajmp(Register::r1()).unwrap(),
+        Instruction::movc2r(Value::word(0x0000), Register::r0()).unwrap(),
+        Instruction::ret(),
+    ]));
+    assert_eq!(assembly.symbols, BTreeMap::from([
+        ("start".to_string(), 0x0000),
+        ("func".to_string(), 0x000A),
+        ("end".to_string(), 0x0010),
+    ]));
+}
+
+#[test]
+fn byte_operand_label() {
+    let assembly = assemble("
+        nop
+        here: mov here, rb0
+    ").unwrap();
+    assert_eq!(assembly.image, compile(&[Instruction::nop(), Instruction::movc2r(Value::byte(2), Register::rb0()).unwrap()]));
+}
+
+#[test]
+fn errors() {
+    assert_eq!(error("call nowhere"), Error::UndefinedSymbol("nowhere".to_string()));
+    assert_eq!(error("a: nop\na: nop"), Error::DuplicateSymbol("a".to_string()));
+    assert_eq!(error("r0: nop"), Error::InvalidLabel("r0".to_string()));
+    assert_eq!(error("mov: nop"), Error::InvalidLabel("mov".to_string()));
+    assert_eq!(error("foo r0"), Error::UnknownMnemonic("foo".to_string()));
+    assert_eq!(
+        error("mov 0x1234, rb0"),
+        Error::OperandWidthMismatch(Instruction::MovC2R(Value::word(0x1234), Register::rb0()))
+    );
+}
+
+#[test]
+fn too_large() {
+    let source = "mov 0, r0\n".repeat(0x4001);
+    assert_eq!(error(&source), Error::AddressOutOfRange(0x10004));
+}
+
+#[test]
+fn org() {
+    let assembly = assemble("
+        .org 4
+        start: ret
+        .org 0x0008
+        end:
+    ").unwrap();
+    assert_eq!(assembly.image, vec![0, 0, 0, 0, 0x33, 0x00]);
+    assert_eq!(assembly.symbols["start"], 4);
+    assert_eq!(assembly.symbols["end"], 8);
+
+    assert_eq!(error("nop\n.org 2\n.org 1"), Error::InvalidOrigin(1));
+    assert_eq!(error(".org later\nlater:"), Error::UndefinedSymbol("later".to_string()));
+    assert_eq!(error(".org -1"), Error::InvalidValue("-1".to_string()));
+    assert_eq!(error(".org 0x10000"), Error::InvalidValue("0x10000".to_string()));
+}
+
+#[test]
+fn data() {
+    let assembly = assemble("
+        .db 1, 0xFF, -1, 'A', \"hi\"
+        .dw 0x1234, table, -2
+        table: .ascii \"ab\\n\"
+        .asciz \"c\"
+    ").unwrap();
+    assert_eq!(assembly.image, vec![
+        0x01, 0xFF, 0xFF, b'A', b'h', b'i',
+        0x34, 0x12, 0x0C, 0x00, 0xFE, 0xFF,
+        b'a', b'b', b'\n',
+        b'c', 0x00,
+    ]);
+
+    assert_eq!(error(".db 256"), Error::InvalidValue("256".to_string()));
+    assert_eq!(error(".dw 0x10000"), Error::InvalidValue("0x10000".to_string()));
+    assert_eq!(error(".db"), Error::InvalidOperands(".db".to_string()));
+    assert_eq!(error(".ascii \"a\", \"b\""), Error::InvalidOperands(".ascii \"a\", \"b\"".to_string()));
+}
+
+#[test]
+fn fill_and_align() {
+    let assembly = assemble("
+        .fill 3, 0xAA
+        .align 4
+        aligned: .fill 1
+        .align 2
+        .align 2
+        end:
+    ").unwrap();
+    assert_eq!(assembly.image, vec![0xAA, 0xAA, 0xAA, 0x00, 0x00, 0x00]);
+    assert_eq!(assembly.symbols["aligned"], 4);
+    assert_eq!(assembly.symbols["end"], 6);
+
+    // Sections after the text one start where their alignment holds
+    let assembly = assemble("
+        .org 3
+        nop
+        .data
+        .db 1
+        .align 4
+        word: .dw 2
+        .bss
+        .align 6
+        bss: .fill 1
+    ").unwrap();
+    assert_eq!(assembly.image, vec![0, 0, 0, 0x00, 0x00, 0, 0, 0, 1, 0, 0, 0, 2, 0]);
+    assert_eq!(assembly.symbols["word"], 12);
+    assert_eq!(assembly.symbols["bss"], 18);
+
+    assert_eq!(error(".align 0"), Error::InvalidValue("0".to_string()));
+    assert_eq!(error(".fill -1"), Error::InvalidValue("-1".to_string()));
+}
+
+#[test]
+fn equ() {
+    let assembly = assemble("
+        .equ VECTORS, 0x0100
+        .equ HANDLERS, 2
+        mov VECTORS, r0
+        mov HANDLERS, rb1
+        .org VECTORS
+        .dw handler, handler
+        handler: ret
+    ").unwrap();
+
+    assert_eq!(&assembly.image[..8], &compile(&[
+        Instruction::movc2r(Value::word(0x0100), Register::r0()).unwrap(),
+        Instruction::movc2r(Value::byte(2), Register::rb1()).unwrap(),
+    ])[..]);
+    assert_eq!(&assembly.image[0x100..], &[0x04, 0x01, 0x04, 0x01, 0x33, 0x00]);
+    assert_eq!(assembly.symbols["VECTORS"], 0x0100);
+
+    assert_eq!(error(".equ A, 1\n.equ A, 2"), Error::DuplicateSymbol("A".to_string()));
+    assert_eq!(error(".equ A, B\n.equ B, 1"), Error::UndefinedSymbol("B".to_string()));
+    assert_eq!(error(".foo 1"), Error::UnknownDirective(".foo".to_string()));
+}
+
+#[test]
+fn expressions() {
+    let assembly = assemble("
+        .equ WIDTH, 16
+        mov (TABLE + 4) & 0xFF, rb0
+        shl WIDTH-1, r2
+        mov hi(TABLE), rb1
+        mov end - TABLE, r3
+        here: mov . - here, r4
+        .org 0x0100
+        TABLE: .dw lo(TABLE) | 0xAB00
+        end:
+    ").unwrap();
+
+    assert_eq!(&assembly.image[..18], &compile(&[
+        Instruction::movc2r(Value::byte(0x04), Register::rb0()).unwrap(),
+        Instruction::shl(Value::byte(15), Register::r2()).unwrap(),
+        Instruction::movc2r(Value::byte(0x01), Register::rb1()).unwrap(),
+        Instruction::movc2r(Value::word(2), Register::r3()).unwrap(),
+        Instruction::movc2r(Value::word(0), Register::r4()).unwrap(),
+    ])[..]);
+    assert_eq!(&assembly.image[0x100..], &[0x00, 0xAB]);
+}
+
+#[test]
+fn expression_width() {
+    assert_eq!(
+        error(".equ TABLE, 0x1234\nmov TABLE + 1, rb0"),
+        Error::OperandWidthMismatch(Instruction::MovC2R(Value::word(0x1235), Register::rb0()))
+    );
+    assert_eq!(
+        error(".equ WIDTH, 16\nshl WIDTH + 1, rb0"),
+        Error::OperandWidthMismatch(Instruction::Shl(Value::byte(17), Register::rb0()))
+    );
+
+    // Shifts have to fit their nibble, whatever the value's width
+    assert_eq!(error("shl 16, r0"), Error::OperandWidthMismatch(Instruction::Shl(Value::byte(16), Register::r0())));
+    assert_eq!(error("shl 0x100, r0"), Error::OperandWidthMismatch(Instruction::Shl(Value::word(0x100), Register::r0())));
+    assert_eq!(error("shl 9, rb0"), Error::OperandWidthMismatch(Instruction::Shl(Value::byte(9), Register::rb0())));
+    assert_eq!(
+        error("call 0x8000 * 4"),
+        Error::InvalidValue("131072".to_string())
+    );
+    assert_eq!(error("mov 1 +, r0"), Error::InvalidExpression("1 +".to_string()));
+}
+
+#[test]
+fn macros() {
+    let assembly = assemble("
+        .macro prologue
+            .irp n, 0, 1, 2
+                push r\\n
+            .endr
+        .endm
+        .macro spin reg
+            again: mov again, \\reg
+                ajmp \\reg
+        .endm
+        start: prologue
+        spin r0
+        spin r1
+    ").unwrap();
+
+    assert_eq!(assembly.image, compile(&[
+        Instruction::push(Register::r0()).unwrap(),
+        Instruction::push(Register::r1()).unwrap(),
+        Instruction::push(Register::r2()).unwrap(),
+        Instruction::movc2r(Value::word(6), Register::r0()).unwrap(),
+        Instruction::ajmp(Register::r0()).unwrap(),
+        Instruction::movc2r(Value::word(12), Register::r1()).unwrap(),
+        Instruction::ajmp(Register::r1()).unwrap(),
+    ]));
+    assert_eq!(assembly.symbols["start"], 0);
+}
+
+#[test]
+fn diagnostics() {
+    let source = "
+        .macro load value
+            mov \\value, rb0
+        .endm
+        start: call nowhere
+        mov 0x1234, rb0
+        foo r0
+        load 0x100
+    ";
+    let Err(Error::Assembly(Diagnostics(diagnostics))) = assemble_named("main.s", source) else {
+        panic!();
+    };
+
+    let summary = diagnostics.iter().map(|d| (d.error.clone(), d.file.as_str(), d.line, d.column, d.len)).collect::<Vec<_>>();
+    assert_eq!(summary, vec![
+        (Error::UndefinedSymbol("nowhere".to_string()), "main.s", 5, 21, 7),
+        (Error::OperandWidthMismatch(Instruction::MovC2R(Value::word(0x1234), Register::rb0())), "main.s", 6, 9, 15),
+        (Error::UnknownMnemonic("foo".to_string()), "main.s", 7, 9, 3),
+        (Error::OperandWidthMismatch(Instruction::MovC2R(Value::word(0x100), Register::rb0())), "main.s", 3, 13, 14),
+    ]);
+    assert_eq!(diagnostics[3].snippet, "            mov 0x100, rb0");
+
+    assert_eq!(diagnostics[0].to_string(), "\
+error: undefined symbol: \"nowhere\"
+ --> main.s:5:21
+  |
+5 |         start: call nowhere
+  |                     ^^^^^^^");
+}
+
+#[test]
+fn macro_diagnostics() {
+    let Err(Error::Assembly(Diagnostics(diagnostics))) = assemble(".endm\nnop\n.rept\n.endr") else {
+        panic!();
+    };
+    let summary = diagnostics.iter().map(|d| (d.error.clone(), d.line)).collect::<Vec<_>>();
+    assert_eq!(summary, vec![
+        (Error::UnexpectedEnd(".endm".to_string()), 1),
+        (Error::InvalidOperands(".rept".to_string()), 3),
+    ]);
+}
+
+#[test]
+fn sections() {
+    let assembly = assemble("
+        .data
+        message: .asciz \"hi\"
+        .bss
+        buffer: .fill 4
+        .text
+        start: mov message, r0
+            mov buffer, r1
+    ").unwrap();
+
+    assert_eq!(assembly.image, [
+        &compile(&[
+            Instruction::movc2r(Value::word(0x0008), Register::r0()).unwrap(),
+            Instruction::movc2r(Value::word(0x000B), Register::r1()).unwrap(),
+        ])[..],
+        b"hi\0",
+    ].concat());
+    assert_eq!(assembly.symbols["buffer"], 0x000B);
+
+    assert_eq!(error(".bss\nnop"), Error::InvalidSection("nop".to_string()));
+    assert_eq!(error(".bss\n.db 1"), Error::InvalidSection(".db".to_string()));
+    assert_eq!(error(".bss\n.fill 2, 1"), Error::InvalidSection(".fill".to_string()));
+    assert_eq!(error(".extern putc\ncall putc"), Error::UndefinedSymbol("putc".to_string()));
+}
+
+#[test]
+fn object() {
+    let object = assemble_object("main.s", "
+        .global main, count
+        .extern putc
+        main: mov message, r0
+            call putc
+            mov count - ., r1
+            ret
+        .data
+        message: .asciz \"hi\"
+            .dw main + 2
+        .bss
+        count: .fill 2
+    ").unwrap();
+
+    assert_eq!(object.text, compile(&[
+        Instruction::movc2r(Value::word(0), Register::r0()).unwrap(),
+        Instruction::callc(Value::word(0)).unwrap(),
+        Instruction::movc2r(Value::word(0), Register::r1()).unwrap(),
+        Instruction::ret(),
+    ]));
+    assert_eq!(object.data, b"hi\0\0\0");
+    assert_eq!(object.bss, 2);
+
+    let symbol = |name : &str, binding, section, value| object::Symbol { name: name.to_string(), binding, section, value };
+    assert_eq!(object.symbols, vec![
+        symbol("count", Binding::Global, Some(Section::Bss), 0),
+        symbol("main", Binding::Global, Some(Section::Text), 0),
+        symbol("message", Binding::Local, Some(Section::Data), 0),
+        symbol("putc", Binding::Extern, None, 0),
+    ]);
+
+    let relocation = |section, offset, kind, target, addend| object::Relocation { section, offset, kind, target, addend };
+    assert_eq!(object.relocations, vec![
+        relocation(Section::Text, 2, RelocationKind::Absolute, Target::Section(Section::Data), 0),
+        relocation(Section::Text, 6, RelocationKind::Absolute, Target::Symbol(3), 0),
+        relocation(Section::Text, 10, RelocationKind::Relative, Target::Section(Section::Bss), 2),
+        relocation(Section::Data, 3, RelocationKind::Absolute, Target::Section(Section::Text), 2),
+    ]);
+}
+
+#[test]
+fn object_errors() {
+    let error = |source| match assemble_object("main.s", source) {
+        Err(Error::Assembly(diagnostics)) => diagnostics.0[0].error.clone(),
+        res => panic!("{:?}", res),
+    };
+
+    assert_eq!(error(".extern f\nmov f, rb0"), Error::NotRelocatable("mov f, rb0".to_string()));
+    assert_eq!(error(".extern f\nmov f * 2, r0"), Error::NotRelocatable("f * 2".to_string()));
+    assert_eq!(error(".extern f\n.db f"), Error::NotRelocatable("f".to_string()));
+    assert_eq!(error(".extern f\nf: nop"), Error::DuplicateSymbol("f".to_string()));
+    assert_eq!(error(".global g"), Error::UndefinedSymbol("g".to_string()));
+}
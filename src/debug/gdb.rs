@@ -0,0 +1,337 @@
+//! GDB remote serial protocol stub, so GDB can debug the emulator with
+//! `target remote`.
+//!
+//! Registers are 16 bits, in encoding order as told by the target
+//! description: RINFO, RIP, RINT, Flags, RSB, RSH and R0 to R9. Supported
+//! are reading and writing registers and memory, software and hardware
+//! breakpoints, write, read and access watchpoints, single steps and
+//! continuing, which runs until something stops it or the debugger's limit.
+
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+};
+
+use crate::{
+    emu::Bus,
+    utils::{Error, Result},
+    Register, Width,
+};
+
+use super::{Debugger, Hit, Stop, Watchpoint};
+
+/// Signals of the stop replies
+const SIGILL : u8 = 4;
+const SIGTRAP : u8 = 5;
+const SIGSEGV : u8 = 11;
+
+/// Every register in encoding order
+fn registers() -> impl Iterator<Item = Register> {
+    (0..16).map(|code| Register::from_src(Width::Word, code))
+}
+
+fn target() -> String {
+    let mut res = String::from("<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n");
+    res += "<feature name=\"org.smplworks.smplcore\">\n";
+    for reg in registers() {
+        let kind = match reg {
+            Register::RIP | Register::RINT => "code_ptr",
+            Register::RSB | Register::RSH => "data_ptr",
+            _ => "uint16",
+        };
+        res += &format!("<reg name=\"{}\" bitsize=\"16\" type=\"{kind}\"/>\n", reg.to_string().to_lowercase());
+    }
+    res += "</feature>\n</target>\n";
+    res
+}
+
+fn hex(bytes : &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn unhex(s : &str) -> Option<Vec<u8>> {
+    if s.len() % 2 == 1 {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|idx| u8::from_str_radix(s.get(idx..idx + 2)?, 16).ok()).collect()
+}
+
+fn number(s : &str) -> Option<u16> {
+    u16::from_str_radix(s, 16).ok()
+}
+
+/// `address,len` of memory packets
+fn range(s : &str) -> Option<(u16, u16)> {
+    let (address, len) = s.split_once(',')?;
+    Some((number(address)?, number(len)?))
+}
+
+/// Stream GDB talks through together with what's been read of it
+struct Connection<S : Read + Write> {
+    stream : S,
+    buffer : Vec<u8>,
+    ack : bool,
+}
+
+impl<S : Read + Write> Connection<S> {
+    fn byte(&mut self) -> Result<Option<u8>> {
+        if self.buffer.is_empty() {
+            let mut chunk = [0; 256];
+            let read = loop {
+                match self.stream.read(&mut chunk) {
+                    Ok(read) => break read,
+                    Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(err.into()),
+                }
+            };
+            self.buffer.extend_from_slice(&chunk[..read]);
+        }
+        Ok((!self.buffer.is_empty()).then(|| self.buffer.remove(0)))
+    }
+
+    /// Next packet with a valid checksum, or `None` once the stream ends.
+    /// Anything between packets, like acknowledgments and interrupt
+    /// requests, is ignored.
+    fn receive(&mut self) -> Result<Option<String>> {
+        loop {
+            match self.byte()? {
+                None => return Ok(None),
+                Some(b'$') => (),
+                Some(_) => continue,
+            }
+
+            let mut data = vec![];
+            loop {
+                match self.byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                }
+            }
+            let mut checksum = [0; 2];
+            for digit in checksum.iter_mut() {
+                *digit = match self.byte()? {
+                    Some(byte) => byte,
+                    None => return Ok(None),
+                };
+            }
+
+            let valid = std::str::from_utf8(&checksum).ok()
+                .and_then(|checksum| u8::from_str_radix(checksum, 16).ok())
+                .is_some_and(|checksum| checksum == data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)));
+            if self.ack {
+                self.stream.write_all(if valid { b"+" } else { b"-" })?;
+                self.stream.flush()?;
+            }
+            if valid {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+        }
+    }
+
+    /// Sends a packet, again until it's acknowledged
+    fn send(&mut self, data : &str) -> Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        loop {
+            write!(self.stream, "${data}#{checksum:02x}")?;
+            self.stream.flush()?;
+            if !self.ack {
+                return Ok(());
+            }
+            loop {
+                match self.byte()? {
+                    Some(b'+') | None => return Ok(()),
+                    Some(b'-') => break,
+                    Some(_) => continue,
+                }
+            }
+        }
+    }
+}
+
+/// Reader and writer used as a single stream, like stdin and stdout
+pub struct Pipe<R : Read, W : Write>(pub R, pub W);
+
+impl<R : Read, W : Write> Read for Pipe<R, W> {
+    fn read(&mut self, buf : &mut [u8]) -> std::io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<R : Read, W : Write> Write for Pipe<R, W> {
+    fn write(&mut self, buf : &[u8]) -> std::io::Result<usize> {
+        self.1.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.1.flush()
+    }
+}
+
+impl<B : Bus> Debugger<B> {
+    /// Stop reply for why running stopped
+    fn stop_reply(&self, stop : Result<Stop>) -> String {
+        match stop {
+            Ok(Stop::Halted(code)) => format!("W{:02x}", code as u8),
+            Ok(Stop::Watchpoint(id, Hit::Access(access))) => {
+                let kind = match self.watchpoints.get(&id) {
+                    Some(Watchpoint::Memory { read: true, write: true, .. }) => "awatch",
+                    Some(Watchpoint::Memory { read: true, .. }) => "rwatch",
+                    _ => "watch",
+                };
+                format!("T{SIGTRAP:02x}{kind}:{:x};", access.address)
+            },
+            Ok(_) => format!("S{SIGTRAP:02x}"),
+            Err(Error::UnknownOpcode(_) | Error::TruncatedInstruction(..) | Error::InvalidRegisterNibble(..) | Error::NonCanonicalEncoding(_) |
+                Error::OperandWidthMismatch(_) | Error::DestOperandNotWritable(_)) => format!("S{SIGILL:02x}"),
+            Err(_) => format!("S{SIGSEGV:02x}"),
+        }
+    }
+
+    /// Reply to a packet, empty for unsupported ones, or `None` to end the
+    /// session
+    fn gdb_command(&mut self, packet : &str, watches : &mut BTreeMap<(char, u16, u16), usize>) -> Result<Option<String>> {
+        const ERROR : &str = "E01";
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+
+        let res = match command {
+            "?" => format!("S{SIGTRAP:02x}"),
+            "g" => {
+                let bytes = registers().flat_map(|reg| self.machine.cpu.get(reg).to_le_bytes()).collect::<Vec<_>>();
+                hex(&bytes)
+            },
+            "G" => match unhex(args).filter(|bytes| bytes.len() == 32) {
+                Some(bytes) => {
+                    self.clear_history();
+                    for (reg, value) in registers().zip(bytes.chunks(2)) {
+                        self.machine.cpu.set(reg, u16::from_le_bytes([value[0], value[1]]));
+                    }
+                    "OK".to_string()
+                },
+                None => ERROR.to_string(),
+            },
+            "p" => match number(args).filter(|code| *code < 16) {
+                Some(code) => hex(&self.machine.cpu.get(Register::from_src(Width::Word, code as u8)).to_le_bytes()),
+                None => ERROR.to_string(),
+            },
+            "P" => {
+                let parsed = args.split_once('=').and_then(|(code, value)| Some((number(code)?, unhex(value)?)));
+                match parsed {
+                    Some((code, value)) if code < 16 && value.len() == 2 => {
+                        self.clear_history();
+                        self.machine.cpu.set(Register::from_src(Width::Word, code as u8), u16::from_le_bytes([value[0], value[1]]));
+                        "OK".to_string()
+                    },
+                    _ => ERROR.to_string(),
+                }
+            },
+            "m" => match range(args) {
+                Some((address, len)) => {
+                    let bytes = (0..len).map(|idx| self.machine.bus.peek(address.wrapping_add(idx))).collect::<Vec<_>>();
+                    hex(&bytes)
+                },
+                None => ERROR.to_string(),
+            },
+            "M" => {
+                let parsed = args.split_once(':').and_then(|(range, data)| Some((self::range(range)?, unhex(data)?)));
+                match parsed {
+                    Some(((address, len), data)) if data.len() == len as usize => {
+                        self.clear_history();
+                        let written = data.iter().enumerate()
+                            .try_for_each(|(idx, byte)| self.machine.bus.write(address.wrapping_add(idx as u16), *byte as u16, Width::Byte));
+                        if written.is_ok() { "OK".to_string() } else { ERROR.to_string() }
+                    },
+                    _ => ERROR.to_string(),
+                }
+            },
+            "Z" | "z" => {
+                let mut fields = args.split(',');
+                let parsed = (|| Some((fields.next()?.chars().next()?, number(fields.next()?)?, number(fields.next()?)?)))();
+                match parsed {
+                    Some(('0' | '1', address, _)) => {
+                        match command {
+                            "Z" => self.set_breakpoint(address),
+                            _ => self.remove_breakpoint(address),
+                        };
+                        "OK".to_string()
+                    },
+                    Some((kind @ ('2' | '3' | '4'), start, len)) => {
+                        let key = (kind, start, len);
+                        match command {
+                            "Z" => {
+                                let end = start.saturating_add(len.max(1) - 1);
+                                let id = self.watch(Watchpoint::Memory { start, end, read: kind != '2', write: kind != '3' });
+                                if let Some(old) = watches.insert(key, id) {
+                                    self.unwatch(old);
+                                }
+                            },
+                            _ => {
+                                if let Some(id) = watches.remove(&key) {
+                                    self.unwatch(id);
+                                }
+                            },
+                        }
+                        "OK".to_string()
+                    },
+                    Some(_) => String::new(),
+                    None => ERROR.to_string(),
+                }
+            },
+            "s" | "c" => {
+                if let Some(address) = number(args) {
+                    self.clear_history();
+                    self.machine.cpu.rip = address;
+                }
+                let stop = match command {
+                    "s" => self.step(),
+                    _ => self.resume(),
+                };
+                self.stop_reply(stop)
+            },
+            "D" | "k" => return Ok(None),
+            "H" => "OK".to_string(),
+            _ if packet.starts_with("qSupported") => "PacketSize=1000;qXfer:features:read+;QStartNoAckMode+".to_string(),
+            _ if packet == "QStartNoAckMode" => "OK".to_string(),
+            _ if packet == "qAttached" => "1".to_string(),
+            _ if packet == "qfThreadInfo" => "m1".to_string(),
+            _ if packet == "qsThreadInfo" => "l".to_string(),
+            _ if packet == "qC" => "QC1".to_string(),
+            _ if packet.starts_with("qXfer:features:read:target.xml:") => {
+                let target = target();
+                match range(&packet["qXfer:features:read:target.xml:".len()..]) {
+                    Some((offset, len)) => {
+                        let start = (offset as usize).min(target.len());
+                        let end = (start + len as usize).min(target.len());
+                        let more = if end < target.len() { "m" } else { "l" };
+                        format!("{more}{}", &target[start..end])
+                    },
+                    None => ERROR.to_string(),
+                }
+            },
+            _ => String::new(),
+        };
+        Ok(Some(res))
+    }
+
+    /// Serves a GDB session through `stream`, until GDB detaches, kills the
+    /// target or the stream ends. Continuing can't be interrupted.
+    pub fn serve_gdb(&mut self, stream : impl Read + Write) -> Result<()> {
+        let mut connection = Connection { stream, buffer: vec![], ack: true };
+        let mut watches = BTreeMap::new();
+        while let Some(packet) = connection.receive()? {
+            match self.gdb_command(&packet, &mut watches)? {
+                Some(res) => {
+                    connection.send(&res)?;
+                    if packet == "QStartNoAckMode" {
+                        connection.ack = false;
+                    }
+                },
+                None => {
+                    connection.send("OK")?;
+                    return Ok(());
+                },
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,302 @@
+//! Debugger over the [emulator](crate::emu).
+//!
+//! [`Debugger`] is the library API, stopping a [`Machine`] at breakpoints
+//! and watchpoints and stepping through it, forwards and backwards, and
+//! [`Debugger::repl`] a line oriented front end to it, and
+//! [`Debugger::serve_gdb`] one for GDB.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use crate::{
+    emu::{Bus, Cpu, Machine, Observed},
+    instruction::parse_literal,
+    utils::{Error, Result},
+    DecodeMode, Decoder, Instruction, Register, Width,
+};
+
+mod gdb;
+pub use gdb::Pipe;
+
+mod repl;
+
+mod reverse;
+pub use reverse::Location;
+
+mod watch;
+pub use watch::{Hit, Trigger, Watchpoint};
+
+/// Why running stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// Did what was asked
+    Done,
+
+    /// Reached a breakpoint, before executing the instruction there
+    Breakpoint(u16),
+
+    /// Hit the watchpoint with an id, after the instruction that did
+    Watchpoint(usize, Hit),
+
+    /// The bus halted with an exit code
+    Halted(u16),
+
+    /// Executed as many instructions as the limit without stopping
+    Limit,
+
+    /// Went back to the oldest step in the history
+    Beginning,
+
+    /// Went back over a step that wrote a device at an address, which
+    /// couldn't be undone
+    Irreversible(u16),
+}
+
+/// [`Machine`] with breakpoints and symbols
+#[derive(Debug)]
+pub struct Debugger<B : Bus> {
+    pub machine : Machine<B>,
+
+    /// Most instructions a single command runs
+    pub limit : u64,
+
+    /// Most steps kept to go back through, 1000 by default and none if 0.
+    /// Changing the machine other than by stepping should
+    /// [clear](Self::clear_history) them.
+    pub history_limit : usize,
+    history : VecDeque<Observed>,
+    breakpoints : BTreeSet<u16>,
+    watchpoints : BTreeMap<usize, Watchpoint>,
+    symbols : BTreeMap<String, u16>,
+}
+
+impl<B : Bus> Debugger<B> {
+    pub fn new(machine : Machine<B>) -> Self {
+        Self {
+            machine,
+            limit: 1_000_000,
+            history_limit: 1000,
+            history: VecDeque::new(),
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeMap::new(),
+            symbols: BTreeMap::new(),
+        }
+    }
+
+    /// Adds `symbols` to the ones addresses can be given and shown by
+    pub fn with_symbols(mut self, symbols : impl IntoIterator<Item = (String, u16)>) -> Self {
+        self.symbols.extend(symbols);
+        self
+    }
+
+    pub fn symbols(&self) -> &BTreeMap<String, u16> {
+        &self.symbols
+    }
+
+    /// Address of a symbol, or of an integer literal
+    pub fn address(&self, s : &str) -> Result<u16> {
+        if let Some(address) = self.symbols.get(s) {
+            return Ok(*address);
+        }
+        if !s.starts_with(|c : char| c.is_ascii_digit() || c == '-') {
+            return Err(Error::UndefinedSymbol(s.to_string()));
+        }
+        match parse_literal(s)? {
+            (value @ 0.., _) => Ok(value as u16),
+            _ => Err(Error::InvalidValue(s.to_string())),
+        }
+    }
+
+    /// `address` as the closest symbol at or before it and an offset, like `loop+4`
+    pub fn symbolize(&self, address : u16) -> Option<String> {
+        let (name, start) = self.symbols.iter()
+            .filter(|(_, start)| **start <= address)
+            .max_by_key(|(name, start)| (**start, std::cmp::Reverse(*name)))?;
+        Some(match address - start {
+            0 => name.clone(),
+            offset => format!("{name}+{offset}"),
+        })
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Sets a breakpoint, returning whether there wasn't one already
+    pub fn set_breakpoint(&mut self, address : u16) -> bool {
+        self.breakpoints.insert(address)
+    }
+
+    /// Sets a breakpoint at a symbol, returning its address
+    pub fn break_at(&mut self, symbol : &str) -> Result<u16> {
+        let address = *self.symbols.get(symbol).ok_or_else(|| Error::UndefinedSymbol(symbol.to_string()))?;
+        self.breakpoints.insert(address);
+        Ok(address)
+    }
+
+    /// Removes a breakpoint, returning whether there was one
+    pub fn remove_breakpoint(&mut self, address : u16) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = (usize, &Watchpoint)> + '_ {
+        self.watchpoints.iter().map(|(id, watchpoint)| (*id, watchpoint))
+    }
+
+    /// Sets a watchpoint, returning its id
+    pub fn watch(&mut self, watchpoint : Watchpoint) -> usize {
+        let id = self.watchpoints.keys().next_back().map_or(1, |id| id + 1);
+        self.watchpoints.insert(id, watchpoint);
+        id
+    }
+
+    /// Forgets every step taken, as they can't be undone anymore once the
+    /// machine is changed otherwise
+    pub fn clear_history(&mut self) {
+        self.history.clear();
+    }
+
+    /// Removes a watchpoint, returning whether there was one with that id
+    pub fn unwatch(&mut self, id : usize) -> bool {
+        self.watchpoints.remove(&id).is_some()
+    }
+
+    /// Steps, returning the instruction and the first watchpoint it hit
+    fn step_watched(&mut self) -> Result<(Instruction, Option<Stop>)> {
+        let observed = self.machine.observe()?;
+        let hit = self.watchpoints.iter().find_map(|(id, watchpoint)| {
+            watchpoint.hit(&observed.before, &self.machine.cpu, &observed.accesses).map(|hit| Stop::Watchpoint(*id, hit))
+        });
+
+        let inst = observed.inst;
+        if self.history_limit > 0 {
+            self.history.push_back(observed);
+            if self.history.len() > self.history_limit {
+                self.history.pop_front();
+            }
+        }
+        Ok((inst, hit))
+    }
+
+    /// Steps until `done` holds after an instruction, stopping first at
+    /// breakpoints other than the one at RIP, watchpoints, and if the bus halts
+    fn run(&mut self, mut done : impl FnMut(&Cpu, &Instruction) -> bool) -> Result<Stop> {
+        for idx in 0..self.limit {
+            if let Some(code) = self.machine.bus.halted() {
+                return Ok(Stop::Halted(code));
+            }
+            let rip = self.machine.cpu.rip;
+            if idx > 0 && self.breakpoints.contains(&rip) {
+                return Ok(Stop::Breakpoint(rip));
+            }
+
+            let (inst, hit) = self.step_watched()?;
+            if let Some(stop) = hit {
+                return Ok(stop);
+            }
+            if done(&self.machine.cpu, &inst) {
+                return Ok(Stop::Done);
+            }
+        }
+        Ok(Stop::Limit)
+    }
+
+    /// Executes a single instruction
+    pub fn step(&mut self) -> Result<Stop> {
+        self.run(|_, _| true)
+    }
+
+    /// Executes a single instruction, running calls until they return
+    pub fn step_over(&mut self) -> Result<Stop> {
+        let inst = self.machine.cpu.peek(&self.machine.bus)?;
+        if !matches!(inst, Instruction::CallC(_) | Instruction::CallR(_)) {
+            return self.step();
+        }
+
+        let next = self.machine.cpu.rip.wrapping_add(inst.len());
+        let depth = self.machine.cpu.rsh;
+        self.run(|cpu, _| cpu.rip == next && cpu.rsh == depth)
+    }
+
+    /// Runs until the current function returns
+    pub fn step_out(&mut self) -> Result<Stop> {
+        let depth = self.machine.cpu.rsh;
+        self.run(|cpu, inst| *inst == Instruction::Ret && cpu.rsh < depth)
+    }
+
+    /// Runs until a breakpoint or the bus halts
+    pub fn resume(&mut self) -> Result<Stop> {
+        self.run(|_, _| false)
+    }
+
+    /// Every register with its value, one per line
+    pub fn registers(&self) -> String {
+        let cpu = &self.machine.cpu;
+        let mut registers = vec![Register::RINFO, Register::RIP, Register::RINT, Register::Flags, Register::RSB, Register::RSH];
+        registers.extend((0..10).map(|n| Register::R(Width::Word, n)));
+
+        let mut res = String::new();
+        for reg in registers {
+            res += &format!("{:<5}  {:#06X}", reg.to_string(), cpu.get(reg));
+            if reg == Register::Flags {
+                res += &format!("  {:?}", cpu.flags);
+            }
+            res += "\n";
+        }
+        res
+    }
+
+    /// Hex dump of the `len` bytes from `address` on, 16 per line
+    pub fn memory(&self, address : u16, len : u16) -> String {
+        let mut res = String::new();
+        for line in (0..len).step_by(16) {
+            let start = address.wrapping_add(line);
+            res += &format!("{start:#06X} ");
+            for idx in line..len.min(line.saturating_add(16)) {
+                res += &format!(" {:02X}", self.machine.bus.peek(address.wrapping_add(idx)));
+            }
+            res += "\n";
+        }
+        res
+    }
+
+    /// Decodes `count` instructions from `address` on, invalid bytes being
+    /// [`Instruction::DB`]
+    pub fn disassemble(&self, address : u16, count : usize) -> Vec<(u16, Instruction)> {
+        let bytes = (0..4 * count).map(|idx| self.machine.bus.peek(address.wrapping_add(idx as u16))).collect::<Vec<_>>();
+        Decoder::new(&bytes, DecodeMode::Resync)
+            .with_origin(address)
+            .filter_map(Result::ok)
+            .take(count)
+            .collect()
+    }
+
+    /// Disassembly of up to `before` instructions before RIP, the one at it
+    /// and `after` more. What comes before is decoded from the furthest
+    /// address that leads to RIP.
+    pub fn listing(&self, before : usize, after : usize) -> String {
+        let rip = self.machine.cpu.rip;
+        let mut insts = (0..=4 * before as u16).rev()
+            .filter(|back| *back <= rip)
+            .map(|back| self.disassemble(rip - back, back as usize + 1))
+            .find_map(|insts| {
+                let idx = insts.iter().position(|(address, _)| *address == rip)?;
+                Some(insts[idx.saturating_sub(before)..idx].to_vec())
+            })
+            .unwrap_or_default();
+        insts.extend(self.disassemble(rip, after + 1));
+
+        let mut res = String::new();
+        for (address, inst) in insts {
+            if let Some((name, _)) = self.symbols.iter().find(|(_, start)| **start == address) {
+                res += &format!("{name}:\n");
+            }
+            let marker = if address == rip { "=>" } else { "  " };
+            let breakpoint = if self.breakpoints.contains(&address) { "*" } else { " " };
+            res += &format!("{marker}{breakpoint} {address:#06X}  {inst}\n");
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod test;
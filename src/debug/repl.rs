@@ -0,0 +1,227 @@
+use std::{io::{BufRead, Write}, str::FromStr};
+
+use crate::{emu::Bus, utils::{Error, Result}, Register};
+
+use super::{Debugger, Location, Stop, Trigger, Watchpoint};
+
+const HELP : &str = "\
+break [address]     set a breakpoint, or list them
+delete address      remove a breakpoint
+watch [address [len]]
+                    stop after writes to memory, or list every watchpoint
+rwatch address [len]
+awatch address [len]
+                    stop after reads, or any access, to memory
+watch register [op value]
+                    stop after a register changes, or once `register op
+                    value` holds, op being ==, !=, < or >
+unwatch id          remove a watchpoint
+step [count]        execute instructions
+next                execute an instruction, running calls until they return
+finish              run until the current function returns
+continue            run until a breakpoint or the machine halts
+back [count]        go back instructions
+rcontinue           go back until a breakpoint
+lastwrite register|address
+                    go back to the last instruction that wrote it
+registers           show the registers
+memory address [len]
+                    show memory
+list                disassemble around RIP
+quit
+Addresses are integers or symbols, and an empty line repeats the last command.
+break, delete, step, next, finish, continue, registers, memory, list, help and
+quit can be abbreviated to their first letter.
+";
+
+impl<B : Bus> Debugger<B> {
+    /// Describes where running stopped
+    fn stopped(&self, stop : Stop) -> String {
+        let rip = self.machine.cpu.rip;
+        match stop {
+            Stop::Halted(code) => format!("halted with code {code}\n"),
+            Stop::Limit => format!("stopped after {} instructions\n", self.limit),
+            Stop::Beginning => format!("reached the start of the history\n{}", self.listing(0, 0)),
+            Stop::Irreversible(address) => format!("can't undo the write to {}\n{}", self.describe(address), self.listing(0, 0)),
+            Stop::Breakpoint(_) => format!("breakpoint at {}\n{}", self.describe(rip), self.listing(0, 0)),
+            Stop::Watchpoint(id, hit) => match self.watchpoints.get(&id) {
+                Some(watchpoint) => format!("watchpoint {id}, {watchpoint}: {hit}\n{}", self.listing(0, 0)),
+                None => format!("watchpoint {id}: {hit}\n{}", self.listing(0, 0)),
+            },
+            Stop::Done => self.listing(0, 0),
+        }
+    }
+
+    /// `address` in hex together with its symbol, if any
+    fn describe(&self, address : u16) -> String {
+        match self.symbolize(address) {
+            Some(symbol) => format!("{address:#06X} <{symbol}>"),
+            None => format!("{address:#06X}"),
+        }
+    }
+
+    /// Parses the arguments of a watch command
+    fn watchpoint(&self, command : &str, args : &[&str]) -> Result<Watchpoint> {
+        let invalid = || Error::InvalidOperands(args.join(" "));
+        let register = args.first().and_then(|arg| Register::from_str(arg).ok());
+        if let (Some(reg), "watch") = (register, command) {
+            let trigger = match args[1..] {
+                [] => Trigger::Change,
+                [op, value] => {
+                    let value = self.address(value)?;
+                    match op {
+                        "==" => Trigger::Equal(value),
+                        "!=" => Trigger::NotEqual(value),
+                        "<" => Trigger::Below(value),
+                        ">" => Trigger::Above(value),
+                        _ => return Err(invalid()),
+                    }
+                },
+                _ => return Err(invalid()),
+            };
+            return Ok(Watchpoint::Register { reg, trigger });
+        }
+
+        let (start, len) = match args {
+            [address] => (self.address(address)?, 1),
+            [address, len] => (self.address(address)?, self.address(len)?),
+            _ => return Err(invalid()),
+        };
+        let end = start.checked_add(len.max(1) - 1).ok_or_else(invalid)?;
+        let (read, write) = match command {
+            "rwatch" => (true, false),
+            "awatch" => (true, true),
+            _ => (false, true),
+        };
+        Ok(Watchpoint::Memory { start, end, read, write })
+    }
+
+    /// Runs a single command, returning what to print, or `None` to quit
+    fn command(&mut self, line : &str) -> Result<Option<String>> {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let Some((command, args)) = words.split_first() else {
+            return Ok(Some(String::new()));
+        };
+        let arg = |idx : usize| args.get(idx).copied().ok_or_else(|| Error::InvalidOperands(line.to_string()));
+        let count = |idx : usize| match args.get(idx) {
+            Some(arg) => self.address(arg),
+            None => Ok(1),
+        };
+
+        let res = match *command {
+            "b" | "break" if args.is_empty() => self.breakpoints()
+                .map(|address| format!("{}\n", self.describe(address)))
+                .collect(),
+            "b" | "break" => {
+                let address = self.address(arg(0)?)?;
+                self.set_breakpoint(address);
+                format!("breakpoint at {}\n", self.describe(address))
+            },
+            "d" | "delete" => {
+                let address = self.address(arg(0)?)?;
+                match self.remove_breakpoint(address) {
+                    true => String::new(),
+                    false => format!("no breakpoint at {}\n", self.describe(address)),
+                }
+            },
+            "watch" if args.is_empty() => self.watchpoints()
+                .map(|(id, watchpoint)| format!("{id}: {watchpoint}\n"))
+                .collect(),
+            "watch" | "rwatch" | "awatch" => {
+                let watchpoint = self.watchpoint(command, args)?;
+                let id = self.watch(watchpoint);
+                format!("watchpoint {id}, {watchpoint}\n")
+            },
+            "unwatch" => {
+                let arg = arg(0)?;
+                let id = arg.parse().map_err(|_| Error::InvalidValue(arg.to_string()))?;
+                match self.unwatch(id) {
+                    true => String::new(),
+                    false => format!("no watchpoint {id}\n"),
+                }
+            },
+            "s" | "step" => {
+                let mut stop = Stop::Done;
+                for _ in 0..count(0)? {
+                    stop = self.step()?;
+                    if stop != Stop::Done {
+                        break;
+                    }
+                }
+                self.stopped(stop)
+            },
+            "n" | "next" => {
+                let stop = self.step_over()?;
+                self.stopped(stop)
+            },
+            "f" | "finish" => {
+                let stop = self.step_out()?;
+                self.stopped(stop)
+            },
+            "c" | "continue" => {
+                let stop = self.resume()?;
+                self.stopped(stop)
+            },
+            "back" => {
+                let mut stop = Stop::Done;
+                for _ in 0..count(0)? {
+                    stop = self.step_back()?;
+                    if stop != Stop::Done {
+                        break;
+                    }
+                }
+                self.stopped(stop)
+            },
+            "rcontinue" => {
+                let stop = self.reverse()?;
+                self.stopped(stop)
+            },
+            "lastwrite" => {
+                let arg = arg(0)?;
+                let location = match Register::from_str(arg) {
+                    Ok(reg) => Location::Register(reg),
+                    Err(_) => Location::Memory(self.address(arg)?),
+                };
+                let stop = self.last_write(location)?;
+                self.stopped(stop)
+            },
+            "r" | "registers" => self.registers(),
+            "m" | "memory" => {
+                let len = match args.get(1) {
+                    Some(arg) => self.address(arg)?,
+                    None => 16,
+                };
+                self.memory(self.address(arg(0)?)?, len)
+            },
+            "l" | "list" => self.listing(4, 4),
+            "h" | "help" => HELP.to_string(),
+            "q" | "quit" => return Ok(None),
+            _ => format!("unknown command {command:?}, try \"help\"\n"),
+        };
+        Ok(Some(res))
+    }
+
+    /// Reads commands from `input` until it ends or one quits, writing a
+    /// prompt before each one and what it prints to `output`. Failing
+    /// commands print the error instead of stopping.
+    pub fn repl(&mut self, input : impl BufRead, mut output : impl Write) -> Result<()> {
+        let mut last = String::new();
+        write!(output, "> ")?;
+        output.flush()?;
+        for line in input.lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                last = line;
+            }
+
+            match self.command(&last) {
+                Ok(Some(res)) => write!(output, "{res}")?,
+                Ok(None) => return Ok(()),
+                Err(err) => writeln!(output, "error: {err}")?,
+            }
+            write!(output, "> ")?;
+            output.flush()?;
+        }
+        Ok(())
+    }
+}
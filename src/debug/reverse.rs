@@ -0,0 +1,90 @@
+use crate::{
+    emu::{AccessKind, Bus, Observed},
+    utils::Result,
+    Register, Width,
+};
+
+use super::{Debugger, Stop};
+
+/// Something steps write
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Location {
+    Register(Register),
+    Memory(u16),
+}
+
+impl<B : Bus> Debugger<B> {
+    /// Undoes the last step in the history, writing back the bytes it
+    /// overwrote in memory and putting back the interrupt it delivered,
+    /// together with the first address it wrote that isn't
+    /// [reversible](Bus::reversible). Those are left alone, as devices can't
+    /// undo what they did with the writes.
+    fn undo(&mut self) -> Result<Option<(Observed, Option<u16>)>> {
+        let Some(observed) = self.history.pop_back() else {
+            return Ok(None);
+        };
+        let mut irreversible = None;
+        for (address, value) in observed.overwritten.iter().rev() {
+            match self.machine.bus.reversible(*address) {
+                true => self.machine.bus.write(*address, *value as u16, Width::Byte)?,
+                false => irreversible = Some(*address),
+            }
+        }
+        self.machine.cpu = observed.before.clone();
+        self.machine.cycles = observed.cycles;
+        if let Some(number) = observed.interrupt {
+            self.machine.interrupts.unget(number);
+        }
+        Ok(Some((observed, irreversible)))
+    }
+
+    /// Goes back a single step
+    pub fn step_back(&mut self) -> Result<Stop> {
+        Ok(match self.undo()? {
+            Some((_, Some(address))) => Stop::Irreversible(address),
+            Some((_, None)) => Stop::Done,
+            None => Stop::Beginning,
+        })
+    }
+
+    /// Goes back until RIP is at a breakpoint, or over a step that wrote a
+    /// device
+    pub fn reverse(&mut self) -> Result<Stop> {
+        for _ in 0..self.limit {
+            match self.undo()? {
+                None => return Ok(Stop::Beginning),
+                Some((_, Some(address))) => return Ok(Stop::Irreversible(address)),
+                Some((_, None)) => (),
+            }
+            let rip = self.machine.cpu.rip;
+            if self.breakpoints.contains(&rip) {
+                return Ok(Stop::Breakpoint(rip));
+            }
+        }
+        Ok(Stop::Limit)
+    }
+
+    /// Goes back to right before the last step that wrote `location`,
+    /// registers counting as written when their value changed, stopping
+    /// early after a step that wrote a device
+    pub fn last_write(&mut self, location : Location) -> Result<Stop> {
+        for _ in 0..self.limit {
+            let after = self.machine.cpu.clone();
+            let Some((observed, irreversible)) = self.undo()? else {
+                return Ok(Stop::Beginning);
+            };
+            let wrote = match location {
+                Location::Register(reg) => observed.before.get(reg) != after.get(reg),
+                Location::Memory(address) => observed.accesses.iter()
+                    .any(|access| access.kind == AccessKind::Write && access.overlaps(address, address)),
+            };
+            if wrote {
+                return Ok(Stop::Done);
+            }
+            if let Some(address) = irreversible {
+                return Ok(Stop::Irreversible(address));
+            }
+        }
+        Ok(Stop::Limit)
+    }
+}
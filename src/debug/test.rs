@@ -0,0 +1,405 @@
+use super::*;
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{
+    asm::assemble,
+    emu::{device::{Console, Halt}, Access, AccessKind, Flags, Memory, MemoryMap, Ram, Rom},
+};
+
+const PROGRAM : &str = "
+        mov 0x0003, r0
+        call double
+        call double
+    end:
+        mov end, r1
+        ajmp r1
+
+    double:
+        add r0, r0
+        ret
+";
+
+fn debugger() -> Debugger<Memory> {
+    let assembly = assemble(PROGRAM).unwrap();
+    let mut machine = Machine::new(Memory::with(0, &assembly.image));
+    machine.cpu.rsb = 0x8000;
+    machine.cpu.rsh = 0x8000;
+    Debugger::new(machine).with_symbols(assembly.symbols)
+}
+
+#[test]
+fn symbols() {
+    let debugger = debugger();
+    assert_eq!(debugger.address("double"), Ok(0x0012));
+    assert_eq!(debugger.address("0x20"), Ok(0x0020));
+    assert_eq!(debugger.address("18"), Ok(0x0012));
+    assert_eq!(debugger.address("nope"), Err(Error::UndefinedSymbol("nope".to_string())));
+    assert_eq!(debugger.address("-1"), Err(Error::InvalidValue("-1".to_string())));
+
+    assert_eq!(debugger.symbolize(0x0012), Some("double".to_string()));
+    assert_eq!(debugger.symbolize(0x0014), Some("double+2".to_string()));
+    assert_eq!(debugger.symbolize(0x000E), Some("end+2".to_string()));
+    assert_eq!(debugger.symbolize(0x0002), None);
+}
+
+#[test]
+fn breakpoints() {
+    let mut debugger = debugger();
+    assert_eq!(debugger.break_at("double"), Ok(0x0012));
+    assert_eq!(debugger.break_at("nope"), Err(Error::UndefinedSymbol("nope".to_string())));
+    assert_eq!(debugger.breakpoints().collect::<Vec<_>>(), vec![0x0012]);
+
+    assert_eq!(debugger.resume(), Ok(Stop::Breakpoint(0x0012)));
+    assert_eq!(debugger.machine.cpu.r[0], 3);
+    assert_eq!(debugger.resume(), Ok(Stop::Breakpoint(0x0012)));
+    assert_eq!(debugger.machine.cpu.r[0], 6);
+
+    assert!(debugger.remove_breakpoint(0x0012));
+    assert!(!debugger.remove_breakpoint(0x0012));
+    debugger.limit = 100;
+    assert_eq!(debugger.resume(), Ok(Stop::Limit));
+    assert_eq!(debugger.machine.cpu.r[0], 12);
+}
+
+#[test]
+fn stepping() {
+    let mut debugger = debugger();
+    assert_eq!(debugger.step(), Ok(Stop::Done));
+    assert_eq!(debugger.machine.cpu.rip, 0x0004);
+
+    // Over the first call, then into the second one
+    assert_eq!(debugger.step_over(), Ok(Stop::Done));
+    assert_eq!((debugger.machine.cpu.rip, debugger.machine.cpu.r[0]), (0x0008, 6));
+    assert_eq!(debugger.step(), Ok(Stop::Done));
+    assert_eq!(debugger.machine.cpu.rip, 0x0012);
+    assert_eq!(debugger.step_out(), Ok(Stop::Done));
+    assert_eq!((debugger.machine.cpu.rip, debugger.machine.cpu.r[0]), (0x000C, 12));
+
+    // Stepping over something that isn't a call
+    assert_eq!(debugger.step_over(), Ok(Stop::Done));
+    assert_eq!(debugger.machine.cpu.rip, 0x0010);
+
+    // Breakpoints inside calls still stop them
+    let mut debugger = self::debugger();
+    debugger.set_breakpoint(0x0014);
+    debugger.step().unwrap();
+    assert_eq!(debugger.step_over(), Ok(Stop::Breakpoint(0x0014)));
+}
+
+#[test]
+fn watchpoints() {
+    let mut debugger = debugger();
+    let stack = debugger.watch(Watchpoint::Memory { start: 0x8000, end: 0x8001, read: false, write: true });
+    let write = Access { kind: AccessKind::Write, address: 0x8000, width: Width::Word };
+    assert_eq!(debugger.resume(), Ok(Stop::Watchpoint(stack, Hit::Access(write))));
+    assert_eq!(debugger.machine.cpu.rip, 0x0012);
+
+    // The byte register changes with the word one
+    let byte = debugger.watch(Watchpoint::Register { reg: Register::rb0(), trigger: Trigger::Change });
+    assert_eq!(debugger.resume(), Ok(Stop::Watchpoint(byte, Hit::Register { old: 3, new: 6 })));
+    assert!(debugger.unwatch(byte));
+    assert!(!debugger.unwatch(byte));
+
+    let depth = debugger.watch(Watchpoint::Register { reg: Register::RSH, trigger: Trigger::Equal(0x8000) });
+    assert_eq!(debugger.resume(), Ok(Stop::Watchpoint(depth, Hit::Register { old: 0x8002, new: 0x8000 })));
+    assert_eq!(debugger.machine.cpu.rip, 0x0008);
+    assert_eq!(debugger.watchpoints().map(|(id, _)| id).collect::<Vec<_>>(), vec![stack, depth]);
+
+    // Interrupt delivery pushes the number last
+    let mut debugger = self::debugger();
+    debugger.machine.cpu.flags.insert(Flags::INTERRUPT);
+    debugger.machine.cpu.rint = 0x0012;
+    debugger.machine.interrupts.raise(9);
+    let number = debugger.watch(Watchpoint::Memory { start: 0x8004, end: 0x8004, read: true, write: true });
+    let write = Access { kind: AccessKind::Write, address: 0x8004, width: Width::Word };
+    assert_eq!(debugger.step(), Ok(Stop::Watchpoint(number, Hit::Access(write))));
+    assert_eq!(debugger.machine.cpu.rip, 0x0014);
+}
+
+#[test]
+fn inspection() {
+    let mut debugger = debugger();
+    debugger.set_breakpoint(0x0012);
+    debugger.resume().unwrap();
+
+    assert_eq!(debugger.registers(), "\
+RINFO  0x0000
+RIP    0x0012
+RINT   0x0000
+Flags  0x0000  Flags()
+RSB    0x8000
+RSH    0x8002
+R0     0x0003
+R1     0x0000
+R2     0x0000
+R3     0x0000
+R4     0x0000
+R5     0x0000
+R6     0x0000
+R7     0x0000
+R8     0x0000
+R9     0x0000
+");
+    assert_eq!(debugger.memory(0x7FFC, 20), "\
+0x7FFC  00 00 00 00 08 00 00 00 00 00 00 00 00 00 00 00
+0x800C  00 00 00 00
+");
+    assert_eq!(debugger.listing(2, 1), "\
+end:
+    0x000C  mov 0x000C, r1
+    0x0010  ajmp r1
+double:
+=>* 0x0012  add r0, r0
+    0x0014  ret
+");
+}
+
+#[test]
+fn repl() {
+    let mut debugger = debugger();
+    debugger.limit = 100;
+    let input = "break double\nc\n\nm rsh 2\nb\nd double\nd 0\nn\nnope\nwatch r1 == end\nawatch 0x8000 2\nwatch\nc\nunwatch 2\nunwatch 2\nc\nc\nq\nstep\n";
+    let mut output = vec![];
+    debugger.repl(input.as_bytes(), &mut output).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), "\
+> breakpoint at 0x0012 <double>
+> breakpoint at 0x0012 <double>
+double:
+=>* 0x0012  add r0, r0
+> breakpoint at 0x0012 <double>
+double:
+=>* 0x0012  add r0, r0
+> error: undefined symbol: \"rsh\"
+> 0x0012 <double>
+> > no breakpoint at 0x0000
+> =>  0x0014  ret
+> unknown command \"nope\", try \"help\"
+> watchpoint 1, R1 == 0x000C
+> watchpoint 2, access of 0x8000..=0x8001
+> 1: R1 == 0x000C
+2: access of 0x8000..=0x8001
+> watchpoint 2, access of 0x8000..=0x8001: read Word at 0x8000
+end:
+=>  0x000C  mov 0x000C, r1
+> > no watchpoint 2
+> watchpoint 1, R1 == 0x000C: 0x0000 -> 0x000C
+=>  0x0010  ajmp r1
+> stopped after 100 instructions
+> ");
+}
+
+#[test]
+fn reverse() {
+    let assembly = assemble("
+            mov 0x0005, r1
+            mov r1, r2
+            add r2, r2
+            mov 0x0100, r3
+            movrm r2, r3
+            mov r2, r0
+            add r1, r0
+            push r0
+            nop
+    ").unwrap();
+    let mut machine = Machine::new(Memory::with(0, &assembly.image));
+    machine.cpu.rsb = 0x8000;
+    machine.cpu.rsh = 0x8000;
+    let mut debugger = Debugger::new(machine);
+    for _ in 0..8 {
+        debugger.step().unwrap();
+    }
+    assert_eq!(debugger.machine.cpu.r[0], 15);
+    assert_eq!(debugger.machine.bus.read_word(0x8000), 15);
+
+    // Back through the chain that computed r0
+    assert_eq!(debugger.last_write(Location::Register(Register::r0())), Ok(Stop::Done));
+    assert_eq!((debugger.machine.cpu.rip, debugger.machine.cpu.r[0]), (0x0010, 10));
+    assert_eq!(debugger.machine.bus.read_word(0x8000), 0);
+    assert_eq!(debugger.last_write(Location::Register(Register::rb0())), Ok(Stop::Done));
+    assert_eq!((debugger.machine.cpu.rip, debugger.machine.cpu.r[0]), (0x000E, 0));
+    assert_eq!(debugger.last_write(Location::Memory(0x0101)), Ok(Stop::Done));
+    assert_eq!(debugger.machine.cpu.rip, 0x000C);
+    assert_eq!(debugger.machine.bus.read_word(0x0100), 0);
+
+    assert_eq!(debugger.step_back(), Ok(Stop::Done));
+    assert_eq!(debugger.machine.cpu.rip, 0x0008);
+    debugger.set_breakpoint(0x0004);
+    assert_eq!(debugger.reverse(), Ok(Stop::Breakpoint(0x0004)));
+    assert_eq!(debugger.reverse(), Ok(Stop::Beginning));
+    assert_eq!(debugger.step_back(), Ok(Stop::Beginning));
+    assert_eq!((debugger.machine.cpu.rip, debugger.machine.cycles), (0x0000, 0));
+    assert_eq!(debugger.last_write(Location::Register(Register::r0())), Ok(Stop::Beginning));
+
+    // And forwards again
+    debugger.set_breakpoint(0x0014);
+    assert_eq!(debugger.resume(), Ok(Stop::Breakpoint(0x0004)));
+    assert_eq!(debugger.resume(), Ok(Stop::Breakpoint(0x0014)));
+    assert_eq!(debugger.machine.cpu.r[0], 15);
+
+    let mut output = vec![];
+    debugger.repl("lastwrite r2\nback 2\nrcontinue\n".as_bytes(), &mut output).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), "\
+> =>  0x0006  add r2, r2
+> =>  0x0000  mov 0x0005, r1
+> reached the start of the history
+=>  0x0000  mov 0x0005, r1
+> ");
+}
+
+#[test]
+fn history() {
+    let mut debugger = debugger();
+    debugger.history_limit = 2;
+    for _ in 0..3 {
+        debugger.step().unwrap();
+    }
+    assert_eq!(debugger.step_back(), Ok(Stop::Done));
+    assert_eq!(debugger.step_back(), Ok(Stop::Done));
+    assert_eq!(debugger.step_back(), Ok(Stop::Beginning));
+    assert_eq!(debugger.machine.cpu.rip, 0x0004);
+
+    // Or none at all
+    let mut debugger = self::debugger();
+    assert_eq!(debugger.history_limit, 1000);
+    debugger.history_limit = 0;
+    debugger.step().unwrap();
+    assert_eq!(debugger.step_back(), Ok(Stop::Beginning));
+    debugger.history_limit = 1;
+    debugger.step().unwrap();
+    debugger.clear_history();
+    assert_eq!(debugger.step_back(), Ok(Stop::Beginning));
+
+    // Delivering an interrupt is undone too
+    let mut debugger = self::debugger();
+    debugger.machine.cpu.flags.insert(Flags::INTERRUPT);
+    debugger.machine.interrupts.raise(9);
+    debugger.step().unwrap();
+    assert_eq!(debugger.machine.bus.read_word(0x8004), 9);
+    assert_eq!(debugger.step_back(), Ok(Stop::Done));
+    assert_eq!(debugger.machine.bus.read_word(0x8004), 0);
+    assert_eq!(debugger.machine.interrupts.pending(), vec![9]);
+    assert_eq!(debugger.machine.cpu, Cpu { rsb: 0x8000, rsh: 0x8000, flags: Flags::INTERRUPT, ..Cpu::new() });
+}
+
+#[test]
+fn irreversible() {
+    let image = assemble("
+            mov 0xFF00, r1
+            mov 0x0041, r0
+            movrm r0, r1
+            mov 0x8000, r2
+            movrm r0, r2
+            mov 0xFF20, r1
+            movrm r0, r1
+    ").unwrap().image;
+    let console = Rc::new(RefCell::new(Console::new(vec![], std::io::empty())));
+    let mut map = MemoryMap::new();
+    map.map(0x0000, image.len() as u32, Rom(image)).unwrap();
+    map.map(0x8000, 0x100, Ram::new(0x100)).unwrap();
+    map.map(0xFF00, Console::<Vec<u8>, std::io::Empty>::SIZE, console.clone()).unwrap();
+    map.map(0xFF20, Halt::SIZE, Halt::new()).unwrap();
+    let mut debugger = Debugger::new(Machine::new(map));
+    for _ in 0..7 {
+        debugger.step().unwrap();
+    }
+    assert_eq!(debugger.machine.bus.halted(), Some(0x41));
+
+    // Devices keep what they got, memory doesn't
+    let mut output = vec![];
+    debugger.repl("back\n".as_bytes(), &mut output).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), "> can't undo the write to 0xFF20\n=>  0x0014  movrm r0, r1\n> ");
+    assert_eq!(debugger.machine.bus.halted(), Some(0x41));
+    assert_eq!(debugger.machine.cpu.rip, 0x0014);
+    assert_eq!(debugger.reverse(), Ok(Stop::Irreversible(0xFF00)));
+    assert_eq!(debugger.machine.bus.peek(0x8000), 0);
+    assert_eq!(debugger.machine.cpu.rip, 0x0008);
+    assert_eq!(console.borrow().output(), b"A");
+    assert_eq!(debugger.step_back(), Ok(Stop::Done));
+    assert_eq!(debugger.step_back(), Ok(Stop::Done));
+    assert_eq!(debugger.step_back(), Ok(Stop::Beginning));
+}
+
+/// Packet with its checksum
+fn packet(data : &str) -> String {
+    format!("${data}#{:02x}", data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte)))
+}
+
+/// Acknowledgments and packet contents of what a GDB stub wrote
+fn replies(output : &[u8]) -> (String, Vec<String>) {
+    let output = String::from_utf8(output.to_vec()).unwrap();
+    let (mut acks, mut packets, mut rest) = (String::new(), vec![], output.as_str());
+    while let Some(first) = rest.chars().next() {
+        if first == '$' {
+            let end = rest.find('#').unwrap();
+            packets.push(rest[1..end].to_string());
+            rest = &rest[end + 3..];
+        } else {
+            acks.push(first);
+            rest = &rest[1..];
+        }
+    }
+    (acks, packets)
+}
+
+#[test]
+fn gdb() {
+    let mut debugger = debugger();
+    let script = [
+        "$?#00", &packet("qSupported:swbreak+"), "+", &packet("QStartNoAckMode"), "+",
+        &packet("qXfer:features:read:target.xml:0,fff"), &packet("qXfer:features:read:target.xml:10,10"),
+        &packet("?"), &packet("Z0,12,2"), &packet("c"), &packet("p1"), &packet("p6"), &packet("m8000,2"),
+        &packet("M8000,2:0c00"), &packet("z0,12,2"), &packet("s"), &packet("Z3,8000,2"), &packet("c"),
+        &packet("g"), &packet("P7=ffff"), &packet("p7"), &packet("p10"), &packet("M0,1:00"),
+        &packet("vCont?"), &packet("k"), &packet("?"),
+    ].concat();
+    let mut output = vec![];
+    debugger.serve_gdb(Pipe(script.as_bytes(), &mut output)).unwrap();
+
+    let (acks, replies) = replies(&output);
+    assert_eq!(acks, "-++");
+    assert_eq!(replies[0], "PacketSize=1000;qXfer:features:read+;QStartNoAckMode+");
+    assert_eq!(replies[1], "OK");
+    assert!(replies[2].starts_with("l<?xml version=\"1.0\"?>"));
+    assert!(replies[2].contains("<reg name=\"rinfo\" bitsize=\"16\" type=\"uint16\"/>\n<reg name=\"rip\" bitsize=\"16\" type=\"code_ptr\"/>"));
+    assert!(replies[2].contains("<reg name=\"r9\" bitsize=\"16\" type=\"uint16\"/>\n</feature>"));
+    assert_eq!(replies[3], "m.0\"?>\n<!DOCTYPE ");
+    assert_eq!(replies[4..].to_vec(), vec![
+        "S05", "OK",
+        // The breakpoint in double
+        "S05", "1200", "0300", "0800",
+        // Returning to end instead
+        "OK", "OK", "S05", "OK", "T05rwatch:8000;",
+        "00000c0000000000008000800600000000000000000000000000000000000000", "OK", "ffff",
+        "E01", "OK", "", "OK",
+    ]);
+    assert_eq!(debugger.machine.cpu.rip, 0x000C);
+
+    // Steps before editing the machine can't be undone
+    assert_eq!(debugger.step_back(), Ok(Stop::Beginning));
+}
+
+#[test]
+fn gdb_tcp() {
+    use std::{io::{Read, Write}, net::{TcpListener, TcpStream}};
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = std::thread::spawn(move || {
+        let mut debugger = debugger();
+        debugger.serve_gdb(listener.accept().unwrap().0).unwrap();
+        debugger.machine.cpu.r[0]
+    });
+
+    let mut client = TcpStream::connect(address).unwrap();
+    let mut exchange = |data : &str, len : usize| {
+        client.write_all(packet(data).as_bytes()).unwrap();
+        let mut reply = vec![0; len];
+        client.read_exact(&mut reply).unwrap();
+        client.write_all(b"+").unwrap();
+        String::from_utf8(reply).unwrap()
+    };
+    assert_eq!(exchange("s", 8), format!("+{}", packet("S05")));
+    assert_eq!(exchange("p6", 9), format!("+{}", packet("0300")));
+    assert_eq!(exchange("D", 7), format!("+{}", packet("OK")));
+    assert_eq!(server.join().unwrap(), 3);
+}
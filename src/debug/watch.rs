@@ -0,0 +1,138 @@
+use crate::{
+    emu::{Access, AccessKind, Cpu},
+    Register,
+};
+
+/// When a register watchpoint stops, comparing values as unsigned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Change,
+    Equal(u16),
+    NotEqual(u16),
+    Below(u16),
+    Above(u16),
+}
+
+impl Trigger {
+    /// Whether a register going from `old` to `new` triggers it: it changes,
+    /// or the condition starts holding
+    pub fn fires(&self, old : u16, new : u16) -> bool {
+        let holds = |value : u16| match self {
+            Self::Change => false,
+            Self::Equal(other) => value == *other,
+            Self::NotEqual(other) => value != *other,
+            Self::Below(other) => value < *other,
+            Self::Above(other) => value > *other,
+        };
+        match self {
+            Self::Change => old != new,
+            _ => holds(new) && !holds(old),
+        }
+    }
+}
+
+/// Stops execution after an instruction that touches memory or a register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Watchpoint {
+    /// Reads, writes or both of any address in `start..=end`, including
+    /// those of the stack and interrupt delivery
+    Memory { start : u16, end : u16, read : bool, write : bool },
+
+    /// Value of a register. Byte registers are the low byte of word ones, so
+    /// writing either can fire a watchpoint on the other.
+    Register { reg : Register, trigger : Trigger },
+}
+
+/// What made a [`Watchpoint`] stop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hit {
+    Access(Access),
+    Register { old : u16, new : u16 },
+}
+
+impl Watchpoint {
+    /// Hit of going from `before` to `after` making `accesses`, if any
+    pub fn hit(&self, before : &Cpu, after : &Cpu, accesses : &[Access]) -> Option<Hit> {
+        match *self {
+            Self::Memory { start, end, read, write } => accesses.iter()
+                .find(|access| match access.kind {
+                    AccessKind::Read => read,
+                    AccessKind::Write => write,
+                } && access.overlaps(start, end))
+                .map(|access| Hit::Access(*access)),
+            Self::Register { reg, trigger } => {
+                let (old, new) = (before.get(reg), after.get(reg));
+                trigger.fires(old, new).then_some(Hit::Register { old, new })
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Memory { start, end, read, write } => {
+                let kind = match (read, write) {
+                    (true, true) => "access",
+                    (true, false) => "read",
+                    (false, true) => "write",
+                    (false, false) => "nothing",
+                };
+                write!(f, "{kind} of {start:#06X}..={end:#06X}")
+            },
+            Self::Register { reg, trigger } => match trigger {
+                Trigger::Change => write!(f, "{reg} changes"),
+                Trigger::Equal(value) => write!(f, "{reg} == {value:#06X}"),
+                Trigger::NotEqual(value) => write!(f, "{reg} != {value:#06X}"),
+                Trigger::Below(value) => write!(f, "{reg} < {value:#06X}"),
+                Trigger::Above(value) => write!(f, "{reg} > {value:#06X}"),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for Hit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Access(access) => {
+                let kind = if access.kind == AccessKind::Read { "read" } else { "write" };
+                write!(f, "{kind} {:?} at {:#06X}", access.width, access.address)
+            },
+            Self::Register { old, new } => write!(f, "{old:#06X} -> {new:#06X}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Width;
+
+    #[test]
+    fn triggers() {
+        assert!(Trigger::Change.fires(1, 2));
+        assert!(!Trigger::Change.fires(2, 2));
+        assert!(Trigger::Equal(5).fires(4, 5));
+        assert!(!Trigger::Equal(5).fires(5, 5));
+        assert!(Trigger::NotEqual(5).fires(5, 0));
+        assert!(Trigger::Below(0x8000).fires(0x8000, 0x7FFE));
+        assert!(!Trigger::Below(0x8000).fires(0x7FFE, 0x7FFC));
+        assert!(Trigger::Above(0x80FF).fires(0x80FE, 0x8100));
+    }
+
+    #[test]
+    fn hits() {
+        let before = Cpu { r: [0x1234, 0, 0, 0, 0, 0, 0, 0, 0, 0], ..Cpu::new() };
+        let after = Cpu { r: [0x1256, 0, 0, 0, 0, 0, 0, 0, 0, 0], ..Cpu::new() };
+        let changes = |reg| Watchpoint::Register { reg, trigger: Trigger::Change }.hit(&before, &after, &[]);
+        assert_eq!(changes(Register::r0()), Some(Hit::Register { old: 0x1234, new: 0x1256 }));
+        assert_eq!(changes(Register::rb0()), Some(Hit::Register { old: 0x34, new: 0x56 }));
+        assert_eq!(changes(Register::r1()), None);
+
+        let write = Access { kind: AccessKind::Write, address: 0x7FFF, width: Width::Word };
+        let watch = |read, write| Watchpoint::Memory { start: 0x8000, end: 0x8001, read, write };
+        assert_eq!(watch(true, true).hit(&before, &before, &[write]), Some(Hit::Access(write)));
+        assert_eq!(watch(true, false).hit(&before, &before, &[write]), None);
+        assert_eq!(watch(true, true).hit(&before, &before, &[Access { width: Width::Byte, ..write }]), None);
+    }
+}
@@ -0,0 +1,208 @@
+use std::io::Read;
+
+use crate::{utils::Result, Instruction};
+
+/// What a [`Decoder`] does when it finds bytes that aren't a valid instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeMode {
+    /// Yield the error and stop decoding
+    Strict,
+
+    /// Yield the first byte as [`Instruction::DB`] and continue with the next one
+    Resync,
+}
+
+enum Source<'a> {
+    Bytes(&'a [u8]),
+    Reader(Box<dyn Read + 'a>, Vec<u8>),
+}
+
+impl Source<'_> {
+    /// Returns the pending bytes, at least as many as the longest instruction
+    /// unless the source is exhausted
+    fn peek(&mut self) -> Result<&[u8]> {
+        match self {
+            Source::Bytes(bytes) => Ok(bytes),
+            Source::Reader(reader, buffer) => {
+                let mut chunk = [0; 4];
+                while buffer.len() < chunk.len() {
+                    let read = match reader.read(&mut chunk[..4 - buffer.len()]) {
+                        Ok(read) => read,
+                        Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                        Err(err) => return Err(err.into()),
+                    };
+                    if read == 0 {
+                        break;
+                    }
+                    buffer.extend_from_slice(&chunk[..read]);
+                }
+                Ok(buffer)
+            },
+        }
+    }
+
+    fn consume(&mut self, len : usize) {
+        match self {
+            Source::Bytes(bytes) => *bytes = &bytes[len..],
+            Source::Reader(_, buffer) => { buffer.drain(..len); },
+        }
+    }
+}
+
+/// Iterator over the instructions of a program, yielding each one with its address
+pub struct Decoder<'a> {
+    source : Source<'a>,
+    address : u16,
+    mode : DecodeMode,
+    done : bool,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(bytes : &'a [u8], mode : DecodeMode) -> Self {
+        Self { source: Source::Bytes(bytes), address: 0, mode, done: false }
+    }
+
+    pub fn from_reader(reader : impl Read + 'a, mode : DecodeMode) -> Self {
+        Self { source: Source::Reader(Box::new(reader), Vec::new()), address: 0, mode, done: false }
+    }
+
+    /// Sets the address of the first byte
+    pub fn with_origin(mut self, origin : u16) -> Self {
+        self.address = origin;
+        self
+    }
+
+    pub fn address(&self) -> u16 {
+        self.address
+    }
+}
+
+impl Iterator for Decoder<'_> {
+    type Item = Result<(u16, Instruction)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let bytes = match self.source.peek() {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                self.done = true;
+                return Some(Err(err));
+            },
+        };
+        if bytes.is_empty() {
+            self.done = true;
+            return None;
+        }
+
+        let (inst, len) = match (Instruction::decode(bytes), self.mode) {
+            (Ok(res), _) => res,
+            (Err(_), DecodeMode::Resync) => (Instruction::db(bytes[0]), 1),
+            (Err(err), DecodeMode::Strict) => {
+                self.done = true;
+                return Some(Err(err));
+            },
+        };
+
+        let address = self.address;
+        self.source.consume(len);
+        self.address = self.address.wrapping_add(len as u16);
+        Some(Ok((address, inst)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{utils::Error, Register, Value};
+
+    fn program() -> Vec<Instruction> {
+        vec![
+            Instruction::movc2r(Value::word(0x1234), Register::r0()).unwrap(),
+            Instruction::push(Register::r0()).unwrap(),
+            Instruction::addr2r(Register::rb0(), Register::rb1()).unwrap(),
+            Instruction::callc(Value::word(0x0010)).unwrap(),
+            Instruction::ret(),
+        ]
+    }
+
+    fn expected() -> Vec<(u16, Instruction)> {
+        let mut address = 0;
+        program().into_iter().map(|inst| {
+            let res = (address, inst);
+            address += inst.len();
+            res
+        }).collect()
+    }
+
+    #[test]
+    fn bytes() {
+        let bytes = program().iter().flat_map(Instruction::compile).collect::<Vec<_>>();
+        let decoded = Decoder::new(&bytes, DecodeMode::Strict).collect::<Result<Vec<_>>>();
+        assert_eq!(decoded, Ok(expected()));
+    }
+
+    #[test]
+    fn reader() {
+        let bytes = program().iter().flat_map(Instruction::compile).collect::<Vec<_>>();
+        let decoded = Decoder::from_reader(&bytes[..], DecodeMode::Strict).collect::<Result<Vec<_>>>();
+        assert_eq!(decoded, Ok(expected()));
+    }
+
+    #[test]
+    fn origin() {
+        let bytes = Instruction::ret().compile();
+        let mut decoder = Decoder::new(&bytes, DecodeMode::Strict).with_origin(0x8000);
+        assert_eq!(decoder.next(), Some(Ok((0x8000, Instruction::Ret))));
+        assert_eq!(decoder.address(), 0x8002);
+        assert_eq!(decoder.next(), None);
+    }
+
+    #[test]
+    fn strict() {
+        let bytes = [0x33, 0x00, 0xFF, 0x33, 0x00];
+        let mut decoder = Decoder::new(&bytes, DecodeMode::Strict);
+        assert_eq!(decoder.next(), Some(Ok((0, Instruction::Ret))));
+        assert_eq!(decoder.next(), Some(Err(Error::UnknownOpcode(0xFF))));
+        assert_eq!(decoder.next(), None);
+    }
+
+    #[test]
+    fn resync() {
+        let bytes = [0x33, 0x00, 0xFF, 0x33, 0x00, 0x02];
+        for decoder in [Decoder::new(&bytes, DecodeMode::Resync), Decoder::from_reader(&bytes[..], DecodeMode::Resync)] {
+            assert_eq!(decoder.collect::<Result<Vec<_>>>(), Ok(vec![
+                (0, Instruction::Ret),
+                (2, Instruction::DB(0xFF)),
+                (3, Instruction::Ret),
+                (5, Instruction::DB(0x02)),
+            ]));
+        }
+    }
+
+    #[test]
+    fn strict_truncated() {
+        let bytes = [0x33, 0x00, 0x02, 0x60];
+        let decoder = Decoder::from_reader(&bytes[..], DecodeMode::Strict);
+        assert_eq!(decoder.collect::<Vec<_>>(), vec![
+            Ok((0, Instruction::Ret)),
+            Err(Error::TruncatedInstruction(4, 2)),
+        ]);
+    }
+
+    #[test]
+    fn reader_error() {
+        struct Failing;
+        impl Read for Failing {
+            fn read(&mut self, _ : &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("broken"))
+            }
+        }
+
+        let mut decoder = Decoder::from_reader(Failing, DecodeMode::Resync);
+        assert_eq!(decoder.next(), Some(Err(Error::Io("broken".to_string()))));
+        assert_eq!(decoder.next(), None);
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::Instruction;
+
+use super::{text, Disassembler, Line};
+
+/// Kind of an [`Edge`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EdgeKind {
+    /// To the next instruction, also after calls and conditional jumps that
+    /// aren't taken
+    Next,
+
+    /// Conditional jump, taken
+    Branch,
+
+    /// `Jmp` or `AJmp`
+    Jump,
+
+    /// `CallC` or `CallR`
+    Call,
+
+    /// `Ret`, to wherever the return address points
+    Return,
+}
+
+/// Way out of a [`Block`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Edge {
+    /// Start of the block
+    pub from : u16,
+
+    /// Where it goes, `None` if it goes through a register whose value
+    /// couldn't be told, or returns
+    pub to : Option<u16>,
+    pub kind : EdgeKind,
+}
+
+/// Instructions that execute one after the other, entered only through the
+/// first one and left only after the last one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub start : u16,
+    pub lines : Vec<Line>,
+}
+
+impl Block {
+    /// Address after the last instruction
+    pub fn end(&self) -> u16 {
+        let last = self.lines.last().expect("blocks aren't empty");
+        last.address.wrapping_add(last.inst.len())
+    }
+}
+
+/// Control flow graph of the instructions of a listing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cfg {
+    /// By start
+    pub blocks : BTreeMap<u16, Block>,
+
+    /// Sorted
+    pub edges : Vec<Edge>,
+}
+
+/// Whether a block ends after `inst`
+fn ends(inst : &Instruction) -> bool {
+    use Instruction::*;
+    matches!(inst, AJmp(_) | Jmp(_) | Jeq(_) | Jneq(_) | Jlt(_) | Jgt(_) | Jleq(_) | Jgeq(_) | Jo(_) | Jno(_) | CallC(_) | CallR(_) | Ret)
+}
+
+impl Cfg {
+    /// Splits the instructions of `lines` into blocks, at the targets of
+    /// jumps, calls and `sti`, and after jumps, calls and returns. `db` lines
+    /// are data, and split blocks too.
+    pub fn new(lines : &[Line]) -> Self {
+        let code = lines.iter().filter(|line| !matches!(line.inst, Instruction::DB(_))).collect::<Vec<_>>();
+        let addresses = code.iter().map(|line| line.address).collect::<BTreeSet<_>>();
+
+        let mut leaders = code.iter().filter_map(|line| line.target).filter(|target| addresses.contains(target)).collect::<BTreeSet<_>>();
+        let mut previous : Option<&Line> = None;
+        for line in code.iter() {
+            let follows = previous.is_some_and(|previous| previous.address.wrapping_add(previous.inst.len()) == line.address);
+            if !follows || previous.is_some_and(|previous| ends(&previous.inst)) {
+                leaders.insert(line.address);
+            }
+            previous = Some(line);
+        }
+
+        let mut blocks = BTreeMap::<u16, Block>::new();
+        let mut start = 0;
+        for line in code {
+            if leaders.contains(&line.address) {
+                start = line.address;
+            }
+            blocks.entry(start).or_insert_with(|| Block { start, lines: vec![] }).lines.push(line.clone());
+        }
+
+        let mut edges = vec![];
+        for block in blocks.values() {
+            use Instruction::*;
+            let last = block.lines.last().expect("blocks aren't empty");
+            let mut edge = |to, kind| edges.push(Edge { from: block.start, to, kind });
+            match last.inst {
+                Jeq(_) | Jneq(_) | Jlt(_) | Jgt(_) | Jleq(_) | Jgeq(_) | Jo(_) | Jno(_) => {
+                    edge(last.target, EdgeKind::Branch);
+                    edge(Some(block.end()), EdgeKind::Next);
+                },
+                AJmp(_) | Jmp(_) => edge(last.target, EdgeKind::Jump),
+                CallC(_) | CallR(_) => {
+                    edge(last.target, EdgeKind::Call);
+                    edge(Some(block.end()), EdgeKind::Next);
+                },
+                Ret => edge(None, EdgeKind::Return),
+                _ => edge(Some(block.end()), EdgeKind::Next),
+            }
+        }
+        edges.sort();
+
+        Self { blocks, edges }
+    }
+
+    /// Block holding the instruction at `address`
+    pub fn block(&self, address : u16) -> Option<&Block> {
+        self.blocks.range(..=address).next_back()
+            .map(|(_, block)| block)
+            .filter(|block| block.lines.iter().any(|line| line.address == address))
+    }
+
+    /// Edges out of the block starting at `start`
+    pub fn successors(&self, start : u16) -> impl Iterator<Item = &Edge> + '_ {
+        self.edges.iter().filter(move |edge| edge.from == start)
+    }
+
+    /// Edges into the block starting at `start`
+    pub fn predecessors(&self, start : u16) -> impl Iterator<Item = &Edge> + '_ {
+        self.edges.iter().filter(move |edge| edge.to == Some(start))
+    }
+}
+
+/// Escapes `s` to go inside a DOT string
+fn escape(s : &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Quotes `s` for DOT
+fn quote(s : &str) -> String {
+    format!("\"{}\"", escape(s))
+}
+
+impl Disassembler<'_> {
+    /// Control flow graph of what [`traverse`](Self::traverse) reaches
+    pub fn cfg(&self) -> Cfg {
+        Cfg::new(&self.traverse().lines)
+    }
+
+    /// Graphviz DOT of `cfg`, with a box per block listing its instructions.
+    /// Targets that aren't a block, like data and addresses outside of the
+    /// image, are plain nodes, and unknown ones are dashed.
+    pub fn dot(&self, cfg : &Cfg) -> String {
+        let lines = cfg.blocks.values().flat_map(|block| block.lines.iter().cloned()).collect::<Vec<_>>();
+        let labels = self.labels(&lines);
+        let node = |address : u16| quote(&format!("{address:#06X}"));
+
+        let mut res = String::from("digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n");
+        for block in cfg.blocks.values() {
+            let mut label = String::new();
+            for line in block.lines.iter() {
+                for name in labels.get(&line.address).into_iter().flatten() {
+                    label += &format!("{}:\\l", escape(name));
+                }
+                label += &format!("{:#06X}  {}\\l", line.address, escape(&text(line, &labels)));
+            }
+            // Already escaped, so the \l line breaks stay
+            res += &format!("    {} [label=\"{label}\"];\n", node(block.start));
+        }
+
+        let outside = cfg.edges.iter()
+            .filter_map(|edge| edge.to)
+            .filter(|to| !cfg.blocks.contains_key(to))
+            .collect::<BTreeSet<_>>();
+        for address in outside {
+            res += &format!("    {} [shape=plaintext];\n", node(address));
+        }
+
+        for edge in cfg.edges.iter() {
+            let to = match edge.to {
+                Some(to) => node(to),
+                None => {
+                    let unknown = quote(&format!("{:#06X}?", edge.from));
+                    let label = if edge.kind == EdgeKind::Return { "return" } else { "?" };
+                    res += &format!("    {unknown} [label={}, shape=plaintext];\n", quote(label));
+                    unknown
+                },
+            };
+            let attributes = match (edge.kind, edge.to) {
+                (EdgeKind::Next, _) => String::new(),
+                (EdgeKind::Branch, None) => " [label=\"taken\", style=dashed]".to_string(),
+                (EdgeKind::Branch, Some(_)) => " [label=\"taken\"]".to_string(),
+                (EdgeKind::Jump, None) => " [style=dashed]".to_string(),
+                (EdgeKind::Jump, Some(_)) => String::new(),
+                (EdgeKind::Call, None) => " [label=\"call\", style=dashed]".to_string(),
+                (EdgeKind::Call, Some(_)) => " [label=\"call\"]".to_string(),
+                (EdgeKind::Return, _) => " [style=dotted]".to_string(),
+            };
+            res += &format!("    {} -> {to}{attributes};\n", node(edge.from));
+        }
+        res += "}\n";
+        res
+    }
+}
@@ -0,0 +1,272 @@
+//! Disassembler printing listings of flat images.
+//!
+//! Every instruction is shown with its address and bytes, and word
+//! immediates of `mov` and `call` that are the address of a symbol are shown
+//! as its name. Targets of jumps and calls through a register are recovered
+//! from the `mov` that loaded it, as long as nothing wrote the register in
+//! between, and get a `loc_XXXX` label if no symbol names them. The `mov` of
+//! a relative jump or call is shown as the target minus the address the jump is
+//! relative to, like it's written in assembly.
+//!
+//! [`Disassembler::lines`] decodes the whole image as a linear sweep, while
+//! [`Disassembler::traverse`] only decodes what execution can reach, leaving
+//! the rest as data. A [`Cfg`] splits either into basic blocks, and
+//! [`Disassembler::dot`] draws it with Graphviz.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    instruction::parse_literal,
+    utils::{Error, Result},
+    DecodeMode, Decoder, Instruction, Register, Value, Width,
+};
+
+mod cfg;
+pub use cfg::{Block, Cfg, Edge, EdgeKind};
+
+mod traverse;
+pub use traverse::{Ambiguity, Traversal};
+
+/// Register an instruction writes, besides RIP, RSH and Flags
+fn written(inst : &Instruction) -> Option<Register> {
+    use Instruction::*;
+    match inst {
+        MovC2R(_, dest) | MovR2R(_, dest) | MovM2R(_, dest) | Pop(dest) |
+        AddC2R(_, dest) | AddR2R(_, dest) | SubC2R(_, dest) | SubR2R(_, dest) | Not(dest) |
+        AndC2R(_, dest) | AndR2R(_, dest) | OrC2R(_, dest) | OrR2R(_, dest) |
+        Shl(_, dest) | Shr(_, dest) | Shre(_, dest) => Some(*dest),
+        _ => None,
+    }
+}
+
+/// Whether an instruction goes somewhere else than the next one, or calls
+/// something that can change any register
+fn transfers(inst : &Instruction) -> bool {
+    use Instruction::*;
+    matches!(inst, DB(_) | AJmp(_) | Jmp(_) | CallC(_) | CallR(_) | Ret | Int(_))
+}
+
+/// Reads symbols from a linker [map](crate::link::Output::map), or from
+/// lines of an address and a name. Empty lines and those starting with `#`
+/// are skipped.
+pub fn read_symbols(text : &str) -> Result<Vec<(String, u16)>> {
+    let mut res = vec![];
+    let mut sections = false;
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.ends_with(':') {
+            sections = line == "Sections:";
+            continue;
+        }
+        if sections {
+            continue;
+        }
+
+        let invalid = || Error::InvalidValue(line.to_string());
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let (address, name) = match words[..] {
+            [address, name] | [address, "global" | "local", name, _] => (address, name),
+            _ => return Err(invalid()),
+        };
+        let address = match parse_literal(address)? {
+            (value @ 0..=0xFFFF, _) => value as u16,
+            _ => return Err(invalid()),
+        };
+        res.push((name.to_string(), address));
+    }
+    Ok(res)
+}
+
+/// Address and value of the last `mov` of a word into each register, by its
+/// code, that nothing wrote over since
+type Loads = [Option<(u16, u16)>; 16];
+
+/// Where `inst` at `address` jumps, calls or points RINT to, if it can be
+/// told, together with the address of the `mov` that loaded the target and
+/// how it's used
+fn target(address : u16, inst : &Instruction, loads : &Loads) -> Option<(u16, Option<(u16, Use)>)> {
+    use Instruction::*;
+    let next = address.wrapping_add(inst.len());
+    let loaded = |reg : &Register| loads[reg.compile_src() as usize].filter(|_| reg.width() == Width::Word);
+    match inst {
+        CallC(value) => Some((value.value_word(), None)),
+        AJmp(reg) | Sti(reg) => loaded(reg).map(|(load, value)| (value, Some((load, Use::Absolute)))),
+        Jmp(reg) | CallR(reg) | Jeq(reg) | Jneq(reg) | Jlt(reg) | Jgt(reg) | Jleq(reg) | Jgeq(reg) | Jo(reg) | Jno(reg)
+            => loaded(reg).map(|(load, value)| (next.wrapping_add(value), Some((load, Use::Relative(next))))),
+        _ => None,
+    }
+}
+
+/// Updates `loads` after `inst` at `address`
+fn update(loads : &mut Loads, address : u16, inst : &Instruction) {
+    if transfers(inst) {
+        *loads = [None; 16];
+    } else if let Some(reg) = written(inst) {
+        loads[reg.compile_src() as usize] = match inst {
+            Instruction::MovC2R(value, _) if value.width() == Width::Word => Some((address, value.value_word())),
+            _ => None,
+        };
+    }
+}
+
+/// How a jump or call uses the value a `mov` loaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Use {
+    /// As an address
+    Absolute,
+
+    /// As a displacement from the address after a relative jump
+    Relative(u16),
+}
+
+/// Single instruction of a listing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub address : u16,
+    pub inst : Instruction,
+
+    /// Where a jump or call goes, if it could be told
+    pub target : Option<u16>,
+
+    /// How a jump or call uses the value of a `mov`
+    load : Option<Use>,
+}
+
+/// Disassembler of an image
+#[derive(Debug, Clone)]
+pub struct Disassembler<'a> {
+    image : &'a [u8],
+    origin : u16,
+
+    /// Names of the symbols at each address, sorted
+    symbols : BTreeMap<u16, Vec<String>>,
+
+    /// Where [`traverse`](Self::traverse) starts from, besides the reset
+    /// vector
+    entries : BTreeSet<u16>,
+}
+
+impl<'a> Disassembler<'a> {
+    pub fn new(image : &'a [u8]) -> Self {
+        Self { image, origin: 0, symbols: BTreeMap::new(), entries: BTreeSet::new() }
+    }
+
+    /// Sets the address of the first byte
+    pub fn with_origin(mut self, origin : u16) -> Self {
+        self.origin = origin;
+        self
+    }
+
+    /// Adds `symbols` to the ones shown as labels and instead of addresses
+    pub fn with_symbols(mut self, symbols : impl IntoIterator<Item = (String, u16)>) -> Self {
+        for (name, address) in symbols {
+            let names = self.symbols.entry(address).or_default();
+            names.push(name);
+            names.sort();
+        }
+        self
+    }
+
+    /// Adds addresses to traverse from, like handlers of interrupts that
+    /// aren't set up by the image itself
+    pub fn with_entries(mut self, entries : impl IntoIterator<Item = u16>) -> Self {
+        self.entries.extend(entries);
+        self
+    }
+
+    /// Whether `address` is inside the image
+    fn contains(&self, address : u16) -> bool {
+        (address as usize).wrapping_sub(self.origin as usize) < self.image.len()
+    }
+
+    /// Every instruction of the image, bytes that aren't one being `db`
+    pub fn lines(&self) -> Vec<Line> {
+        let mut lines = Decoder::new(self.image, DecodeMode::Resync).with_origin(self.origin)
+            .map(|res| {
+                let (address, inst) = res.expect("decoding bytes can't fail when resyncing");
+                Line { address, inst, target: None, load: None }
+            })
+            .collect::<Vec<_>>();
+        let indices = lines.iter().enumerate().map(|(idx, line)| (line.address, idx)).collect::<BTreeMap<_, _>>();
+
+        let mut loads = [None; 16];
+        for idx in 0..lines.len() {
+            let (address, inst) = (lines[idx].address, lines[idx].inst);
+            if let Some((target, load)) = target(address, &inst, &loads) {
+                lines[idx].target = Some(target);
+                if let Some((load, usage)) = load {
+                    lines[indices[&load]].load.get_or_insert(usage);
+                }
+            }
+            update(&mut loads, address, &inst);
+        }
+        lines
+    }
+
+    /// Names of every address, those of symbols and `loc_XXXX` for the
+    /// targets inside the image no symbol names
+    fn labels(&self, lines : &[Line]) -> BTreeMap<u16, Vec<String>> {
+        let mut res = self.symbols.clone();
+        for target in lines.iter().filter_map(|line| line.target) {
+            if self.contains(target) {
+                res.entry(target).or_insert_with(|| vec![format!("loc_{target:04X}")]);
+            }
+        }
+        res
+    }
+
+    /// Listing of the image as a linear sweep sees it
+    pub fn listing(&self) -> String {
+        self.render(&self.lines())
+    }
+
+    /// Listing of `lines`, with a line per instruction, the names of the
+    /// addresses before them, and the target after jumps and calls through
+    /// a register
+    pub fn render(&self, lines : &[Line]) -> String {
+        let labels = self.labels(lines);
+        let mut res = String::new();
+        for line in lines.iter() {
+            for label in labels.get(&line.address).into_iter().flatten() {
+                res += &format!("{label}:\n");
+            }
+            let bytes = line.inst.compile().iter().map(|byte| format!("{byte:02X}")).collect::<Vec<_>>().join(" ");
+            res += &format!("{:#06X}  {bytes:<11}  {}\n", line.address, text(line, &labels));
+        }
+        res
+    }
+}
+
+/// Assembly of `line` with the names in `labels`, followed by the target of
+/// jumps and calls through a register
+fn text(line : &Line, labels : &BTreeMap<u16, Vec<String>>) -> String {
+    let name = |address : u16| labels.get(&address).map(|names| names[0].clone());
+    let mut res = match (line.inst, line.load) {
+        (Instruction::MovC2R(value, dest), Some(Use::Relative(base))) => {
+            let target = base.wrapping_add(value.value_word());
+            match name(target) {
+                Some(target) => format!("mov {target} - {}, {}", Value::word(base), dest.to_string().to_lowercase()),
+                None => line.inst.to_string(),
+            }
+        },
+        (Instruction::MovC2R(value, dest), _) if value.width() == Width::Word => match name(value.value_word()) {
+            Some(symbol) => format!("mov {symbol}, {}", dest.to_string().to_lowercase()),
+            None => line.inst.to_string(),
+        },
+        (Instruction::CallC(value), _) => match name(value.value_word()) {
+            Some(symbol) => format!("call {symbol}"),
+            None => line.inst.to_string(),
+        },
+        _ => line.inst.to_string(),
+    };
+    match (line.inst, line.target.and_then(name)) {
+        (Instruction::CallC(_), _) | (_, None) => (),
+        (_, Some(target)) => res += &format!("  ; {target}"),
+    }
+    res
+}
+
+#[cfg(test)]
+mod test;
@@ -0,0 +1,227 @@
+use super::*;
+use crate::asm::assemble;
+
+const PROGRAM : &str = "
+        .equ CONSOLE, 0xFF00
+
+    start:
+        mov CONSOLE, r1
+        mov 0x0003, r0
+        call double
+        mov skip - next, r3
+        cmp 0, rb0
+        jeq r3
+    next:
+        mov double - skip, r4
+        call r4
+    skip:
+        mov start, r2
+        ajmp r2
+
+    double:
+        add r0, r0
+        ret
+        .db 0xFF
+";
+
+#[test]
+fn listing() {
+    let assembly = assemble(PROGRAM).unwrap();
+    let disassembler = Disassembler::new(&assembly.image).with_symbols(assembly.symbols);
+    assert_eq!(disassembler.listing(), "\
+start:
+0x0000  02 70 00 FF  mov CONSOLE, r1
+0x0004  02 60 03 00  mov 0x0003, r0
+0x0008  31 00 22 00  call double
+0x000C  02 90 06 00  mov skip - 0x0016, r3
+0x0010  23 60 00 00  cmp 0x00, rb0
+0x0014  29 09        jeq r3  ; skip
+next:
+0x0016  02 A0 06 00  mov double - 0x001C, r4
+0x001A  32 0A        call r4  ; double
+skip:
+0x001C  02 80 00 00  mov start, r2
+0x0020  27 08        ajmp r2  ; start
+double:
+0x0022  0E 66        add r0, r0
+0x0024  33 00        ret
+0x0026  FF           db 0xFF
+");
+}
+
+#[test]
+fn recovered() {
+    let assembly = assemble(PROGRAM).unwrap();
+    let disassembler = Disassembler::new(&assembly.image);
+    assert_eq!(disassembler.listing(), "\
+loc_0000:
+0x0000  02 70 00 FF  mov 0xFF00, r1
+0x0004  02 60 03 00  mov 0x0003, r0
+0x0008  31 00 22 00  call loc_0022
+0x000C  02 90 06 00  mov loc_001C - 0x0016, r3
+0x0010  23 60 00 00  cmp 0x00, rb0
+0x0014  29 09        jeq r3  ; loc_001C
+0x0016  02 A0 06 00  mov loc_0022 - 0x001C, r4
+0x001A  32 0A        call r4  ; loc_0022
+loc_001C:
+0x001C  02 80 00 00  mov loc_0000, r2
+0x0020  27 08        ajmp r2  ; loc_0000
+loc_0022:
+0x0022  0E 66        add r0, r0
+0x0024  33 00        ret
+0x0026  FF           db 0xFF
+");
+
+    // Relative targets move with the origin, absolute ones don't
+    let lines = Disassembler::new(&assembly.image).with_origin(0x1000).lines();
+    let targets = lines.iter().filter_map(|line| line.target).collect::<Vec<_>>();
+    assert_eq!(targets, vec![0x0022, 0x101C, 0x1022, 0x0000]);
+
+    // Only the last load before the jump counts
+    let image = assemble("mov 0x0010, r2\nmov 0x0020, r2\nadd 1, rb3\najmp r2\nnot r2\najmp r2").unwrap().image;
+    let targets = Disassembler::new(&image).lines().iter().map(|line| line.target).collect::<Vec<_>>();
+    assert_eq!(targets, vec![None, None, None, Some(0x0020), None, None]);
+}
+
+#[test]
+fn symbols() {
+    let map = "\
+Sections:
+  0x0000  0x000E  text  main.o
+
+Symbols:
+  0x0000  global  main  main.o
+  0x8000  local   message  main.o
+";
+    assert_eq!(read_symbols(map), Ok(vec![("main".to_string(), 0x0000), ("message".to_string(), 0x8000)]));
+    assert_eq!(read_symbols("# comment\n0x12 double\n\n4 four\n"), Ok(vec![("double".to_string(), 0x0012), ("four".to_string(), 4)]));
+    assert_eq!(read_symbols("0x12"), Err(Error::InvalidValue("0x12".to_string())));
+    assert_eq!(read_symbols("-1 minus"), Err(Error::InvalidValue("-1 minus".to_string())));
+    assert_eq!(read_symbols("x name"), Err(Error::InvalidValue("x".to_string())));
+}
+
+#[test]
+fn traverse() {
+    let assembly = assemble("
+            mov ticks, r0
+            sti r0
+            mov table, r1
+            movm r1, r2
+            mov main - table, r3
+            jmp r3
+        table:
+            .dw 0x6002, 0x0001
+        main:
+            call r2
+            ret
+        ticks:
+            pop r8
+            pop flags
+            ret
+    ").unwrap();
+    let disassembler = Disassembler::new(&assembly.image).with_symbols(assembly.symbols);
+    let traversal = disassembler.traverse();
+    assert_eq!(disassembler.render(&traversal.lines), "\
+0x0000  02 60 1A 00  mov ticks, r0
+0x0004  35 06        sti r0  ; ticks
+0x0006  02 70 12 00  mov table, r1
+0x000A  06 87        movm r1, r2
+0x000C  02 90 04 00  mov main - 0x0012, r3
+0x0010  28 09        jmp r3  ; main
+table:
+0x0012  02           db 0x02
+0x0013  60           db 0x60
+0x0014  01           db 0x01
+0x0015  00           db 0x00
+main:
+0x0016  32 08        call r2
+0x0018  33 00        ret
+ticks:
+0x001A  0A 0E        pop r8
+0x001C  0A 03        pop flags
+0x001E  33 00        ret
+");
+    assert_eq!(traversal.ambiguities, vec![Ambiguity::Unresolved(0x0016)]);
+    assert_eq!(traversal.ambiguities[0].to_string(), "unknown target of the instruction at 0x0016");
+
+    // Which a linear sweep takes for an instruction
+    assert_eq!(disassembler.lines()[6].inst.to_string(), "mov 0x0001, r0");
+
+    // Into the middle of an instruction, past an invalid one, and out of the image
+    let image = assemble("mov 0x0002, r1\najmp r1\n.db 0xFF").unwrap().image;
+    let traversal = Disassembler::new(&image).with_entries([0x0006]).traverse();
+    assert_eq!(traversal.ambiguities, vec![Ambiguity::Overlap(0x0000, 0x0002), Ambiguity::Invalid(0x0006)]);
+    let traversal = Disassembler::new(&image).with_origin(0x1000).traverse();
+    assert_eq!(traversal.ambiguities, vec![Ambiguity::Outside(0x1004, 0x0002)]);
+    assert_eq!(traversal.lines.len(), 3);
+}
+
+#[test]
+fn cfg() {
+    let assembly = assemble("
+            mov 0x0003, r0
+            call double
+            mov done - next, r3
+            cmp 0x0006, r0
+            jeq r3
+        next:
+            call r9
+        done:
+            mov done, r1
+            ajmp r1
+
+        double:
+            add r0, r0
+            ret
+    ").unwrap();
+    let disassembler = Disassembler::new(&assembly.image).with_symbols(assembly.symbols);
+    let cfg = disassembler.cfg();
+    assert_eq!(cfg.blocks.keys().copied().collect::<Vec<_>>(), vec![0x0000, 0x0008, 0x0012, 0x0014, 0x001A]);
+    let edge = |from, to, kind| Edge { from, to, kind };
+    assert_eq!(cfg.edges, vec![
+        edge(0x0000, Some(0x0008), EdgeKind::Next),
+        edge(0x0000, Some(0x001A), EdgeKind::Call),
+        edge(0x0008, Some(0x0012), EdgeKind::Next),
+        edge(0x0008, Some(0x0014), EdgeKind::Branch),
+        edge(0x0012, None, EdgeKind::Call),
+        edge(0x0012, Some(0x0014), EdgeKind::Next),
+        edge(0x0014, Some(0x0014), EdgeKind::Jump),
+        edge(0x001A, None, EdgeKind::Return),
+    ]);
+    assert_eq!(cfg.block(0x000C).map(|block| block.start), Some(0x0008));
+    assert_eq!(cfg.block(0x000D), None);
+    assert_eq!(cfg.block(0x0008).map(Block::end), Some(0x0012));
+    assert_eq!(cfg.successors(0x0012).count(), 2);
+    assert_eq!(cfg.predecessors(0x0014).map(|edge| edge.from).collect::<Vec<_>>(), vec![0x0008, 0x0012, 0x0014]);
+    assert_eq!(disassembler.dot(&cfg), r#"digraph cfg {
+    node [shape=box, fontname="monospace"];
+    "0x0000" [label="0x0000  mov 0x0003, r0\l0x0004  call double\l"];
+    "0x0008" [label="0x0008  mov done - 0x0012, r3\l0x000C  cmp 0x0006, r0\l0x0010  jeq r3  ; done\l"];
+    "0x0012" [label="next:\l0x0012  call r9\l"];
+    "0x0014" [label="done:\l0x0014  mov done, r1\l0x0018  ajmp r1  ; done\l"];
+    "0x001A" [label="double:\l0x001A  add r0, r0\l0x001C  ret\l"];
+    "0x0000" -> "0x0008";
+    "0x0000" -> "0x001A" [label="call"];
+    "0x0008" -> "0x0012";
+    "0x0008" -> "0x0014" [label="taken"];
+    "0x0012?" [label="?", shape=plaintext];
+    "0x0012" -> "0x0012?" [label="call", style=dashed];
+    "0x0012" -> "0x0014";
+    "0x0014" -> "0x0014";
+    "0x001A?" [label="return", shape=plaintext];
+    "0x001A" -> "0x001A?" [style=dotted];
+}
+"#);
+
+    // Names that look like escapes, or end the label
+    let image = assemble("call 0x0004\nret").unwrap().image;
+    let disassembler = Disassembler::new(&image).with_symbols([("a\\l\"b".to_string(), 0x0004)]);
+    assert!(disassembler.dot(&disassembler.cfg()).contains(r#"[label="0x0000  call a\\l\"b\l"];"#));
+    assert!(disassembler.dot(&disassembler.cfg()).contains(r#"[label="a\\l\"b:\l0x0004  ret\l"];"#));
+
+    // Falling into data
+    let image = assemble("mov 0x0001, r0\n.db 0xFF").unwrap().image;
+    let cfg = Cfg::new(&Disassembler::new(&image).lines());
+    assert_eq!(cfg.edges, vec![edge(0x0000, Some(0x0004), EdgeKind::Next)]);
+    assert!(Disassembler::new(&image).dot(&cfg).contains("    \"0x0004\" [shape=plaintext];\n"));
+}
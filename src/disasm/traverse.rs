@@ -0,0 +1,150 @@
+use std::collections::BTreeMap;
+
+use crate::Instruction;
+
+use super::{target, update, Disassembler, Line, Loads};
+
+/// Something a [`Traversal`] couldn't tell for sure
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Ambiguity {
+    /// Instructions reached at both addresses share bytes, so one of them
+    /// isn't shown
+    Overlap(u16, u16),
+
+    /// The target of a jump, call or `sti` through a register isn't known,
+    /// so the code there may be shown as data
+    Unresolved(u16),
+
+    /// Bytes reached as code that aren't an instruction
+    Invalid(u16),
+
+    /// The instruction at the first address goes to the second one, outside
+    /// of the image
+    Outside(u16, u16),
+}
+
+impl std::fmt::Display for Ambiguity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Overlap(first, second) => write!(f, "instructions at {first:#06X} and {second:#06X} overlap"),
+            Self::Unresolved(address) => write!(f, "unknown target of the instruction at {address:#06X}"),
+            Self::Invalid(address) => write!(f, "invalid instruction reached at {address:#06X}"),
+            Self::Outside(from, to) => write!(f, "instruction at {from:#06X} goes to {to:#06X}, outside of the image"),
+        }
+    }
+}
+
+/// Output of [`Disassembler::traverse`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Traversal {
+    /// Instructions reached from the entry points, and the bytes no path
+    /// reached as `db`, in order
+    pub lines : Vec<Line>,
+
+    /// Sorted, without repeats
+    pub ambiguities : Vec<Ambiguity>,
+}
+
+impl Disassembler<'_> {
+    /// Addresses execution can start from: the reset vector, address 0, or
+    /// the origin if the image doesn't hold it, and the added entry points
+    pub fn entries(&self) -> impl Iterator<Item = u16> + '_ {
+        let reset = if self.contains(0) { 0 } else { self.origin };
+        std::iter::once(reset).chain(self.entries.iter().copied())
+    }
+
+    /// Disassembles what can be reached from the [entry points](Self::entries)
+    /// by falling through, jumping, calling and pointing RINT to handlers
+    /// with `sti`, telling code from data. Targets through registers are
+    /// recovered like [`lines`](Self::lines) does, following the value a
+    /// register holds along the first path that reaches each instruction.
+    pub fn traverse(&self) -> Traversal {
+        let mut code = BTreeMap::<u16, Line>::new();
+
+        // Address of the instruction every byte belongs to, by offset
+        let mut owners = vec![None::<u16>; self.image.len()];
+        let mut ambiguities = vec![];
+        let mut paths = self.entries().map(|entry| (entry, None, [None; 16])).collect::<Vec<(u16, Option<u16>, Loads)>>();
+        paths.reverse();
+
+        while let Some((mut address, mut from, mut loads)) = paths.pop() {
+            loop {
+                if !self.contains(address) {
+                    if let Some(from) = from {
+                        ambiguities.push(Ambiguity::Outside(from, address));
+                    }
+                    break;
+                }
+                if code.contains_key(&address) {
+                    break;
+                }
+
+                let offset = address.wrapping_sub(self.origin) as usize;
+                let overlap = |len : usize| owners[offset..offset + len].iter().flatten().next()
+                    .map(|other : &u16| Ambiguity::Overlap((*other).min(address), (*other).max(address)));
+                if let Some(overlap) = overlap(1) {
+                    ambiguities.push(overlap);
+                    break;
+                }
+                let Ok((inst, len)) = Instruction::decode(&self.image[offset..]) else {
+                    ambiguities.push(Ambiguity::Invalid(address));
+                    break;
+                };
+                if let Some(overlap) = overlap(len) {
+                    ambiguities.push(overlap);
+                    break;
+                }
+                owners[offset..offset + len].fill(Some(address));
+
+                let mut line = Line { address, inst, target: None, load: None };
+                let found = target(address, &inst, &loads);
+                if let Some((target, load)) = found {
+                    line.target = Some(target);
+                    if let Some((load, usage)) = load {
+                        if let Some(load) = code.get_mut(&load) {
+                            load.load.get_or_insert(usage);
+                        }
+                    }
+                }
+                code.insert(address, line);
+
+                use Instruction::*;
+                let branches = matches!(inst, AJmp(_) | Jmp(_) | Jeq(_) | Jneq(_) | Jlt(_) | Jgt(_) | Jleq(_) | Jgeq(_) | Jo(_) | Jno(_) | CallC(_) | CallR(_) | Sti(_));
+                match found {
+                    // Calls and handlers start without knowing any register
+                    Some((target, _)) if matches!(inst, CallC(_) | CallR(_) | Sti(_)) => paths.push((target, Some(address), [None; 16])),
+                    Some((target, _)) => paths.push((target, Some(address), loads)),
+                    None if branches => ambiguities.push(Ambiguity::Unresolved(address)),
+                    None => (),
+                }
+                if matches!(inst, AJmp(_) | Jmp(_) | Ret) {
+                    break;
+                }
+
+                update(&mut loads, address, &inst);
+                from = Some(address);
+                address = address.wrapping_add(len as u16);
+            }
+        }
+
+        let mut lines = vec![];
+        let mut offset = 0;
+        while offset < self.image.len() {
+            let address = self.origin.wrapping_add(offset as u16);
+            match code.remove(&address) {
+                Some(line) => {
+                    offset += line.inst.len() as usize;
+                    lines.push(line);
+                },
+                None => {
+                    lines.push(Line { address, inst: Instruction::db(self.image[offset]), target: None, load: None });
+                    offset += 1;
+                },
+            }
+        }
+
+        ambiguities.sort();
+        ambiguities.dedup();
+        Traversal { lines, ambiguities }
+    }
+}
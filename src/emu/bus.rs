@@ -0,0 +1,476 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{utils::{Error, Reader, Result}, Width};
+
+/// Something the CPU reads and writes through addresses, words being little
+/// endian. Devices mapped into a [`MemoryMap`] see addresses relative to the
+/// start of their region.
+pub trait Bus {
+    /// Reads a value of `width`, a byte being zero extended
+    fn read(&mut self, address : u16, width : Width) -> Result<u16>;
+
+    /// Writes the low byte of `value`, or all of it, depending on `width`
+    fn write(&mut self, address : u16, value : u16, width : Width) -> Result<()>;
+
+    /// Byte at `address` without side effects, for inspection. Devices that
+    /// can't tell it return 0.
+    fn peek(&self, address : u16) -> u8;
+
+    /// Whether a write to `address` would succeed, so that word writes split
+    /// into bytes can fault before writing either of them
+    fn writable(&self, _address : u16) -> bool {
+        true
+    }
+
+    /// Whether writing back what [`peek`](Self::peek) returns for `address`
+    /// puts it back as it was, like it does for memory but not for devices
+    /// that act on writes
+    fn reversible(&self, _address : u16) -> bool {
+        false
+    }
+
+    /// Lets `cycles` CPU cycles pass
+    fn tick(&mut self, _cycles : u64) {}
+
+    /// Number of addresses the device answers to, if it's fixed by what it
+    /// holds, like the contents of memory
+    fn size(&self) -> Option<usize> {
+        None
+    }
+
+    /// Exit code, once something asked the machine to stop
+    fn halted(&self) -> Option<u16> {
+        None
+    }
+
+    /// State to put in a [`Snapshot`](super::Snapshot), memory contents
+    /// included. Devices without any keep the default, which is empty.
+    fn save(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    /// Goes back to the state `save` returned, as long as it fits the device
+    fn restore(&mut self, state : &[u8]) -> Result<()> {
+        expect_state(state, 0)
+    }
+}
+
+/// Fails unless a saved state is `len` bytes long
+pub(crate) fn expect_state(state : &[u8], len : usize) -> Result<()> {
+    match state.len() == len {
+        true => Ok(()),
+        false => Err(Error::InvalidSnapshot(format!("expected {len} bytes of device state, got {}", state.len()))),
+    }
+}
+
+/// Splits a word access into two byte accesses
+fn read_bytes(bus : &mut impl Bus, address : u16) -> Result<u16> {
+    let low = bus.read(address, Width::Byte)?;
+    let high = bus.read(address.wrapping_add(1), Width::Byte)?;
+    Ok(low | high << 8)
+}
+
+/// Faults without writing anything if either byte isn't writable
+fn write_bytes(bus : &mut impl Bus, address : u16, value : u16) -> Result<()> {
+    let bytes = [(address, value & 0xFF), (address.wrapping_add(1), value >> 8)];
+    if let Some((address, byte)) = bytes.iter().find(|(address, _)| !bus.writable(*address)) {
+        return bus.write(*address, *byte, Width::Byte);
+    }
+    for (address, byte) in bytes {
+        bus.write(address, byte, Width::Byte)?;
+    }
+    Ok(())
+}
+
+/// Read-only memory, which faults on writes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rom(pub Vec<u8>);
+
+/// Read-write memory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ram(pub Vec<u8>);
+
+impl Ram {
+    /// Zeroed memory of `len` bytes
+    pub fn new(len : usize) -> Self {
+        Self(vec![0; len])
+    }
+}
+
+/// Reads a value of `width` from `bytes`, faulting past the end
+fn read_slice(bytes : &[u8], address : u16, width : Width) -> Result<u16> {
+    let at = |address : u16| bytes.get(address as usize).map(|byte| *byte as u16).ok_or(Error::Unmapped(address));
+    match width {
+        Width::Byte => at(address),
+        Width::Word => Ok(at(address)? | at(address.wrapping_add(1))? << 8),
+    }
+}
+
+impl Bus for Rom {
+    fn read(&mut self, address : u16, width : Width) -> Result<u16> {
+        read_slice(&self.0, address, width)
+    }
+
+    fn write(&mut self, address : u16, _value : u16, _width : Width) -> Result<()> {
+        Err(Error::ReadOnly(address))
+    }
+
+    fn peek(&self, address : u16) -> u8 {
+        self.0.get(address as usize).copied().unwrap_or_default()
+    }
+
+    fn size(&self) -> Option<usize> {
+        Some(self.0.len())
+    }
+
+    fn writable(&self, _address : u16) -> bool {
+        false
+    }
+
+    fn save(&self) -> Vec<u8> {
+        self.0.clone()
+    }
+
+    fn restore(&mut self, state : &[u8]) -> Result<()> {
+        expect_state(state, self.0.len())?;
+        self.0.copy_from_slice(state);
+        Ok(())
+    }
+}
+
+impl Bus for Ram {
+    fn read(&mut self, address : u16, width : Width) -> Result<u16> {
+        read_slice(&self.0, address, width)
+    }
+
+    fn write(&mut self, address : u16, value : u16, width : Width) -> Result<()> {
+        let len = match width {
+            Width::Byte => 1,
+            Width::Word => 2,
+        };
+        let addresses = (0..len).map(|idx| address.wrapping_add(idx));
+        if let Some(address) = addresses.clone().find(|address| !self.writable(*address)) {
+            return Err(Error::Unmapped(address));
+        }
+        for (idx, address) in addresses.enumerate() {
+            self.0[address as usize] = (value >> (8 * idx)) as u8;
+        }
+        Ok(())
+    }
+
+    fn peek(&self, address : u16) -> u8 {
+        self.0.get(address as usize).copied().unwrap_or_default()
+    }
+
+    fn size(&self) -> Option<usize> {
+        Some(self.0.len())
+    }
+
+    fn writable(&self, address : u16) -> bool {
+        (address as usize) < self.0.len()
+    }
+
+    fn reversible(&self, address : u16) -> bool {
+        self.writable(address)
+    }
+
+    fn save(&self) -> Vec<u8> {
+        self.0.clone()
+    }
+
+    fn restore(&mut self, state : &[u8]) -> Result<()> {
+        expect_state(state, self.0.len())?;
+        self.0.copy_from_slice(state);
+        Ok(())
+    }
+}
+
+/// Shared device, so its owner can still reach it once mapped
+impl<T : Bus> Bus for Rc<RefCell<T>> {
+    fn read(&mut self, address : u16, width : Width) -> Result<u16> {
+        self.borrow_mut().read(address, width)
+    }
+
+    fn write(&mut self, address : u16, value : u16, width : Width) -> Result<()> {
+        self.borrow_mut().write(address, value, width)
+    }
+
+    fn peek(&self, address : u16) -> u8 {
+        self.borrow().peek(address)
+    }
+
+    fn tick(&mut self, cycles : u64) {
+        self.borrow_mut().tick(cycles);
+    }
+
+    fn size(&self) -> Option<usize> {
+        self.borrow().size()
+    }
+
+    fn writable(&self, address : u16) -> bool {
+        self.borrow().writable(address)
+    }
+
+    fn reversible(&self, address : u16) -> bool {
+        self.borrow().reversible(address)
+    }
+
+    fn halted(&self) -> Option<u16> {
+        self.borrow().halted()
+    }
+
+    fn save(&self) -> Vec<u8> {
+        self.borrow().save()
+    }
+
+    fn restore(&mut self, state : &[u8]) -> Result<()> {
+        self.borrow_mut().restore(state)
+    }
+}
+
+struct Region {
+    start : u16,
+
+    /// Last address of the region
+    end : u16,
+    device : Box<dyn Bus>,
+}
+
+/// Address space made of devices mapped into ranges of addresses that
+/// can't overlap. Accesses outside of every range fault, and word accesses
+/// that cross from a region into another one are split into bytes.
+#[derive(Default)]
+pub struct MemoryMap {
+    regions : Vec<Region>,
+}
+
+impl MemoryMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Maps `device` to the `len` addresses from `start` on. Devices with a
+    /// [size](Bus::size) have to be `len` bytes long.
+    pub fn map(&mut self, start : u16, len : u32, device : impl Bus + 'static) -> Result<()> {
+        let end = start as u32 + len;
+        if len == 0 || end > 0x10000 {
+            return Err(Error::AddressOutOfRange(end));
+        }
+        if let Some(size) = device.size().filter(|size| *size != len as usize) {
+            return Err(Error::SizeMismatch(len, size));
+        }
+        let end = (end - 1) as u16;
+
+        if let Some(other) = self.regions.iter().find(|other| other.start <= end && start <= other.end) {
+            return Err(Error::Overlap(start.max(other.start)));
+        }
+        self.regions.push(Region { start, end, device: Box::new(device) });
+        self.regions.sort_by_key(|region| region.start);
+        Ok(())
+    }
+
+    /// Ranges of every region, sorted
+    pub fn ranges(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        self.regions.iter().map(|region| (region.start, region.end))
+    }
+
+    fn region(&self, address : u16) -> Option<usize> {
+        self.regions.iter().position(|region| (region.start..=region.end).contains(&address))
+    }
+
+    /// Region holding every byte of an access, if the access doesn't have to be split
+    fn whole(&self, address : u16, width : Width) -> Result<Option<usize>> {
+        let idx = self.region(address).ok_or(Error::Unmapped(address))?;
+        Ok((width == Width::Byte || address != self.regions[idx].end).then_some(idx))
+    }
+}
+
+/// Moves the address of a fault from a device back into the address space
+fn absolute(err : Error, start : u16) -> Error {
+    match err {
+        Error::Unmapped(address) => Error::Unmapped(address.wrapping_add(start)),
+        Error::ReadOnly(address) => Error::ReadOnly(address.wrapping_add(start)),
+        err => err,
+    }
+}
+
+impl Bus for MemoryMap {
+    fn read(&mut self, address : u16, width : Width) -> Result<u16> {
+        match self.whole(address, width)? {
+            Some(idx) => {
+                let region = &mut self.regions[idx];
+                region.device.read(address - region.start, width).map_err(|err| absolute(err, region.start))
+            },
+            None => read_bytes(self, address),
+        }
+    }
+
+    fn write(&mut self, address : u16, value : u16, width : Width) -> Result<()> {
+        match self.whole(address, width)? {
+            Some(idx) => {
+                let region = &mut self.regions[idx];
+                region.device.write(address - region.start, value, width).map_err(|err| absolute(err, region.start))
+            },
+            None => write_bytes(self, address, value),
+        }
+    }
+
+    fn peek(&self, address : u16) -> u8 {
+        match self.region(address) {
+            Some(idx) => self.regions[idx].device.peek(address - self.regions[idx].start),
+            None => 0,
+        }
+    }
+
+    fn writable(&self, address : u16) -> bool {
+        self.region(address).is_some_and(|idx| self.regions[idx].device.writable(address - self.regions[idx].start))
+    }
+
+    fn reversible(&self, address : u16) -> bool {
+        self.region(address).is_some_and(|idx| self.regions[idx].device.reversible(address - self.regions[idx].start))
+    }
+
+    fn tick(&mut self, cycles : u64) {
+        for region in self.regions.iter_mut() {
+            region.device.tick(cycles);
+        }
+    }
+
+    fn halted(&self) -> Option<u16> {
+        self.regions.iter().find_map(|region| region.device.halted())
+    }
+
+    /// Range and state length, as u16, u16 and u32, and the state of every
+    /// region in order
+    fn save(&self) -> Vec<u8> {
+        let mut res = vec![];
+        for region in self.regions.iter() {
+            let state = region.device.save();
+            res.extend(region.start.to_le_bytes());
+            res.extend(region.end.to_le_bytes());
+            res.extend((state.len() as u32).to_le_bytes());
+            res.extend(state);
+        }
+        res
+    }
+
+    /// Needs the same ranges mapped. Regions before one whose state doesn't
+    /// fit are restored all the same.
+    fn restore(&mut self, state : &[u8]) -> Result<()> {
+        let mut reader = Reader::new(state, Error::InvalidSnapshot);
+        for region in self.regions.iter_mut() {
+            let (start, end) = (reader.u16()?, reader.u16()?);
+            if (start, end) != (region.start, region.end) {
+                return Err(Error::InvalidSnapshot(format!("expected a region at {:#06X}..={:#06X}, got {start:#06X}..={end:#06X}", region.start, region.end)));
+            }
+            let len = reader.u32()? as usize;
+            region.device.restore(reader.bytes(len)?)?;
+        }
+        match reader.is_empty() {
+            true => Ok(()),
+            false => Err(Error::InvalidSnapshot("more regions than mapped".to_string())),
+        }
+    }
+}
+
+impl std::fmt::Debug for MemoryMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.regions.iter().map(|region| region.start..=region.end)).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mapping() {
+        let mut map = MemoryMap::new();
+        map.map(0x0000, 4, Rom(vec![1, 2, 3, 4])).unwrap();
+        map.map(0x8000, 0x100, Ram::new(0x100)).unwrap();
+
+        assert_eq!(map.read(0x0001, Width::Word), Ok(0x0302));
+        assert_eq!(map.read(0x0003, Width::Byte), Ok(0x04));
+        assert_eq!(map.write(0x8010, 0xABCD, Width::Word), Ok(()));
+        assert_eq!(map.read(0x8010, Width::Byte), Ok(0xCD));
+        assert_eq!(map.read(0x8010, Width::Word), Ok(0xABCD));
+        assert_eq!(map.write(0x8020, 0x1234, Width::Byte), Ok(()));
+        assert_eq!(map.read(0x8020, Width::Word), Ok(0x0034));
+        assert_eq!(map.peek(0x8011), 0xAB);
+        assert_eq!(map.ranges().collect::<Vec<_>>(), vec![(0x0000, 0x0003), (0x8000, 0x80FF)]);
+    }
+
+    #[test]
+    fn faults() {
+        let mut map = MemoryMap::new();
+        map.map(0x0000, 4, Rom(vec![1, 2, 3, 4])).unwrap();
+        map.map(0x0004, 4, Ram::new(4)).unwrap();
+
+        assert_eq!(map.write(0x0002, 0, Width::Byte), Err(Error::ReadOnly(0x0002)));
+        assert_eq!(map.read(0x0008, Width::Byte), Err(Error::Unmapped(0x0008)));
+        assert_eq!(map.read(0x0007, Width::Word), Err(Error::Unmapped(0x0008)));
+        assert_eq!(map.peek(0x1000), 0);
+
+        // Crossing from the rom into the ram
+        assert_eq!(map.read(0x0003, Width::Word), Ok(0x0004));
+        assert_eq!(map.write(0x0003, 0xFFFF, Width::Word), Err(Error::ReadOnly(0x0003)));
+    }
+
+    #[test]
+    fn based() {
+        let mut map = MemoryMap::new();
+        map.map(0xC000, 4, Rom(vec![1, 2, 3, 4])).unwrap();
+        map.map(0x8000, 4, Rc::new(RefCell::new(Ram::new(4)))).unwrap();
+
+        assert_eq!(map.read(0xC002, Width::Word), Ok(0x0403));
+        assert_eq!(map.write(0xC002, 0, Width::Byte), Err(Error::ReadOnly(0xC002)));
+        assert_eq!(map.write(0xC003, 0, Width::Word), Err(Error::ReadOnly(0xC003)));
+        assert_eq!(map.write(0x8003, 0xFFFF, Width::Word), Err(Error::Unmapped(0x8004)));
+        assert_eq!(map.read(0x8004, Width::Byte), Err(Error::Unmapped(0x8004)));
+
+        // Memory has to fill its range, no more and no less
+        assert_eq!(map.map(0x1000, 0x100, Ram::new(0x10)), Err(Error::SizeMismatch(0x100, 0x10)));
+        assert_eq!(map.map(0x1000, 2, Rom(vec![0; 4])), Err(Error::SizeMismatch(2, 4)));
+        assert_eq!(map.map(0x1000, 2, Rc::new(RefCell::new(Ram::new(1)))), Err(Error::SizeMismatch(2, 1)));
+        assert_eq!(map.ranges().count(), 2);
+    }
+
+    #[test]
+    fn halfway() {
+        let mut ram = Ram::new(2);
+        assert_eq!(ram.write(1, 0xFFFF, Width::Word), Err(Error::Unmapped(2)));
+        assert_eq!(ram.0, vec![0, 0]);
+
+        // Word writes out of a region, into nothing or something read-only
+        let mut map = MemoryMap::new();
+        map.map(0x8000, 4, Ram::new(4)).unwrap();
+        map.map(0x8004, 4, Rom(vec![1, 2, 3, 4])).unwrap();
+        map.map(0x9000, 2, Ram::new(2)).unwrap();
+        assert_eq!(map.write(0x8003, 0xFFFF, Width::Word), Err(Error::ReadOnly(0x8004)));
+        assert_eq!(map.write(0x9001, 0xFFFF, Width::Word), Err(Error::Unmapped(0x9002)));
+        assert_eq!(map.write(0x8FFF, 0xFFFF, Width::Word), Err(Error::Unmapped(0x8FFF)));
+        assert_eq!((map.peek(0x8003), map.peek(0x9001), map.peek(0x9000)), (0, 0, 0));
+        assert_eq!(map.write(0x8002, 0xABCD, Width::Word), Ok(()));
+        assert_eq!(map.read(0x8002, Width::Word), Ok(0xABCD));
+    }
+
+    #[test]
+    fn overlap() {
+        let mut map = MemoryMap::new();
+        map.map(0x1000, 0x100, Ram::new(0x100)).unwrap();
+        assert_eq!(map.map(0x0F00, 0x101, Ram::new(0x101)), Err(Error::Overlap(0x1000)));
+        assert_eq!(map.map(0x10FF, 1, Ram::new(1)), Err(Error::Overlap(0x10FF)));
+        assert_eq!(map.map(0xFF00, 0x101, Ram::new(0x101)), Err(Error::AddressOutOfRange(0x10001)));
+        assert_eq!(map.map(0x0F00, 0x100, Ram::new(0x100)), Ok(()));
+        assert_eq!(map.map(0xFF00, 0x100, Ram::new(0x100)), Ok(()));
+    }
+
+    #[test]
+    fn shared() {
+        let ram = Rc::new(RefCell::new(Ram::new(2)));
+        let mut map = MemoryMap::new();
+        map.map(0x4000, 2, ram.clone()).unwrap();
+        map.write(0x4000, 0x55AA, Width::Word).unwrap();
+        assert_eq!(ram.borrow().0, vec![0xAA, 0x55]);
+    }
+}
@@ -0,0 +1,350 @@
+//! Reference devices to map into a [`MemoryMap`](super::MemoryMap).
+
+use std::io::{Read, Write};
+
+use crate::{utils::{Error, Result}, Width};
+
+use super::{bus::expect_state, Bus, Interrupts};
+
+/// Reads the `width` bytes at `address` of a device's registers, those past
+/// the end reading as 0
+fn read_registers(registers : &[u8], address : u16, width : Width) -> u16 {
+    let at = |address : u16| registers.get(address as usize).copied().unwrap_or_default() as u16;
+    match width {
+        Width::Byte => at(address),
+        Width::Word => at(address) | at(address.wrapping_add(1)) << 8,
+    }
+}
+
+/// Character console. Its registers are:
+/// - 0, data: writing sends the low byte to the output, reading takes the
+///   next byte of the input, or 0 once it's over
+/// - 1, status: bit 0 is set while there's input left
+pub struct Console<W : Write, R : Read> {
+    output : W,
+    input : R,
+
+    /// Byte read ahead to tell whether there's input left
+    next : Option<Option<u8>>,
+}
+
+impl<W : Write, R : Read> Console<W, R> {
+    pub const SIZE : u32 = 2;
+
+    pub fn new(output : W, input : R) -> Self {
+        Self { output, input, next: None }
+    }
+
+    pub fn output(&self) -> &W {
+        &self.output
+    }
+
+    pub fn into_parts(self) -> (W, R) {
+        (self.output, self.input)
+    }
+
+    fn lookahead(&mut self) -> Result<Option<u8>> {
+        if self.next.is_none() {
+            let mut byte = [0];
+            self.next = Some(match self.input.read(&mut byte)? {
+                0 => None,
+                _ => Some(byte[0]),
+            });
+        }
+        Ok(self.next.flatten())
+    }
+}
+
+impl<W : Write, R : Read> Bus for Console<W, R> {
+    fn read(&mut self, address : u16, width : Width) -> Result<u16> {
+        let data = match address {
+            0 => {
+                let res = self.lookahead()?;
+                if res.is_some() {
+                    self.next = None;
+                }
+                res.unwrap_or_default()
+            },
+            _ => 0,
+        };
+        // Only looked ahead for when asked, as it may wait for input
+        let status = match (address, width) {
+            (1, _) | (0, Width::Word) => self.lookahead()?.is_some() as u8,
+            _ => 0,
+        };
+        Ok(read_registers(&[data, status], address, width))
+    }
+
+    fn write(&mut self, address : u16, value : u16, _width : Width) -> Result<()> {
+        if address == 0 {
+            self.output.write_all(&[value as u8])?;
+            self.output.flush()?;
+        }
+        Ok(())
+    }
+
+    fn peek(&self, address : u16) -> u8 {
+        match (address, self.next) {
+            (1, Some(next)) => next.is_some() as u8,
+            _ => 0,
+        }
+    }
+
+    /// The byte read ahead, as 0 if there's none, 1 if the input is over, or
+    /// 2 and the byte. The input and output themselves can't be saved.
+    fn save(&self) -> Vec<u8> {
+        match self.next {
+            None => vec![0],
+            Some(None) => vec![1],
+            Some(Some(byte)) => vec![2, byte],
+        }
+    }
+
+    fn restore(&mut self, state : &[u8]) -> Result<()> {
+        self.next = match state {
+            [0] => None,
+            [1] => Some(None),
+            [2, byte] => Some(Some(*byte)),
+            _ => return Err(Error::InvalidSnapshot(format!("invalid console state {state:02X?}"))),
+        };
+        Ok(())
+    }
+}
+
+/// Programmable timer, raising an interrupt every `period` cycles. Its word
+/// registers are:
+/// - 0, period: 0 stops the timer, and writing it restarts the count
+/// - 2, number of the interrupt to raise
+/// - 4, cycles since the last interrupt, read-only
+#[derive(Debug, Clone)]
+pub struct Timer {
+    period : u16,
+    number : u16,
+    elapsed : u16,
+    interrupts : Interrupts,
+}
+
+impl Timer {
+    pub const SIZE : u32 = 6;
+
+    pub fn new(interrupts : Interrupts) -> Self {
+        Self { period: 0, number: 0, elapsed: 0, interrupts }
+    }
+
+    fn registers(&self) -> Vec<u8> {
+        [self.period, self.number, self.elapsed].iter().flat_map(|value| value.to_le_bytes()).collect()
+    }
+}
+
+impl Bus for Timer {
+    fn read(&mut self, address : u16, width : Width) -> Result<u16> {
+        Ok(read_registers(&self.registers(), address, width))
+    }
+
+    fn write(&mut self, address : u16, value : u16, width : Width) -> Result<()> {
+        let mut registers = self.registers();
+        let bytes = value.to_le_bytes();
+        let len = if width == Width::Byte { 1 } else { 2 };
+        for (idx, byte) in bytes.iter().take(len).enumerate() {
+            // The count is read-only
+            if let Some(register) = registers.get_mut(address as usize + idx).filter(|_| address as usize + idx < 4) {
+                *register = *byte;
+            }
+        }
+
+        let period = u16::from_le_bytes([registers[0], registers[1]]);
+        if address < 2 {
+            self.elapsed = 0;
+        }
+        self.period = period;
+        self.number = u16::from_le_bytes([registers[2], registers[3]]);
+        Ok(())
+    }
+
+    fn peek(&self, address : u16) -> u8 {
+        read_registers(&self.registers(), address, Width::Byte) as u8
+    }
+
+    fn tick(&mut self, cycles : u64) {
+        if self.period == 0 {
+            return;
+        }
+
+        let total = self.elapsed as u64 + cycles;
+        for _ in 0..total / self.period as u64 {
+            self.interrupts.raise(self.number);
+        }
+        self.elapsed = (total % self.period as u64) as u16;
+    }
+
+    /// Its registers
+    fn save(&self) -> Vec<u8> {
+        self.registers()
+    }
+
+    fn restore(&mut self, state : &[u8]) -> Result<()> {
+        expect_state(state, 6)?;
+        let word = |idx : usize| u16::from_le_bytes([state[idx], state[idx + 1]]);
+        (self.period, self.number, self.elapsed) = (word(0), word(2), word(4));
+        Ok(())
+    }
+}
+
+/// Writing a word to its only register stops the machine with that exit code
+#[derive(Debug, Clone, Default)]
+pub struct Halt {
+    code : Option<u16>,
+}
+
+impl Halt {
+    pub const SIZE : u32 = 2;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Bus for Halt {
+    fn read(&mut self, _address : u16, _width : Width) -> Result<u16> {
+        Ok(0)
+    }
+
+    fn write(&mut self, address : u16, value : u16, width : Width) -> Result<()> {
+        self.code = Some(match (address, width) {
+            (0, Width::Word) => value,
+            _ => value & 0xFF,
+        });
+        Ok(())
+    }
+
+    fn peek(&self, _address : u16) -> u8 {
+        0
+    }
+
+    fn halted(&self) -> Option<u16> {
+        self.code
+    }
+
+    /// Whether it halted as a byte, and the exit code
+    fn save(&self) -> Vec<u8> {
+        let [low, high] = self.code.unwrap_or_default().to_le_bytes();
+        vec![self.code.is_some() as u8, low, high]
+    }
+
+    fn restore(&mut self, state : &[u8]) -> Result<()> {
+        self.code = match state {
+            [0, 0, 0] => None,
+            [1, low, high] => Some(u16::from_le_bytes([*low, *high])),
+            _ => return Err(Error::InvalidSnapshot(format!("invalid halt state {state:02X?}"))),
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn console() {
+        let mut console = Console::new(vec![], &b"ab"[..]);
+        console.write(0, b'h' as u16, Width::Byte).unwrap();
+        console.write(0, 0x1269, Width::Word).unwrap();
+        assert_eq!(console.output(), b"hi");
+
+        assert_eq!(console.read(1, Width::Byte), Ok(1));
+        assert_eq!(console.read(0, Width::Byte), Ok(b'a' as u16));
+        assert_eq!(console.read(0, Width::Word), Ok(b'b' as u16));
+        assert_eq!(console.read(1, Width::Byte), Ok(0));
+        assert_eq!(console.read(0, Width::Byte), Ok(0));
+    }
+
+    /// Input counting how many times it's read
+    struct Counted<'a>(&'a [u8], usize);
+
+    impl Read for Counted<'_> {
+        fn read(&mut self, buf : &mut [u8]) -> std::io::Result<usize> {
+            self.1 += 1;
+            self.0.read(buf)
+        }
+    }
+
+    #[test]
+    fn lookahead() {
+        // Taking a byte doesn't wait for the next one
+        let mut console = Console::new(vec![], Counted(b"ab", 0));
+        assert_eq!(console.read(1, Width::Byte), Ok(1));
+        assert_eq!(console.read(0, Width::Byte), Ok(b'a' as u16));
+        assert_eq!(console.read(0, Width::Byte), Ok(b'b' as u16));
+        assert_eq!(console.into_parts().1.1, 2);
+    }
+
+    #[test]
+    fn timer() {
+        let interrupts = Interrupts::new();
+        let mut timer = Timer::new(interrupts.clone());
+        timer.tick(100);
+        assert!(interrupts.is_empty());
+
+        timer.write(2, 7, Width::Word).unwrap();
+        timer.write(0, 10, Width::Word).unwrap();
+        timer.tick(25);
+        assert_eq!(interrupts.pending(), vec![7, 7]);
+        assert_eq!(timer.read(4, Width::Word), Ok(5));
+
+        timer.write(4, 0, Width::Word).unwrap();
+        assert_eq!(timer.read(4, Width::Word), Ok(5));
+        timer.write(0, 3, Width::Byte).unwrap();
+        assert_eq!(timer.read(0, Width::Word), Ok(3));
+        assert_eq!(timer.read(4, Width::Word), Ok(0));
+        timer.tick(3);
+        assert_eq!(interrupts.pending(), vec![7, 7, 7]);
+
+        timer.write(0, 0, Width::Word).unwrap();
+        timer.tick(100);
+        assert_eq!(interrupts.pending().len(), 3);
+    }
+
+    #[test]
+    fn halt() {
+        let mut halt = Halt::new();
+        assert_eq!(halt.halted(), None);
+        halt.write(0, 0x1234, Width::Word).unwrap();
+        assert_eq!(halt.halted(), Some(0x1234));
+    }
+
+    #[test]
+    fn states() {
+        let mut console = Console::new(vec![], &b"ab"[..]);
+        assert_eq!(console.save(), vec![0]);
+        console.read(1, Width::Byte).unwrap();
+        let state = console.save();
+        assert_eq!(state, vec![2, b'a']);
+
+        // The read ahead byte comes back, but the input stays where it was
+        console.read(0, Width::Byte).unwrap();
+        console.restore(&state).unwrap();
+        assert_eq!(console.read(0, Width::Byte), Ok(b'a' as u16));
+        assert_eq!(console.read(0, Width::Byte), Ok(b'b' as u16));
+        assert_eq!(console.read(0, Width::Byte), Ok(0));
+        assert_eq!(console.restore(&[3]), Err(Error::InvalidSnapshot("invalid console state [03]".to_string())));
+
+        let interrupts = Interrupts::new();
+        let mut timer = Timer::new(interrupts.clone());
+        timer.write(0, 10, Width::Word).unwrap();
+        timer.write(2, 7, Width::Word).unwrap();
+        timer.tick(4);
+        let state = timer.save();
+        timer.tick(20);
+        timer.restore(&state).unwrap();
+        assert_eq!(timer.read(4, Width::Word), Ok(4));
+        assert_eq!(timer.restore(&state[1..]), Err(Error::InvalidSnapshot("expected 6 bytes of device state, got 5".to_string())));
+
+        let mut halt = Halt::new();
+        let state = halt.save();
+        halt.write(0, 3, Width::Byte).unwrap();
+        assert_eq!(halt.save(), vec![1, 3, 0]);
+        halt.restore(&state).unwrap();
+        assert_eq!(halt.halted(), None);
+    }
+}
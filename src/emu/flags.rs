@@ -0,0 +1,366 @@
+use std::ops::{BitAnd, BitOr, BitOrAssign, Not};
+
+use crate::{Instruction, Width};
+
+/// Value of [`Register::Flags`](crate::Register::Flags)
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Flags(u16);
+
+impl Flags {
+    /// The result is zero
+    pub const ZERO : Self = Self(1 << 0);
+
+    /// Unsigned overflow: carry out of an addition, borrow of a subtraction
+    /// or the last bit shifted out
+    pub const CARRY : Self = Self(1 << 1);
+
+    /// The highest bit of the result is set
+    pub const SIGN : Self = Self(1 << 2);
+
+    /// Signed overflow of an addition or subtraction
+    pub const OVERFLOW : Self = Self(1 << 3);
+
+    /// Interrupts raised by devices are delivered, set by `Sti` and cleared
+    /// by `Cli` and on delivery
+    pub const INTERRUPT : Self = Self(1 << 4);
+
+    /// Every flag arithmetic instructions set
+    pub const ARITHMETIC : Self = Self(Self::ZERO.0 | Self::CARRY.0 | Self::SIGN.0 | Self::OVERFLOW.0);
+
+    const NAMES : [(Self, &'static str); 5] = [
+        (Self::ZERO, "Z"), (Self::CARRY, "C"), (Self::SIGN, "S"), (Self::OVERFLOW, "O"), (Self::INTERRUPT, "I"),
+    ];
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Flags with every bit of `bits`, even those without a name
+    pub const fn from_bits(bits : u16) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(&self) -> u16 {
+        self.0
+    }
+
+    pub const fn contains(&self, other : Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn insert(&mut self, other : Self) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other : Self) {
+        self.0 &= !other.0;
+    }
+
+    /// Inserts `other` if `value`, removes it otherwise
+    pub fn set(&mut self, other : Self, value : bool) {
+        if value {
+            self.insert(other);
+        } else {
+            self.remove(other);
+        }
+    }
+
+    /// `self` with the arithmetic flags replaced by those of `other`
+    pub fn with_arithmetic(self, other : Self) -> Self {
+        (self & !Self::ARITHMETIC) | (other & Self::ARITHMETIC)
+    }
+}
+
+impl BitOr for Flags {
+    type Output = Self;
+
+    fn bitor(self, rhs : Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for Flags {
+    fn bitor_assign(&mut self, rhs : Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl BitAnd for Flags {
+    type Output = Self;
+
+    fn bitand(self, rhs : Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+impl Not for Flags {
+    type Output = Self;
+
+    fn not(self) -> Self {
+        Self(!self.0)
+    }
+}
+
+impl std::fmt::Debug for Flags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut names = Self::NAMES.iter().filter(|(flag, _)| self.contains(*flag)).map(|(_, name)| name.to_string()).collect::<Vec<_>>();
+        let named = Self::NAMES.iter().fold(Self::empty(), |res, (flag, _)| res | *flag);
+        if !(*self & !named).is_empty() {
+            names.push(format!("{:#06X}", (*self & !named).0));
+        }
+        write!(f, "Flags({})", names.join(" | "))
+    }
+}
+
+/// Operation of an arithmetic instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AluOp {
+    Add, Sub, And, Or, Not, Shl, Shr, Shre,
+}
+
+impl AluOp {
+    pub const ALL : [Self; 8] = [Self::Add, Self::Sub, Self::And, Self::Or, Self::Not, Self::Shl, Self::Shr, Self::Shre];
+
+    /// Operation `inst` performs, compares being subtractions
+    pub fn of(inst : &Instruction) -> Option<Self> {
+        use Instruction::*;
+        match inst {
+            AddC2R(..) | AddR2R(..) => Some(Self::Add),
+            SubC2R(..) | SubR2R(..) | CmpC2R(..) | CmpR2R(..) => Some(Self::Sub),
+            AndC2R(..) | AndR2R(..) => Some(Self::And),
+            OrC2R(..) | OrR2R(..) => Some(Self::Or),
+            Not(_) => Some(Self::Not),
+            Shl(..) => Some(Self::Shl),
+            Shr(..) => Some(Self::Shr),
+            Shre(..) => Some(Self::Shre),
+            _ => None,
+        }
+    }
+}
+
+/// Result and flags of `lhs op rhs` on values of `width`, `lhs` being the
+/// destination operand and `rhs` the source or the shift amount. Only the
+/// bits within `width` of the operands are used.
+///
+/// Logic operations clear the carry and overflow flags, and so do shifts
+/// by 0. Shifts set the carry to the last bit shifted out, and never
+/// overflow.
+pub fn alu(op : AluOp, lhs : u16, rhs : u16, width : Width) -> (u16, Flags) {
+    let (mask, sign, bits) = match width {
+        Width::Byte => (0xFFu32, 0x80u32, 8),
+        Width::Word => (0xFFFF, 0x8000, 16),
+    };
+    let (lhs, rhs) = (lhs as u32 & mask, rhs as u32 & mask);
+    let signed = if lhs & sign != 0 { lhs as i32 - (mask as i32 + 1) } else { lhs as i32 };
+
+    let (res, carry, overflow) = match (op, rhs) {
+        (AluOp::Add, _) => {
+            let res = lhs + rhs;
+            (res, res > mask, (lhs & sign) == (rhs & sign) && (res & sign) != (lhs & sign))
+        },
+        (AluOp::Sub, _) => {
+            let res = lhs.wrapping_sub(rhs);
+            (res, rhs > lhs, (lhs & sign) != (rhs & sign) && (res & sign) != (lhs & sign))
+        },
+        (AluOp::And, _) => (lhs & rhs, false, false),
+        (AluOp::Or, _) => (lhs | rhs, false, false),
+        (AluOp::Not, _) => (!lhs, false, false),
+        (AluOp::Shl | AluOp::Shr | AluOp::Shre, 0) => (lhs, false, false),
+        (AluOp::Shl, n) => (lhs.checked_shl(n).unwrap_or(0), n <= bits && lhs >> (bits - n) & 1 != 0, false),
+        (AluOp::Shr, n) => (lhs.checked_shr(n).unwrap_or(0), lhs.checked_shr(n - 1).unwrap_or(0) & 1 != 0, false),
+        (AluOp::Shre, n) => ((signed >> n.min(31)) as u32, signed >> (n - 1).min(31) & 1 != 0, false),
+    };
+
+    let res = res & mask;
+    let mut flags = Flags::empty();
+    flags.set(Flags::ZERO, res == 0);
+    flags.set(Flags::CARRY, carry);
+    flags.set(Flags::SIGN, res & sign != 0);
+    flags.set(Flags::OVERFLOW, overflow);
+    (res as u16, flags)
+}
+
+/// Condition of a jump, comparisons being signed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    Always, Eq, Neq, Lt, Gt, Leq, Geq, Overflow, NoOverflow,
+}
+
+impl Condition {
+    pub const ALL : [Self; 9] = [
+        Self::Always, Self::Eq, Self::Neq, Self::Lt, Self::Gt, Self::Leq, Self::Geq, Self::Overflow, Self::NoOverflow,
+    ];
+
+    /// Condition of a relative jump
+    pub fn of(inst : &Instruction) -> Option<Self> {
+        use Instruction::*;
+        match inst {
+            Jmp(_) => Some(Self::Always),
+            Jeq(_) => Some(Self::Eq),
+            Jneq(_) => Some(Self::Neq),
+            Jlt(_) => Some(Self::Lt),
+            Jgt(_) => Some(Self::Gt),
+            Jleq(_) => Some(Self::Leq),
+            Jgeq(_) => Some(Self::Geq),
+            Jo(_) => Some(Self::Overflow),
+            Jno(_) => Some(Self::NoOverflow),
+            _ => None,
+        }
+    }
+
+    /// Whether the condition holds after comparing `a` to `b` (`cmp b, a`),
+    /// which resulted in `flags`
+    pub fn holds(&self, flags : Flags) -> bool {
+        let z = flags.contains(Flags::ZERO);
+        let less = flags.contains(Flags::SIGN) != flags.contains(Flags::OVERFLOW);
+        match self {
+            Self::Always => true,
+            Self::Eq => z,
+            Self::Neq => !z,
+            Self::Lt => less,
+            Self::Gt => !z && !less,
+            Self::Leq => z || less,
+            Self::Geq => !less,
+            Self::Overflow => flags.contains(Flags::OVERFLOW),
+            Self::NoOverflow => !flags.contains(Flags::OVERFLOW),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn flags(z : bool, c : bool, s : bool, o : bool) -> Flags {
+        let mut res = Flags::empty();
+        res.set(Flags::ZERO, z);
+        res.set(Flags::CARRY, c);
+        res.set(Flags::SIGN, s);
+        res.set(Flags::OVERFLOW, o);
+        res
+    }
+
+    fn signed(value : u32, width : Width) -> i32 {
+        match width {
+            Width::Byte => value as u8 as i8 as i32,
+            Width::Word => value as u16 as i16 as i32,
+        }
+    }
+
+    /// Reference result of `op`, computed with wide integers
+    fn reference(op : AluOp, lhs : u32, rhs : u32, width : Width) -> (u16, Flags) {
+        let (mask, bits) = match width {
+            Width::Byte => (0xFF, 8),
+            Width::Word => (0xFFFF, 16),
+        };
+        let (res, c, o) = match op {
+            AluOp::Add => (lhs + rhs, lhs + rhs > mask, !(-(mask as i32 + 1) / 2..=mask as i32 / 2).contains(&(signed(lhs, width) + signed(rhs, width)))),
+            AluOp::Sub => (lhs.wrapping_sub(rhs), lhs < rhs, !(-(mask as i32 + 1) / 2..=mask as i32 / 2).contains(&(signed(lhs, width) - signed(rhs, width)))),
+            AluOp::And => (lhs & rhs, false, false),
+            AluOp::Or => (lhs | rhs, false, false),
+            AluOp::Not => (!lhs, false, false),
+            AluOp::Shl => ((lhs << rhs) & mask, rhs > 0 && (lhs << rhs) & (mask + 1) != 0, false),
+            AluOp::Shr => (lhs >> rhs, rhs > 0 && (lhs << 1 >> rhs) & 1 != 0, false),
+            AluOp::Shre => ((signed(lhs, width) >> rhs) as u32, rhs > 0 && ((signed(lhs, width) << 1) >> rhs) & 1 != 0, false),
+        };
+        let res = res & mask;
+        (res as u16, flags(res == 0, c, res >> (bits - 1) != 0, o))
+    }
+
+    #[test]
+    fn bits() {
+        let mut f = Flags::ZERO | Flags::SIGN;
+        assert!(f.contains(Flags::ZERO) && !f.contains(Flags::CARRY));
+        f.remove(Flags::ZERO);
+        f.insert(Flags::OVERFLOW);
+        assert_eq!(f, Flags::SIGN | Flags::OVERFLOW);
+        assert_eq!(f.bits(), 0b1100);
+        assert_eq!(Flags::from_bits(0x0013).with_arithmetic(Flags::CARRY), Flags::from_bits(0x0012));
+        assert_eq!(format!("{:?}", Flags::from_bits(0x0117)), "Flags(Z | C | S | I | 0x0100)");
+    }
+
+    #[test]
+    fn byte_exhaustive() {
+        for op in AluOp::ALL {
+            for lhs in 0..=0xFF {
+                let rhs_range = match op {
+                    AluOp::Shl | AluOp::Shr | AluOp::Shre => 0..=8,
+                    _ => 0..=0xFF,
+                };
+                for rhs in rhs_range {
+                    assert_eq!(alu(op, lhs as u16, rhs as u16, Width::Byte), reference(op, lhs, rhs, Width::Byte), "{op:?} {lhs:#X} {rhs:#X}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn word() {
+        let values = [0, 1, 2, 0x7F, 0x80, 0xFF, 0x100, 0x1234, 0x7FFE, 0x7FFF, 0x8000, 0x8001, 0xABCD, 0xFFFE, 0xFFFF];
+        for op in AluOp::ALL {
+            for lhs in values {
+                let shifts = (0..=16).collect::<Vec<_>>();
+                let rhs_values = match op {
+                    AluOp::Shl | AluOp::Shr | AluOp::Shre => &shifts[..],
+                    _ => &values[..],
+                };
+                for rhs in rhs_values.iter().copied() {
+                    assert_eq!(alu(op, lhs as u16, rhs as u16, Width::Word), reference(op, lhs, rhs, Width::Word), "{op:?} {lhs:#X} {rhs:#X}");
+                }
+            }
+        }
+
+        assert_eq!(alu(AluOp::Add, 0xFFFF, 1, Width::Word), (0, Flags::ZERO | Flags::CARRY));
+        assert_eq!(alu(AluOp::Sub, 0x8000, 1, Width::Word), (0x7FFF, Flags::OVERFLOW));
+        assert_eq!(alu(AluOp::Shl, 0x8001, 16, Width::Word), (0, Flags::ZERO | Flags::CARRY));
+        assert_eq!(alu(AluOp::Shre, 0x8000, 16, Width::Word), (0xFFFF, Flags::SIGN | Flags::CARRY));
+        assert_eq!(alu(AluOp::Add, 0x12FF, 1, Width::Byte), (0, Flags::ZERO | Flags::CARRY));
+    }
+
+    #[test]
+    fn conditions() {
+        for bits in 0..16 {
+            let f = Flags::from_bits(bits);
+            let (z, s, o) = (f.contains(Flags::ZERO), f.contains(Flags::SIGN), f.contains(Flags::OVERFLOW));
+            let expected = [true, z, !z, s != o, !z && s == o, z || s != o, s == o, o, !o];
+            for (condition, expected) in Condition::ALL.iter().zip(expected) {
+                assert_eq!(condition.holds(f), expected, "{condition:?} {f:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn compare() {
+        // After `cmp b, a`, every condition matches the signed comparison of a and b
+        for width in [Width::Byte, Width::Word] {
+            let values = [0u32, 1, 2, 0x7F, 0x80, 0xFF, 0x7FFF, 0x8000, 0xFFFF];
+            for a in values {
+                for b in values {
+                    let (_, f) = alu(AluOp::Sub, a as u16, b as u16, width);
+                    let (sa, sb) = (signed(a, width), signed(b, width));
+                    let expected = [sa == sb, sa != sb, sa < sb, sa > sb, sa <= sb, sa >= sb];
+                    let conditions = [Condition::Eq, Condition::Neq, Condition::Lt, Condition::Gt, Condition::Leq, Condition::Geq];
+                    for (condition, expected) in conditions.iter().zip(expected) {
+                        assert_eq!(condition.holds(f), expected, "{condition:?} {a:#X} {b:#X} {width:?}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn instructions() {
+        use crate::{Register, Value};
+
+        let r0 = Register::r0();
+        assert_eq!(AluOp::of(&Instruction::CmpR2R(r0, r0)), Some(AluOp::Sub));
+        assert_eq!(AluOp::of(&Instruction::Shre(Value::byte(1), r0)), Some(AluOp::Shre));
+        assert_eq!(AluOp::of(&Instruction::MovR2R(r0, r0)), None);
+        assert_eq!(Condition::of(&Instruction::Jgeq(r0)), Some(Condition::Geq));
+        assert_eq!(Condition::of(&Instruction::AJmp(r0)), None);
+    }
+}
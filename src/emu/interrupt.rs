@@ -0,0 +1,40 @@
+use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+
+/// Queue of pending interrupts, shared by the [`Machine`](super::Machine)
+/// and the devices that raise them. Clones refer to the same queue.
+#[derive(Debug, Clone, Default)]
+pub struct Interrupts(Rc<RefCell<VecDeque<u16>>>);
+
+impl Interrupts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues interrupt `number`, to be delivered once interrupts are enabled
+    pub fn raise(&self, number : u16) {
+        self.0.borrow_mut().push_back(number);
+    }
+
+    /// Takes the oldest pending interrupt
+    pub fn next(&self) -> Option<u16> {
+        self.0.borrow_mut().pop_front()
+    }
+
+    /// Puts back an interrupt that couldn't be delivered, as the oldest one
+    pub(crate) fn unget(&self, number : u16) {
+        self.0.borrow_mut().push_front(number);
+    }
+
+    /// Pending interrupts, oldest first
+    pub fn pending(&self) -> Vec<u16> {
+        self.0.borrow().iter().copied().collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.borrow().is_empty()
+    }
+
+    pub fn clear(&self) {
+        self.0.borrow_mut().clear();
+    }
+}
@@ -0,0 +1,136 @@
+use crate::{utils::Result, Instruction, Width};
+
+use super::{Access, AccessKind, Bus, Condition, Cpu, Flags, Interrupts, Timing};
+
+/// What a step did, as seen by [`Machine::observe`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Observed {
+    /// CPU and cycle count before the step
+    pub before : Cpu,
+    pub cycles : u64,
+
+    /// Interrupt delivered before the instruction
+    pub interrupt : Option<u16>,
+
+    /// Address of the instruction
+    pub rip : u16,
+    pub inst : Instruction,
+
+    /// Accesses of delivering the interrupt and of the instruction, in order
+    pub accesses : Vec<Access>,
+
+    /// Value every write among `accesses` stored, in order
+    pub stored : Vec<u16>,
+
+    /// Address and old value of every byte written, in order, as peeked
+    /// before writing
+    pub overwritten : Vec<(u16, u8)>,
+}
+
+/// CPU together with its bus and the interrupts devices raise
+#[derive(Debug)]
+pub struct Machine<B : Bus> {
+    pub cpu : Cpu,
+    pub bus : B,
+    pub interrupts : Interrupts,
+    pub timing : Timing,
+
+    /// Cycles elapsed since the start, as told by `timing`
+    pub cycles : u64,
+}
+
+impl<B : Bus> Machine<B> {
+    pub fn new(bus : B) -> Self {
+        Self { cpu: Cpu::new(), bus, interrupts: Interrupts::new(), timing: Timing::new(), cycles: 0 }
+    }
+
+    /// Delivers the oldest pending interrupt if interrupts are enabled, then
+    /// executes the instruction at RIP, returning it, and lets the cycles
+    /// both took pass for the devices. An interrupt that can't be delivered
+    /// stays pending.
+    pub fn step(&mut self) -> Result<Instruction> {
+        self.deliver()?;
+        self.step_instruction()
+    }
+
+    /// First half of [`step`](Self::step), delivering the oldest pending
+    /// interrupt if interrupts are enabled and returning its number
+    pub fn deliver(&mut self) -> Result<Option<u16>> {
+        if !self.cpu.flags.contains(Flags::INTERRUPT) {
+            return Ok(None);
+        }
+        let Some(number) = self.interrupts.next() else {
+            return Ok(None);
+        };
+
+        if let Err(err) = self.cpu.interrupt(number, &mut self.bus) {
+            self.interrupts.unget(number);
+            return Err(err);
+        }
+        self.elapse(self.timing.delivery());
+        Ok(Some(number))
+    }
+
+    /// Second half of [`step`](Self::step), executing the instruction at RIP
+    pub fn step_instruction(&mut self) -> Result<Instruction> {
+        let flags = self.cpu.flags;
+        let inst = self.cpu.step(&mut self.bus)?;
+        let taken = Condition::of(&inst).is_some_and(|condition| condition.holds(flags));
+        self.elapse(self.timing.cost(&inst, taken));
+        Ok(inst)
+    }
+
+    /// [`step`](Self::step), telling what it did
+    pub fn observe(&mut self) -> Result<Observed> {
+        let before = self.cpu.clone();
+        let cycles = self.cycles;
+        let mut accesses = match self.cpu.flags.contains(Flags::INTERRUPT) && !self.interrupts.is_empty() {
+            true => before.interrupt_accesses(),
+            false => vec![],
+        };
+        let mut overwritten = self.overwritten(&accesses);
+        let interrupt = self.deliver()?;
+        let mut stored = match interrupt {
+            Some(number) => before.interrupt_stores(number, before.rip),
+            None => vec![],
+        };
+
+        let state = self.cpu.clone();
+        if let Ok(inst) = state.peek(&self.bus) {
+            overwritten.extend(self.overwritten(&state.accesses(&inst)));
+        }
+        let inst = self.step_instruction()?;
+        accesses.extend(state.accesses(&inst));
+        stored.extend(state.stores(&inst));
+        Ok(Observed { before, cycles, interrupt, rip: state.rip, inst, accesses, stored, overwritten })
+    }
+
+    /// Bytes the writes among `accesses` would overwrite
+    fn overwritten(&self, accesses : &[Access]) -> Vec<(u16, u8)> {
+        accesses.iter()
+            .filter(|access| access.kind == AccessKind::Write)
+            .flat_map(|access| match access.width {
+                Width::Byte => vec![access.address],
+                Width::Word => vec![access.address, access.address.wrapping_add(1)],
+            })
+            .map(|address| (address, self.bus.peek(address)))
+            .collect()
+    }
+
+    fn elapse(&mut self, cycles : u64) {
+        self.cycles += cycles;
+        self.bus.tick(cycles);
+    }
+
+    /// Steps until the bus halts, returning the exit code, or `None` if it
+    /// didn't after `limit` instructions
+    pub fn run(&mut self, limit : u64) -> Result<Option<u16>> {
+        for _ in 0..limit {
+            if let Some(code) = self.bus.halted() {
+                return Ok(Some(code));
+            }
+            self.step()?;
+        }
+        Ok(self.bus.halted())
+    }
+}
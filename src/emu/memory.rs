@@ -0,0 +1,97 @@
+use crate::{utils::Result, Width};
+
+use super::{bus::expect_state, Bus};
+
+/// Flat 64 KiB memory, which never faults
+#[derive(Clone, PartialEq, Eq)]
+pub struct Memory(Box<[u8; 0x10000]>);
+
+impl Memory {
+    pub fn new() -> Self {
+        Self(Box::new([0; 0x10000]))
+    }
+
+    /// Memory holding `bytes` from `address` on, the rest being zero
+    pub fn with(address : u16, bytes : &[u8]) -> Self {
+        let mut res = Self::new();
+        res.load(address, bytes);
+        res
+    }
+
+    /// Copies `bytes` from `address` on, wrapping around at the end
+    pub fn load(&mut self, address : u16, bytes : &[u8]) {
+        for (idx, byte) in bytes.iter().enumerate() {
+            self.write_byte(address.wrapping_add(idx as u16), *byte);
+        }
+    }
+
+    pub fn read_byte(&self, address : u16) -> u8 {
+        self.0[address as usize]
+    }
+
+    pub fn write_byte(&mut self, address : u16, value : u8) {
+        self.0[address as usize] = value;
+    }
+
+    pub fn read_word(&self, address : u16) -> u16 {
+        u16::from_le_bytes([self.read_byte(address), self.read_byte(address.wrapping_add(1))])
+    }
+
+    pub fn write_word(&mut self, address : u16, value : u16) {
+        let [low, high] = value.to_le_bytes();
+        self.write_byte(address, low);
+        self.write_byte(address.wrapping_add(1), high);
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.0[..]
+    }
+}
+
+impl Bus for Memory {
+    fn read(&mut self, address : u16, width : Width) -> Result<u16> {
+        Ok(match width {
+            Width::Byte => self.read_byte(address) as u16,
+            Width::Word => self.read_word(address),
+        })
+    }
+
+    fn write(&mut self, address : u16, value : u16, width : Width) -> Result<()> {
+        match width {
+            Width::Byte => self.write_byte(address, value as u8),
+            Width::Word => self.write_word(address, value),
+        }
+        Ok(())
+    }
+
+    fn peek(&self, address : u16) -> u8 {
+        self.read_byte(address)
+    }
+
+    fn reversible(&self, _address : u16) -> bool {
+        true
+    }
+
+    fn save(&self) -> Vec<u8> {
+        self.0.to_vec()
+    }
+
+    fn restore(&mut self, state : &[u8]) -> Result<()> {
+        expect_state(state, self.0.len())?;
+        self.0.copy_from_slice(state);
+        Ok(())
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for Memory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let used = self.0.iter().filter(|byte| **byte != 0).count();
+        write!(f, "Memory({used} non-zero bytes)")
+    }
+}
@@ -0,0 +1,335 @@
+//! Reference emulator for the SmplCore ISA.
+//!
+//! Semantics:
+//! - Execution starts at address 0, and RIP points past the current
+//!   instruction while it executes
+//! - `Jmp`, the conditional jumps and `CallR` add the register, as a signed
+//!   displacement, to RIP, while `AJmp` and `CallC` jump to an absolute
+//!   address
+//! - The stack grows upwards from RSB: `Push` writes a word at RSH and adds 2
+//!   to it, `Pop` subtracts 2 from RSH and reads the word there, failing if
+//!   that would go below RSB
+//! - Calls push the return address, and `Ret` pops it
+//! - `Cmp` sets the flags like `Sub` without writing the result, and the
+//!   flags of every arithmetic instruction are: zero, carry (or borrow, or
+//!   the last bit shifted out), sign and signed overflow, as computed by [`alu`]
+//! - Memory accesses, including instruction fetches and the stack, go
+//!   through a [`Bus`], and the registers don't change if one faults
+//! - Delivering an interrupt pushes the return address, Flags and the
+//!   interrupt number, clears [`Flags::INTERRUPT`] and jumps to RINT. Handlers
+//!   pop the number, and return with `pop flags` and `ret`, which enables
+//!   interrupts again if they were. Handlers that enable interrupts themselves
+//!   can be interrupted, nesting on the stack.
+//! - `Int` delivers an interrupt right away, even if interrupts are disabled,
+//!   returning to the next instruction
+//! - Devices raise interrupts through [`Interrupts`], and they're delivered
+//!   by [`Machine::step`] in order while [`Flags::INTERRUPT`] is set, which
+//!   `Sti` does after pointing RINT to the handler, and `Cli` undoes
+//! - [`Machine`] counts the cycles every instruction and interrupt delivery
+//!   takes as told by its [`Timing`], and devices see them pass
+//! - A [`Snapshot`] saves and restores the whole state of a machine
+
+use crate::{
+    utils::{Error, Result},
+    Instruction, Register, Width,
+};
+
+mod bus;
+pub use bus::{Bus, MemoryMap, Ram, Rom};
+
+pub mod device;
+
+mod flags;
+pub use flags::{alu, AluOp, Condition, Flags};
+
+mod interrupt;
+pub use interrupt::Interrupts;
+
+mod machine;
+pub use machine::{Machine, Observed};
+
+mod memory;
+pub use memory::Memory;
+
+mod snapshot;
+pub use snapshot::Snapshot;
+
+mod timing;
+pub use timing::Timing;
+
+pub mod trace;
+
+/// Whether an [`Access`] reads or writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// Access to the bus an instruction makes, besides fetching it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    pub kind : AccessKind,
+    pub address : u16,
+    pub width : Width,
+}
+
+impl Access {
+    /// Whether it touches any address in `start..=end`
+    pub fn overlaps(&self, start : u16, end : u16) -> bool {
+        let last = match self.width {
+            Width::Byte => self.address,
+            Width::Word => self.address.wrapping_add(1),
+        };
+        (start..=end).contains(&self.address) || (start..=end).contains(&last)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Cpu {
+    pub rinfo : u16,
+    pub rip : u16,
+    pub rint : u16,
+    pub flags : Flags,
+    pub rsb : u16,
+    pub rsh : u16,
+
+    /// General purpose registers, `rbN` being the low byte of `rN`
+    pub r : [u16; 10],
+}
+
+impl Cpu {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Value of `reg`, zero extended if it's a byte
+    pub fn get(&self, reg : Register) -> u16 {
+        use Register::*;
+        match reg {
+            RINFO => self.rinfo,
+            RIP => self.rip,
+            RINT => self.rint,
+            Flags => self.flags.bits(),
+            RSB => self.rsb,
+            RSH => self.rsh,
+            R(Width::Byte, n) => self.r[n as usize] & 0xFF,
+            R(Width::Word, n) => self.r[n as usize],
+        }
+    }
+
+    /// Sets `reg`, which only changes the low byte of the word register if it's a byte
+    pub fn set(&mut self, reg : Register, value : u16) {
+        use Register::*;
+        match reg {
+            RINFO => self.rinfo = value,
+            RIP => self.rip = value,
+            RINT => self.rint = value,
+            Flags => self.flags = flags::Flags::from_bits(value),
+            RSB => self.rsb = value,
+            RSH => self.rsh = value,
+            R(Width::Byte, n) => self.r[n as usize] = (self.r[n as usize] & 0xFF00) | (value & 0xFF),
+            R(Width::Word, n) => self.r[n as usize] = value,
+        }
+    }
+
+    /// Reads and decodes the instruction at RIP
+    pub fn fetch(&self, bus : &mut impl Bus) -> Result<Instruction> {
+        let opcode = bus.read(self.rip, Width::Byte)? as u8;
+        let mut bytes = vec![opcode];
+        for idx in 1..Instruction::encoded_len(opcode)? {
+            bytes.push(bus.read(self.rip.wrapping_add(idx as u16), Width::Byte)? as u8);
+        }
+        Instruction::decode(&bytes).map(|(inst, _)| inst)
+    }
+
+    /// Decodes the instruction at RIP without side effects
+    pub fn peek(&self, bus : &impl Bus) -> Result<Instruction> {
+        let bytes = [0, 1, 2, 3].map(|idx| bus.peek(self.rip.wrapping_add(idx)));
+        Instruction::decode(&bytes).map(|(inst, _)| inst)
+    }
+
+    /// Executes the instruction at RIP, returning it
+    pub fn step(&mut self, bus : &mut impl Bus) -> Result<Instruction> {
+        let inst = self.fetch(bus)?;
+        self.execute(&inst, bus)?;
+        Ok(inst)
+    }
+
+    /// Accesses executing `inst` makes, in order, if none of them faults
+    pub fn accesses(&self, inst : &Instruction) -> Vec<Access> {
+        use Instruction::*;
+
+        let access = |kind, address, width| Access { kind, address, width };
+        match inst {
+            MovM2R(src, dest) => vec![access(AccessKind::Read, self.get(*src), dest.width())],
+            MovR2M(src, dest) => vec![access(AccessKind::Write, self.get(*dest), src.width())],
+            Push(_) | CallC(_) | CallR(_) => self.pushes(1),
+            Pop(_) | Ret => vec![access(AccessKind::Read, self.rsh.wrapping_sub(2), Width::Word)],
+            Int(_) => self.interrupt_accesses(),
+            _ => vec![],
+        }
+    }
+
+    /// Accesses delivering an interrupt makes
+    pub fn interrupt_accesses(&self) -> Vec<Access> {
+        self.pushes(3)
+    }
+
+    /// Values the writes among the [`accesses`](Self::accesses) of `inst`
+    /// store, in order
+    pub fn stores(&self, inst : &Instruction) -> Vec<u16> {
+        use Instruction::*;
+
+        let next = self.rip.wrapping_add(inst.len());
+        match inst {
+            MovR2M(src, _) | Push(src) => vec![self.get(*src)],
+            CallC(_) | CallR(_) => vec![next],
+            Int(reg) => self.interrupt_stores(self.get(*reg), next),
+            _ => vec![],
+        }
+    }
+
+    /// Values delivering interrupt `number` stores, to return to `ret`
+    pub fn interrupt_stores(&self, number : u16, ret : u16) -> Vec<u16> {
+        vec![ret, self.flags.bits(), number]
+    }
+
+    fn pushes(&self, count : u16) -> Vec<Access> {
+        (0..count)
+            .map(|idx| Access { kind: AccessKind::Write, address: self.rsh.wrapping_add(2 * idx), width: Width::Word })
+            .collect()
+    }
+
+    /// Pushes `values` in order
+    pub fn push(&mut self, values : &[u16], bus : &mut impl Bus) -> Result<()> {
+        for (idx, value) in values.iter().enumerate() {
+            bus.write(self.rsh.wrapping_add(2 * idx as u16), *value, Width::Word)?;
+        }
+        self.rsh = self.rsh.wrapping_add(2 * values.len() as u16);
+        Ok(())
+    }
+
+    pub fn pop(&mut self, bus : &mut impl Bus) -> Result<u16> {
+        if self.rsh < self.rsb.wrapping_add(2) || self.rsh < 2 {
+            return Err(Error::StackUnderflow(self.rsh));
+        }
+        let value = bus.read(self.rsh - 2, Width::Word)?;
+        self.rsh -= 2;
+        Ok(value)
+    }
+
+    /// Pushes what an interrupt handler returns with, and disables interrupts,
+    /// returning the address of the handler
+    fn enter(&mut self, number : u16, ret : u16, bus : &mut impl Bus) -> Result<u16> {
+        self.push(&[ret, self.flags.bits(), number], bus)?;
+        self.flags.remove(Flags::INTERRUPT);
+        Ok(self.rint)
+    }
+
+    /// Delivers interrupt `number` before the instruction at RIP, whether
+    /// interrupts are enabled or not
+    pub fn interrupt(&mut self, number : u16, bus : &mut impl Bus) -> Result<()> {
+        self.rip = self.enter(number, self.rip, bus)?;
+        Ok(())
+    }
+
+    /// Executes `inst` as if it was at RIP
+    pub fn execute(&mut self, inst : &Instruction, bus : &mut impl Bus) -> Result<()> {
+        use Instruction::*;
+
+        if !inst.is_valid() {
+            return Err(Error::OperandWidthMismatch(*inst));
+        }
+
+        let next = self.rip.wrapping_add(inst.len());
+        let writable = |reg : &Register| reg.is_writable().then_some(()).ok_or(Error::DestOperandNotWritable(*inst));
+
+        let mut rip = next;
+        match inst {
+            Nop => (),
+            DB(opcode) => return Err(Error::UnknownOpcode(*opcode)),
+
+            MovC2R(value, dest) => {
+                writable(dest)?;
+                self.set(*dest, value.value_word());
+            },
+            MovR2R(src, dest) => {
+                writable(dest)?;
+                self.set(*dest, self.get(*src));
+            },
+            MovM2R(src, dest) => {
+                writable(dest)?;
+                let value = bus.read(self.get(*src), dest.width())?;
+                self.set(*dest, value);
+            },
+            MovR2M(src, dest) => bus.write(self.get(*dest), self.get(*src), src.width())?,
+            Push(reg) => self.push(&[self.get(*reg)], bus)?,
+            Pop(reg) => {
+                if *reg == Register::RINFO {
+                    return Err(Error::DestOperandNotWritable(*inst));
+                }
+                let value = self.pop(bus)?;
+                self.set(*reg, value);
+                if *reg == Register::RIP {
+                    rip = value;
+                }
+            },
+
+            AddC2R(value, dest) | SubC2R(value, dest) | AndC2R(value, dest) | OrC2R(value, dest) | CmpC2R(value, dest) |
+            Shl(value, dest) | Shr(value, dest) | Shre(value, dest) => {
+                writable(dest)?;
+                let op = AluOp::of(inst).unwrap_or(AluOp::Add);
+                let (res, flags) = alu(op, self.get(*dest), value.value_word(), dest.width());
+                if !matches!(inst, CmpC2R(..)) {
+                    self.set(*dest, res);
+                }
+                self.flags = self.flags.with_arithmetic(flags);
+            },
+            AddR2R(src, dest) | SubR2R(src, dest) | AndR2R(src, dest) | OrR2R(src, dest) | CmpR2R(src, dest) => {
+                writable(dest)?;
+                let op = AluOp::of(inst).unwrap_or(AluOp::Add);
+                let (res, flags) = alu(op, self.get(*dest), self.get(*src), dest.width());
+                if !matches!(inst, CmpR2R(..)) {
+                    self.set(*dest, res);
+                }
+                self.flags = self.flags.with_arithmetic(flags);
+            },
+            Not(reg) => {
+                writable(reg)?;
+                let (res, flags) = alu(AluOp::Not, self.get(*reg), 0, reg.width());
+                self.set(*reg, res);
+                self.flags = self.flags.with_arithmetic(flags);
+            },
+
+            AJmp(reg) => rip = self.get(*reg),
+            Jmp(reg) | Jeq(reg) | Jneq(reg) | Jlt(reg) | Jgt(reg) | Jleq(reg) | Jgeq(reg) | Jo(reg) | Jno(reg) => {
+                if Condition::of(inst).is_some_and(|condition| condition.holds(self.flags)) {
+                    rip = next.wrapping_add(self.get(*reg));
+                }
+            },
+            CallC(value) => {
+                self.push(&[next], bus)?;
+                rip = value.value_word();
+            },
+            CallR(reg) => {
+                rip = next.wrapping_add(self.get(*reg));
+                self.push(&[next], bus)?;
+            },
+            Ret => rip = self.pop(bus)?,
+
+            Int(reg) => rip = self.enter(self.get(*reg), next, bus)?,
+            Sti(reg) => {
+                self.rint = self.get(*reg);
+                self.flags.insert(Flags::INTERRUPT);
+            },
+            Cli => self.flags.remove(Flags::INTERRUPT),
+        }
+
+        self.rip = rip;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test;
@@ -0,0 +1,249 @@
+//! Machine state snapshots.
+//!
+//! A snapshot holds the CPU, the cycle count, the pending interrupts and
+//! the state every device of the bus [saves](Bus::save), memory included.
+//! The timing isn't part of it, and restoring needs a machine with the same
+//! devices mapped the same way. Every multi-byte value is little endian:
+//!
+//! ```text
+//! magic "SMPS", version: u16, registers: 16 u16 in encoding order
+//! (RINFO, RIP, RINT, Flags, RSB, RSH, R0 to R9), cycles: u64,
+//! interrupt count: u16, pending interrupts: u16 each, oldest first,
+//! bus state length: u32, bus state
+//! ```
+
+use std::io::{Read, Write};
+
+use crate::{
+    utils::{Error, Reader, Result},
+    Register, Width,
+};
+
+use super::{Bus, Cpu, Machine};
+
+const MAGIC : &[u8; 4] = b"SMPS";
+pub const VERSION : u16 = 1;
+
+/// Every register in encoding order
+fn registers() -> impl Iterator<Item = Register> {
+    (0..16).map(|code| Register::from_src(Width::Word, code))
+}
+
+/// State of a [`Machine`] at some point
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub cpu : Cpu,
+    pub cycles : u64,
+
+    /// Pending interrupts, oldest first
+    pub interrupts : Vec<u16>,
+
+    /// What [`Bus::save`] returned
+    pub bus : Vec<u8>,
+}
+
+impl Snapshot {
+    pub fn take<B : Bus>(machine : &Machine<B>) -> Self {
+        Self {
+            cpu: machine.cpu.clone(),
+            cycles: machine.cycles,
+            interrupts: machine.interrupts.pending(),
+            bus: machine.bus.save(),
+        }
+    }
+
+    /// Puts `machine` back in the state of the snapshot. If the bus state
+    /// doesn't fit, the rest of the machine is left alone, but some devices
+    /// may have been restored.
+    pub fn restore<B : Bus>(&self, machine : &mut Machine<B>) -> Result<()> {
+        machine.bus.restore(&self.bus)?;
+        machine.cpu = self.cpu.clone();
+        machine.cycles = self.cycles;
+        machine.interrupts.clear();
+        for number in self.interrupts.iter() {
+            machine.interrupts.raise(*number);
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut res = MAGIC.to_vec();
+        res.extend(VERSION.to_le_bytes());
+        for reg in registers() {
+            res.extend(self.cpu.get(reg).to_le_bytes());
+        }
+        res.extend(self.cycles.to_le_bytes());
+        res.extend((self.interrupts.len() as u16).to_le_bytes());
+        for number in self.interrupts.iter() {
+            res.extend(number.to_le_bytes());
+        }
+        res.extend((self.bus.len() as u32).to_le_bytes());
+        res.extend(&self.bus);
+        res
+    }
+
+    pub fn from_bytes(bytes : &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(bytes, Error::InvalidSnapshot);
+        if reader.bytes(4)? != MAGIC {
+            return Err(Error::InvalidSnapshot("bad magic".to_string()));
+        }
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        let mut cpu = Cpu::new();
+        for reg in registers() {
+            cpu.set(reg, reader.u16()?);
+        }
+        let cycles = reader.u64()?;
+        let interrupts = (0..reader.u16()?).map(|_| reader.u16()).collect::<Result<_>>()?;
+        let len = reader.u32()? as usize;
+        let bus = reader.bytes(len)?.to_vec();
+
+        if !reader.is_empty() {
+            return Err(Error::InvalidSnapshot("trailing bytes".to_string()));
+        }
+
+        Ok(Self { cpu, cycles, interrupts, bus })
+    }
+
+    pub fn write(&self, mut writer : impl Write) -> Result<()> {
+        Ok(writer.write_all(&self.to_bytes())?)
+    }
+
+    pub fn read(mut reader : impl Read) -> Result<Self> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes)?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        asm::assemble,
+        emu::{device::{Halt, Timer}, Memory, MemoryMap, Ram, Rom},
+    };
+
+    /// Counts to 40 in memory while the timer interrupts it, then halts with
+    /// how many times it did
+    const PROGRAM : &str = "
+            .equ TIMER, 0xFF10
+            .equ HALT, 0xFF20
+
+            mov ticks, r0
+            sti r0
+            mov TIMER + 2, r0
+            mov 0x0004, r1
+            movrm r1, r0
+            mov TIMER, r0
+            mov 0x0040, r1
+            movrm r1, r0
+
+            mov 0x8080, r5
+            mov halt - next, r3
+            mov count, r4
+        count:
+            add 1, r2
+            movrm r2, r5
+            cmp 0x0028, r2
+            jeq r3
+        next:
+            ajmp r4
+        halt:
+            mov HALT, r1
+            movrm r9, r1
+
+        ticks:
+            pop r8
+            add 1, r9
+            pop flags
+            ret
+    ";
+
+    fn machine() -> Machine<MemoryMap> {
+        let image = assemble(PROGRAM).unwrap().image;
+        let mut machine = Machine::new(MemoryMap::new());
+        machine.bus.map(0x0000, image.len() as u32, Rom(image)).unwrap();
+        machine.bus.map(0x8000, 0x100, Ram::new(0x100)).unwrap();
+        machine.bus.map(0xFF10, Timer::SIZE, Timer::new(machine.interrupts.clone())).unwrap();
+        machine.bus.map(0xFF20, Halt::SIZE, Halt::new()).unwrap();
+        machine.cpu.rsb = 0x8000;
+        machine.cpu.rsh = 0x8000;
+        machine
+    }
+
+    #[test]
+    fn restore() {
+        let mut machine = machine();
+        machine.run(60).unwrap();
+        machine.interrupts.raise(4);
+        let snapshot = Snapshot::take(&machine);
+        assert_eq!(snapshot.interrupts, vec![4]);
+
+        let code = machine.run(1000).unwrap();
+        assert!(code.is_some());
+        let end = Snapshot::take(&machine);
+
+        // Again from the snapshot, and on a machine built the same way
+        snapshot.restore(&mut machine).unwrap();
+        assert_eq!(Snapshot::take(&machine), snapshot);
+        assert_eq!(machine.bus.halted(), None);
+        assert_eq!(machine.run(1000), Ok(code));
+        assert_eq!(Snapshot::take(&machine), end);
+
+        let mut other = self::machine();
+        snapshot.restore(&mut other).unwrap();
+        assert_eq!(other.run(1000), Ok(code));
+        assert_eq!(Snapshot::take(&other), end);
+        assert_eq!(other.bus.peek(0x8080), 40);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut machine = machine();
+        machine.run(60).unwrap();
+        let snapshot = Snapshot::take(&machine);
+        let bytes = snapshot.to_bytes();
+        assert_eq!(&bytes[..4], b"SMPS");
+        assert_eq!(Snapshot::from_bytes(&bytes), Ok(snapshot.clone()));
+
+        let mut bytes = vec![];
+        snapshot.write(&mut bytes).unwrap();
+        assert_eq!(Snapshot::read(&bytes[..]), Ok(snapshot));
+    }
+
+    #[test]
+    fn invalid() {
+        let snapshot = Snapshot::take(&machine());
+        let bytes = snapshot.to_bytes();
+
+        let mut bad = bytes.clone();
+        bad[0] = b'X';
+        assert_eq!(Snapshot::from_bytes(&bad), Err(Error::InvalidSnapshot("bad magic".to_string())));
+
+        let mut bad = bytes.clone();
+        bad[4] = 2;
+        assert_eq!(Snapshot::from_bytes(&bad), Err(Error::UnsupportedVersion(2)));
+
+        assert_eq!(Snapshot::from_bytes(&bytes[..bytes.len() - 1]), Err(Error::InvalidSnapshot("truncated".to_string())));
+
+        let mut bad = bytes.clone();
+        bad.push(0);
+        assert_eq!(Snapshot::from_bytes(&bad), Err(Error::InvalidSnapshot("trailing bytes".to_string())));
+
+        // Other devices, which leave the CPU alone
+        let mut memory = Machine::new(Memory::new());
+        assert!(matches!(snapshot.restore(&mut memory), Err(Error::InvalidSnapshot(_))));
+        assert_eq!(memory.cpu, Cpu::new());
+
+        let mut moved = Machine::new(MemoryMap::new());
+        moved.bus.map(0x1000, 0x100, Ram::new(0x100)).unwrap();
+        assert_eq!(
+            snapshot.restore(&mut moved),
+            Err(Error::InvalidSnapshot("expected a region at 0x1000..=0x10FF, got 0x0000..=0x0043".to_string())),
+        );
+    }
+}
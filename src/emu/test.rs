@@ -0,0 +1,446 @@
+use super::*;
+use crate::{asm::assemble, Value};
+
+/// Runs `source` until it executes a `ret` with an empty stack
+fn run(source : &str) -> (Cpu, Memory) {
+    let mut memory = Memory::with(0, &assemble(source).unwrap().image);
+    let mut cpu = Cpu { rsb: 0x8000, rsh: 0x8000, ..Cpu::new() };
+    for _ in 0..1000 {
+        if cpu.peek(&memory) == Ok(Instruction::Ret) && cpu.rsh == cpu.rsb {
+            return (cpu, memory);
+        }
+        cpu.step(&mut memory).unwrap();
+    }
+    panic!("didn't finish: {cpu:?}");
+}
+
+#[test]
+fn registers() {
+    let mut cpu = Cpu::new();
+    cpu.set(Register::r3(), 0x1234);
+    cpu.set(Register::rb3(), 0xAB);
+    assert_eq!(cpu.get(Register::r3()), 0x12AB);
+    assert_eq!(cpu.get(Register::rb3()), 0xAB);
+
+    cpu.set(Register::rb3(), 0x1FF);
+    assert_eq!(cpu.get(Register::r3()), 0x12FF);
+    assert_eq!(cpu.r[3], 0x12FF);
+}
+
+#[test]
+fn moves() {
+    let (cpu, memory) = run("
+        mov 0x1234, r0
+        mov r0, r1
+        mov 0x0100, r2
+        movrm r0, r2
+        movm r2, rb3
+        mov 0x56, rb0
+        movrm rb0, r2
+        movm r2, r4
+        ret
+    ");
+    assert_eq!(cpu.r[..5], [0x1256, 0x1234, 0x0100, 0x0034, 0x1256]);
+    assert_eq!(memory.read_word(0x0100), 0x1256);
+}
+
+#[test]
+fn arithmetic() {
+    let (cpu, _) = run("
+        mov 10, rb0
+        add 5, rb0
+        sub 20, rb0
+        mov 0x00F0, r1
+        and 0x0F0F, r1
+        or 0x0003, r1
+        mov 0x00FF, r2
+        not r2
+        mov 0x81, rb3
+        shl 1, rb3
+        mov 0x8000, r4
+        shre 3, r4
+        mov 0x8000, r5
+        shr 3, r5
+        ret
+    ");
+    assert_eq!(cpu.r[..6], [0x00FB, 0x0003, 0xFF00, 0x0002, 0xF000, 0x1000]);
+}
+
+#[test]
+fn flags() {
+    let mut cpu = Cpu::new();
+    let mut memory = Memory::new();
+    let mut exec = |inst : Instruction, cpu : &mut Cpu| {
+        cpu.execute(&inst, &mut memory).unwrap();
+        cpu.flags
+    };
+
+    cpu.set(Register::rb0(), 0xFF);
+    assert_eq!(exec(Instruction::addc2r(Value::byte(1), Register::rb0()).unwrap(), &mut cpu), Flags::ZERO | Flags::CARRY);
+    cpu.set(Register::rb0(), 0x7F);
+    assert_eq!(exec(Instruction::addc2r(Value::byte(1), Register::rb0()).unwrap(), &mut cpu), Flags::SIGN | Flags::OVERFLOW);
+    assert_eq!(exec(Instruction::cmpc2r(Value::byte(0x81), Register::rb0()).unwrap(), &mut cpu), Flags::SIGN | Flags::CARRY);
+    assert_eq!(cpu.get(Register::rb0()), 0x80);
+    cpu.set(Register::r1(), 3);
+    assert_eq!(exec(Instruction::shr(Value::byte(2), Register::r1()).unwrap(), &mut cpu), Flags::ZERO | Flags::CARRY);
+}
+
+#[test]
+fn jumps() {
+    let (cpu, _) = run("
+            mov 0, r0
+            mov 5, r1
+            mov exit - back, r2
+            mov loop - exit, r3
+        loop:
+            add r1, r0
+            sub 1, r1
+            jeq r2
+        back:
+            jmp r3
+        exit:
+            mov after, r4
+            ajmp r4
+            mov 0xDEAD, r0
+        after:
+            cmp 3, r1
+            mov skip - taken, r2
+            jlt r2
+        taken:
+            mov 0xBEEF, r5
+        skip:
+            jgt r2
+            mov 0xCAFE, r6
+            ret
+    ");
+    assert_eq!(cpu.r[0], 15);
+    assert_eq!(cpu.r[5], 0);
+    assert_eq!(cpu.r[6], 0xCAFE);
+}
+
+#[test]
+fn calls() {
+    let (cpu, _) = run("
+            mov 0x8000, r9
+            call double
+            mov triple - back, r8
+            call r8
+        back:
+            ret
+        double:
+            add r0, r0
+            mov 21, r0
+            add r0, r0
+            ret
+        triple:
+            mov r0, r1
+            add r0, r1
+            add r0, r1
+            ret
+    ");
+    assert_eq!(cpu.r[0], 42);
+    assert_eq!(cpu.r[1], 126);
+    assert_eq!(cpu.rsh, 0x8000);
+}
+
+#[test]
+fn stack() {
+    let (cpu, memory) = run("
+        mov 0x1111, r0
+        mov 0x2222, r1
+        push r0
+        push r1
+        pop r0
+        pop r1
+        ret
+    ");
+    assert_eq!(cpu.r[..2], [0x2222, 0x1111]);
+    assert_eq!(memory.read_word(0x8000), 0x1111);
+    assert_eq!(memory.read_word(0x8002), 0x2222);
+}
+
+#[test]
+fn interrupts() {
+    let (cpu, _) = run("
+            mov handler, r0
+            sti r0
+            mov 7, r1
+            int r1
+            ret
+        handler:
+            pop r2
+            pop flags
+            mov r2, r3
+            ret
+    ");
+    assert_eq!(cpu.rint, 14);
+    assert_eq!(cpu.r[3], 7);
+}
+
+#[test]
+fn errors() {
+    let mut cpu = Cpu { rsb: 0x8000, rsh: 0x8000, ..Cpu::new() };
+    let mut memory = Memory::with(0, &[0x33, 0x00, 0xFF]);
+    assert_eq!(cpu.step(&mut memory), Err(Error::StackUnderflow(0x8000)));
+    assert_eq!(cpu.rip, 0);
+
+    cpu.rip = 2;
+    assert_eq!(cpu.step(&mut memory), Err(Error::UnknownOpcode(0xFF)));
+
+    let not = Instruction::Not(Register::RIP);
+    assert_eq!(cpu.execute(&not, &mut memory), Err(Error::DestOperandNotWritable(not)));
+    let pop = Instruction::Pop(Register::RINFO);
+    assert_eq!(cpu.execute(&pop, &mut memory), Err(Error::DestOperandNotWritable(pop)));
+}
+
+#[test]
+fn memory_map() {
+    let image = assemble("
+        mov 0x8000, r0
+        mov 0x1234, r1
+        movrm r1, r0
+        movm r0, rb2
+        push r1
+        mov 0x0000, r0
+        movrm r1, r0
+    ").unwrap().image;
+
+    let mut map = MemoryMap::new();
+    map.map(0x0000, image.len() as u32, Rom(image)).unwrap();
+    map.map(0x8000, 0x10, Ram::new(0x10)).unwrap();
+    let mut cpu = Cpu { rsb: 0x800F, rsh: 0x800F, ..Cpu::new() };
+    for _ in 0..4 {
+        cpu.step(&mut map).unwrap();
+    }
+    assert_eq!(cpu.r[2], 0x0034);
+
+    // The stack runs past the end of the ram
+    assert_eq!(cpu.step(&mut map), Err(Error::Unmapped(0x8010)));
+    assert_eq!(cpu.rsh, 0x800F);
+
+    cpu.rip += 2;
+    cpu.step(&mut map).unwrap();
+    assert_eq!(cpu.step(&mut map), Err(Error::ReadOnly(0x0000)));
+    assert_eq!(cpu.rip, 0x0012);
+}
+
+/// Machine running `source`, with the stack at 0x8000
+fn machine(source : &str) -> Machine<Memory> {
+    let mut machine = Machine::new(Memory::with(0, &assemble(source).unwrap().image));
+    machine.cpu.rsb = 0x8000;
+    machine.cpu.rsh = 0x8000;
+    machine
+}
+
+/// Handler that logs every interrupt number at r8 onwards, enabling
+/// interrupts while it runs so they can nest
+const LOGGER : &str = "
+        mov 0x4000, r8
+        mov handler, r0
+        sti r0
+    idle:
+        mov idle, r1
+        ajmp r1
+    handler:
+        pop r1
+        movrm r1, r8
+        add 2, r8
+        sti r0
+        nop
+        pop flags
+        ret
+";
+
+#[test]
+fn device_interrupts() {
+    let mut machine = machine(LOGGER);
+    machine.interrupts.raise(5);
+    machine.interrupts.raise(6);
+
+    // Masked until `sti`
+    for _ in 0..3 {
+        machine.step().unwrap();
+    }
+    assert_eq!(machine.interrupts.pending(), vec![5, 6]);
+    assert!(machine.cpu.flags.contains(Flags::INTERRUPT));
+
+    // Delivered one at a time, the second one nesting once the handler enables interrupts
+    machine.step().unwrap();
+    assert_eq!(machine.cpu.rsh, 0x8004);
+    assert!(!machine.cpu.flags.contains(Flags::INTERRUPT));
+    assert_eq!(machine.interrupts.pending(), vec![6]);
+    for _ in 0..4 {
+        machine.step().unwrap();
+    }
+    assert_eq!(machine.cpu.rsh, 0x8008);
+    assert!(machine.interrupts.is_empty());
+
+    for _ in 0..20 {
+        machine.step().unwrap();
+    }
+    assert_eq!(machine.cpu.rsh, 0x8000);
+    assert!(machine.cpu.flags.contains(Flags::INTERRUPT));
+    assert_eq!([machine.bus.read_word(0x4000), machine.bus.read_word(0x4002)], [5, 6]);
+    assert_eq!(machine.cpu.r[8], 0x4004);
+}
+
+#[test]
+fn software_interrupts() {
+    let mut machine = machine("
+            mov handler, r0
+            sti r0
+            cli
+            mov 9, r1
+            int r1
+            mov 0x1111, r2
+        end:
+            mov end, r3
+            ajmp r3
+        handler:
+            pop r4
+            pop r5
+            ret
+    ");
+    for _ in 0..3 {
+        machine.step().unwrap();
+    }
+    machine.interrupts.raise(1);
+    for _ in 0..4 {
+        machine.step().unwrap();
+    }
+
+    // `int` isn't masked, but pending interrupts are
+    assert_eq!(machine.cpu.r[4], 9);
+    assert_eq!(Flags::from_bits(machine.cpu.r[5]), Flags::empty());
+    machine.step().unwrap();
+    machine.step().unwrap();
+    assert_eq!(machine.cpu.r[2], 0x1111);
+    assert_eq!(machine.interrupts.pending(), vec![1]);
+}
+
+#[test]
+fn undeliverable_interrupt() {
+    let image = assemble("mov handler, r0\nsti r0\nhandler: nop").unwrap().image;
+    let mut map = MemoryMap::new();
+    map.map(0x0000, image.len() as u32, Rom(image)).unwrap();
+    let mut machine = Machine::new(map);
+    machine.step().unwrap();
+    machine.step().unwrap();
+
+    machine.interrupts.raise(3);
+    assert_eq!(machine.step(), Err(Error::ReadOnly(0x0000)));
+    assert_eq!(machine.interrupts.pending(), vec![3]);
+    assert_eq!(machine.cpu.rip, 6);
+}
+
+#[test]
+fn devices() {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::device::{Console, Halt, Timer};
+
+    let image = assemble("
+            .equ CONSOLE, 0xFF00
+            .equ TIMER, 0xFF10
+            .equ HALT, 0xFF20
+
+            mov ticks, r0
+            sti r0
+            mov TIMER + 2, r0
+            mov 0x0004, r1
+            movrm r1, r0
+            mov TIMER, r0
+            mov 0x0040, r1
+            movrm r1, r0
+
+            mov message, r0
+            mov CONSOLE, r1
+            mov done - next, r3
+        print:
+            movm r0, rb2
+            cmp 0, rb2
+            jeq r3
+        next:
+            movrm rb2, r1
+            add 1, r0
+            mov print, r4
+            ajmp r4
+        done:
+            mov 0x0007, r0
+            mov HALT, r1
+            movrm r9, r1
+
+        ticks:
+            pop r8
+            add 1, r9
+            pop flags
+            ret
+
+        message: .asciz \"hello\\n\"
+    ").unwrap().image;
+
+    let interrupts = Interrupts::new();
+    let console = Rc::new(RefCell::new(Console::new(vec![], std::io::empty())));
+    let mut map = MemoryMap::new();
+    map.map(0x0000, image.len() as u32, Rom(image)).unwrap();
+    map.map(0x8000, 0x100, Ram::new(0x100)).unwrap();
+    map.map(0xFF00, Console::<Vec<u8>, std::io::Empty>::SIZE, console.clone()).unwrap();
+    map.map(0xFF10, Timer::SIZE, Timer::new(interrupts.clone())).unwrap();
+    map.map(0xFF20, Halt::SIZE, Halt::new()).unwrap();
+
+    let mut machine = Machine { interrupts, ..Machine::new(map) };
+    machine.cpu.rsb = 0x8000;
+    machine.cpu.rsh = 0x8000;
+    let code = machine.run(1000).unwrap().unwrap();
+
+    assert_eq!(console.borrow().output(), b"hello\n");
+    // One tick every 64 cycles
+    assert!(code > 0 && code == machine.cpu.r[9], "{code}");
+    assert_eq!(machine.cpu.r[8], 4);
+}
+
+#[test]
+fn cycles() {
+    let mut machine = machine("
+            mov 2, rb0
+            mov loop - next, r1
+        loop:
+            sub 1, rb0
+            cmp 0, rb0
+            jneq r1
+        next:
+            push r0
+    ");
+    for _ in 0..9 {
+        machine.step().unwrap();
+    }
+    // Both movs, twice sub, cmp and jneq, only taken the first time, and push
+    assert_eq!(machine.cycles, 2 * 5 + 2 * (5 + 5 + 3) + 1 + 7);
+
+    machine.timing = Timing { fetch: 0, memory: 0, taken: 10, ..Timing::new() };
+    machine.cycles = 0;
+    machine.cpu.rip = 0;
+    for _ in 0..5 {
+        machine.step().unwrap();
+    }
+    assert_eq!(machine.cycles, 5 + 10);
+}
+
+#[test]
+fn accesses() {
+    let cpu = Cpu { rsh: 0x8002, r: [0x1000, 0x2000, 0, 0, 0, 0, 0, 0, 0, 0], ..Cpu::new() };
+    let access = |kind, address, width| Access { kind, address, width };
+    let inst = |source : &str| source.parse::<Instruction>().unwrap();
+
+    assert_eq!(cpu.accesses(&inst("movm r0, rb2")), vec![access(AccessKind::Read, 0x1000, Width::Byte)]);
+    assert_eq!(cpu.accesses(&inst("movrm r0, r1")), vec![access(AccessKind::Write, 0x2000, Width::Word)]);
+    assert_eq!(cpu.accesses(&inst("call r0")), vec![access(AccessKind::Write, 0x8002, Width::Word)]);
+    assert_eq!(cpu.accesses(&inst("ret")), vec![access(AccessKind::Read, 0x8000, Width::Word)]);
+    assert_eq!(cpu.accesses(&inst("int r0")).len(), 3);
+    assert_eq!(cpu.accesses(&inst("add r0, r1")), vec![]);
+    assert_eq!(cpu.stores(&inst("movrm rb0, r1")), vec![0x00]);
+    assert_eq!(cpu.stores(&inst("call r0")), vec![0x0002]);
+    assert_eq!(cpu.stores(&inst("int r1")), vec![0x0002, 0, 0x2000]);
+    assert!(access(AccessKind::Read, 0x0FFF, Width::Word).overlaps(0x1000, 0x1FFF));
+    assert!(!access(AccessKind::Read, 0x0FFF, Width::Byte).overlaps(0x1000, 0x1FFF));
+}
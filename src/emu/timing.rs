@@ -0,0 +1,97 @@
+use crate::{Instruction, Width};
+
+/// Cycles every instruction takes, adding up:
+/// - `fetch` for every byte of it
+/// - `execute` of its opcode
+/// - `memory` for every other byte it moves over the bus, the stack
+///   included, so words cost twice as much as bytes
+/// - `taken` if it's a relative jump whose condition holds
+///
+/// The defaults model a simple implementation with a byte wide bus, and can
+/// be overridden to model others.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Timing {
+    pub fetch : u64,
+    pub memory : u64,
+    pub taken : u64,
+
+    /// Cycles to deliver an interrupt, besides pushing to the stack
+    pub interrupt : u64,
+
+    /// Cycles to execute each instruction, by opcode
+    pub execute : [u64; 256],
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        let mut execute = [1; 256];
+        // Shifts go a bit at a time
+        execute[0x1D..=0x22].fill(2);
+        Self { fetch: 1, memory: 2, taken: 1, interrupt: 2, execute }
+    }
+}
+
+impl Timing {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Byte accesses to the bus of `inst`, besides fetching it
+    pub fn accesses(inst : &Instruction) -> u64 {
+        use Instruction::*;
+        let bytes = |width| match width {
+            Width::Byte => 1,
+            Width::Word => 2,
+        };
+        match inst {
+            MovM2R(_, reg) | MovR2M(reg, _) => bytes(reg.width()),
+            Push(_) | Pop(_) | CallC(_) | CallR(_) | Ret => 2,
+            Int(_) => 6,
+            _ => 0,
+        }
+    }
+
+    /// Cycles `inst` takes, `taken` telling whether it jumps if it's a
+    /// relative jump
+    pub fn cost(&self, inst : &Instruction, taken : bool) -> u64 {
+        let mut cycles = self.fetch * inst.len() as u64
+            + self.execute[inst.opcode() as usize]
+            + self.memory * Self::accesses(inst);
+        if taken {
+            cycles += self.taken;
+        }
+        cycles
+    }
+
+    /// Cycles to deliver an interrupt, pushing three words
+    pub fn delivery(&self) -> u64 {
+        self.interrupt + 6 * self.memory
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Register, Value};
+
+    #[test]
+    fn cost() {
+        let r0 = Register::R(Width::Word, 0);
+        let timing = Timing::new();
+
+        assert_eq!(timing.cost(&Instruction::nop(), false), 3);
+        assert_eq!(timing.cost(&Instruction::movc2r(Value::word(5), r0).unwrap(), false), 5);
+        assert_eq!(timing.cost(&Instruction::movm2r(r0, r0).unwrap(), false), 7);
+        assert_eq!(timing.cost(&Instruction::movm2r(r0, Register::R(Width::Byte, 0)).unwrap(), false), 5);
+        assert_eq!(timing.cost(&Instruction::shl(Value::byte(1), r0).unwrap(), false), 4);
+        assert_eq!(timing.cost(&Instruction::jeq(r0).unwrap(), false), 3);
+        assert_eq!(timing.cost(&Instruction::jeq(r0).unwrap(), true), 4);
+        assert_eq!(timing.cost(&Instruction::int(r0).unwrap(), false), 15);
+        assert_eq!(timing.delivery(), 14);
+
+        let mut timing = Timing { fetch: 0, memory: 10, ..Timing::new() };
+        timing.execute[Instruction::ret().opcode() as usize] = 4;
+        assert_eq!(timing.cost(&Instruction::ret(), false), 24);
+        assert_eq!(timing.cost(&Instruction::push(r0).unwrap(), false), 21);
+    }
+}
@@ -0,0 +1,431 @@
+//! Execution traces.
+//!
+//! A trace holds the CPU it started from and what every step changed. Every
+//! multi-byte value is little endian:
+//!
+//! ```text
+//! magic "SMPT", version: u16, start registers: 16 u16 in encoding order
+//! (RINFO, RIP, RINT, Flags, RSB, RSH, R0 to R9), step count: u32,
+//! steps:     rip: u16, has: u8 (1: interrupt, 2: flags), [interrupt: u16],
+//!            instruction bytes, [flags: u16], register count: u8, registers,
+//!            store count: u8, stores
+//! registers: code: u8, value: u16
+//! stores:    address: u16, width: u8 (0: byte, 1: word), value: u16
+//! ```
+
+use std::io::{Read, Write};
+
+use crate::{
+    utils::{Error, Reader, Result},
+    Instruction, Register, Width,
+};
+
+use super::{AccessKind, Bus, Cpu, Flags, Machine, Observed};
+
+const MAGIC : &[u8; 4] = b"SMPT";
+pub const VERSION : u16 = 1;
+
+const HAS_INTERRUPT : u8 = 1;
+const HAS_FLAGS : u8 = 2;
+
+/// Every register in encoding order
+fn registers() -> impl Iterator<Item = Register> {
+    (0..16).map(|code| Register::from_src(Width::Word, code))
+}
+
+/// Write to the bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Store {
+    pub address : u16,
+    pub width : Width,
+    pub value : u16,
+}
+
+/// What a single step changed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    /// Interrupt delivered before the instruction
+    pub interrupt : Option<u16>,
+
+    /// Address of the instruction
+    pub rip : u16,
+    pub inst : Instruction,
+
+    /// New values of the registers that changed, but Flags, in encoding order
+    pub registers : Vec<(Register, u16)>,
+    pub flags : Option<Flags>,
+
+    /// Writes, with the values stored
+    pub stores : Vec<Store>,
+}
+
+impl Step {
+    /// Step that `observed` saw, ending with `after`
+    pub fn new(observed : &Observed, after : &Cpu) -> Self {
+        let before = &observed.before;
+        let registers = registers()
+            .filter(|reg| *reg != Register::Flags && before.get(*reg) != after.get(*reg))
+            .map(|reg| (reg, after.get(reg)))
+            .collect();
+        let stores = observed.accesses.iter()
+            .filter(|access| access.kind == AccessKind::Write)
+            .zip(observed.stored.iter())
+            .map(|(access, value)| {
+                let value = match access.width {
+                    Width::Byte => value & 0xFF,
+                    Width::Word => *value,
+                };
+                Store { address: access.address, width: access.width, value }
+            })
+            .collect();
+
+        Self {
+            interrupt: observed.interrupt,
+            rip: observed.rip,
+            inst: observed.inst,
+            registers,
+            flags: (before.flags != after.flags).then_some(after.flags),
+            stores,
+        }
+    }
+
+    /// New values of registers, flags and memory
+    fn changes(&self) -> String {
+        let mut changes = self.registers.iter().map(|(reg, value)| format!("{reg}={value:#06X}")).collect::<Vec<_>>();
+        changes.extend(self.flags.map(|flags| format!("{flags:?}")));
+        changes.extend(self.stores.iter().map(|store| match store.width {
+            Width::Byte => format!("[{:#06X}]={:#04X}", store.address, store.value),
+            Width::Word => format!("[{:#06X}]={:#06X}", store.address, store.value),
+        }));
+        changes.join(" ")
+    }
+
+    /// Single line describing the step, and one before it for the interrupt
+    pub fn render(&self) -> String {
+        let mut res = String::new();
+        if let Some(number) = self.interrupt {
+            res += &format!("interrupt {number:#06X}\n");
+        }
+        let line = format!("{:#06X}  {:<20}  {}", self.rip, self.inst.to_string(), self.changes());
+        res += line.trim_end();
+        res += "\n";
+        res
+    }
+
+    /// First difference with `other`, as expected
+    fn difference(&self, other : &Self) -> Option<String> {
+        let differs = |what : &str, expected : String, got : String| Some(format!("expected {what} {expected}, got {got}"));
+        if self.interrupt != other.interrupt {
+            return differs("interrupt", format!("{:?}", other.interrupt), format!("{:?}", self.interrupt));
+        }
+        if self.rip != other.rip {
+            return differs("RIP", format!("{:#06X}", other.rip), format!("{:#06X}", self.rip));
+        }
+        if self.inst != other.inst {
+            return differs("instruction", other.inst.to_string(), self.inst.to_string());
+        }
+        if self != other {
+            return differs("changes", other.changes(), self.changes());
+        }
+        None
+    }
+}
+
+/// Steps a [`Machine`] took from a starting CPU
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trace {
+    pub start : Cpu,
+    pub steps : Vec<Step>,
+}
+
+impl Trace {
+    /// Steps `machine` until the bus halts or `limit` steps
+    pub fn record<B : Bus>(machine : &mut Machine<B>, limit : u64) -> Result<Self> {
+        let mut res = Self { start: machine.cpu.clone(), steps: vec![] };
+        for _ in 0..limit {
+            if machine.bus.halted().is_some() {
+                break;
+            }
+            let observed = machine.observe()?;
+            res.steps.push(Step::new(&observed, &machine.cpu));
+        }
+        Ok(res)
+    }
+
+    /// Executes the steps again on `machine`, which has to start like the
+    /// trace did, failing at the first one that doesn't do the same. Recorded
+    /// interrupts are raised again, so devices shouldn't raise them.
+    pub fn replay<B : Bus>(&self, machine : &mut Machine<B>) -> Result<()> {
+        if machine.cpu != self.start {
+            return Err(Error::TraceMismatch(0, format!("expected to start from {:?}, got {:?}", self.start, machine.cpu)));
+        }
+
+        for (idx, step) in self.steps.iter().enumerate() {
+            if let Some(number) = step.interrupt {
+                machine.interrupts.raise(number);
+            }
+            let observed = machine.observe().map_err(|err| Error::TraceMismatch(idx, err.to_string()))?;
+            if let Some(difference) = Step::new(&observed, &machine.cpu).difference(step) {
+                return Err(Error::TraceMismatch(idx, difference));
+            }
+        }
+        Ok(())
+    }
+
+    /// Every step, one per line
+    pub fn render(&self) -> String {
+        self.steps.iter().map(Step::render).collect()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut res = MAGIC.to_vec();
+        res.extend(VERSION.to_le_bytes());
+        for reg in registers() {
+            res.extend(self.start.get(reg).to_le_bytes());
+        }
+        res.extend((self.steps.len() as u32).to_le_bytes());
+
+        for step in self.steps.iter() {
+            res.extend(step.rip.to_le_bytes());
+            let has = match step.interrupt { Some(_) => HAS_INTERRUPT, None => 0 }
+                | match step.flags { Some(_) => HAS_FLAGS, None => 0 };
+            res.push(has);
+            if let Some(number) = step.interrupt {
+                res.extend(number.to_le_bytes());
+            }
+            res.extend(step.inst.compile());
+            if let Some(flags) = step.flags {
+                res.extend(flags.bits().to_le_bytes());
+            }
+
+            res.push(step.registers.len() as u8);
+            for (reg, value) in step.registers.iter() {
+                res.push(reg.compile_src());
+                res.extend(value.to_le_bytes());
+            }
+            res.push(step.stores.len() as u8);
+            for store in step.stores.iter() {
+                res.extend(store.address.to_le_bytes());
+                res.push(if store.width == Width::Byte { 0 } else { 1 });
+                res.extend(store.value.to_le_bytes());
+            }
+        }
+
+        res
+    }
+
+    pub fn from_bytes(bytes : &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(bytes, Error::InvalidTrace);
+        if reader.bytes(4)? != MAGIC {
+            return Err(Error::InvalidTrace("bad magic".to_string()));
+        }
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        let mut start = Cpu::new();
+        for reg in registers() {
+            start.set(reg, reader.u16()?);
+        }
+
+        let mut steps = vec![];
+        for _ in 0..reader.u32()? {
+            let rip = reader.u16()?;
+            let has = reader.u8()?;
+            if has & !(HAS_INTERRUPT | HAS_FLAGS) != 0 {
+                return Err(Error::InvalidTrace(format!("invalid step {has:#04X}")));
+            }
+            let interrupt = match has & HAS_INTERRUPT {
+                0 => None,
+                _ => Some(reader.u16()?),
+            };
+
+            let opcode = reader.u8()?;
+            let len = Instruction::encoded_len(opcode).map_err(|err| Error::InvalidTrace(err.to_string()))?;
+            let mut inst = vec![opcode];
+            inst.extend(reader.bytes(len - 1)?);
+            let (inst, _) = Instruction::decode(&inst).map_err(|err| Error::InvalidTrace(err.to_string()))?;
+
+            let flags = match has & HAS_FLAGS {
+                0 => None,
+                _ => Some(Flags::from_bits(reader.u16()?)),
+            };
+
+            let mut registers = vec![];
+            for _ in 0..reader.u8()? {
+                let code = reader.u8()?;
+                if code > 0xF {
+                    return Err(Error::InvalidTrace(format!("invalid register {code}")));
+                }
+                registers.push((Register::from_src(Width::Word, code), reader.u16()?));
+            }
+            let mut stores = vec![];
+            for _ in 0..reader.u8()? {
+                let address = reader.u16()?;
+                let width = match reader.u8()? {
+                    0 => Width::Byte,
+                    1 => Width::Word,
+                    width => return Err(Error::InvalidTrace(format!("invalid width {width}"))),
+                };
+                stores.push(Store { address, width, value: reader.u16()? });
+            }
+
+            steps.push(Step { interrupt, rip, inst, registers, flags, stores });
+        }
+
+        if !reader.is_empty() {
+            return Err(Error::InvalidTrace("trailing bytes".to_string()));
+        }
+
+        Ok(Self { start, steps })
+    }
+
+    pub fn write(&self, mut writer : impl Write) -> Result<()> {
+        Ok(writer.write_all(&self.to_bytes())?)
+    }
+
+    pub fn read(mut reader : impl Read) -> Result<Self> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes)?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        asm::assemble,
+        emu::{device::{Console, Halt}, Memory, MemoryMap, Rom},
+    };
+
+    const PROGRAM : &str = "
+            mov handler, r0
+            mov 0x00FF, r1
+            push r1
+            add 1, rb1
+            sti r0
+        handler:
+            pop r2
+            nop
+    ";
+
+    fn machine(source : &str) -> Machine<Memory> {
+        let mut machine = Machine::new(Memory::with(0, &assemble(source).unwrap().image));
+        machine.cpu.rsb = 0x8000;
+        machine.cpu.rsh = 0x8000;
+        machine
+    }
+
+    fn trace() -> Trace {
+        let mut machine = machine(PROGRAM);
+        machine.interrupts.raise(7);
+        Trace::record(&mut machine, 7).unwrap()
+    }
+
+    #[test]
+    fn record() {
+        assert_eq!(trace().render(), "\
+0x0000  mov 0x0010, r0        RIP=0x0004 R0=0x0010
+0x0004  mov 0x00FF, r1        RIP=0x0008 R1=0x00FF
+0x0008  push r1               RIP=0x000A RSH=0x8002 [0x8000]=0x00FF
+0x000A  add 0x01, rb1         RIP=0x000E R1=0x0000 Flags(Z | C)
+0x000E  sti r0                RIP=0x0010 RINT=0x0010 Flags(Z | C | I)
+interrupt 0x0007
+0x0010  pop r2                RIP=0x0012 RSH=0x8006 R2=0x0007 Flags(Z | C) [0x8002]=0x0010 [0x8004]=0x0013 [0x8006]=0x0007
+0x0012  nop                   RIP=0x0014
+");
+    }
+
+    #[test]
+    fn roundtrip() {
+        let trace = trace();
+        let bytes = trace.to_bytes();
+        assert_eq!(&bytes[..4], b"SMPT");
+        assert_eq!(Trace::from_bytes(&bytes), Ok(trace.clone()));
+
+        let mut bytes = vec![];
+        trace.write(&mut bytes).unwrap();
+        assert_eq!(Trace::read(&bytes[..]), Ok(trace));
+    }
+
+    #[test]
+    fn invalid() {
+        let bytes = trace().to_bytes();
+
+        let mut bad = bytes.clone();
+        bad[0] = b'X';
+        assert_eq!(Trace::from_bytes(&bad), Err(Error::InvalidTrace("bad magic".to_string())));
+
+        let mut bad = bytes.clone();
+        bad[4] = 2;
+        assert_eq!(Trace::from_bytes(&bad), Err(Error::UnsupportedVersion(2)));
+
+        assert_eq!(Trace::from_bytes(&bytes[..bytes.len() - 1]), Err(Error::InvalidTrace("truncated".to_string())));
+
+        let mut bad = bytes.clone();
+        bad.push(0);
+        assert_eq!(Trace::from_bytes(&bad), Err(Error::InvalidTrace("trailing bytes".to_string())));
+    }
+
+    #[test]
+    fn replay() {
+        let trace = trace();
+        assert_eq!(trace.replay(&mut machine(PROGRAM)), Ok(()));
+
+        let changed = PROGRAM.replace("add 1, rb1", "add 2, rb1");
+        assert_eq!(
+            trace.replay(&mut machine(&changed)),
+            Err(Error::TraceMismatch(3, "expected instruction add 0x01, rb1, got add 0x02, rb1".to_string())),
+        );
+
+        // As if the flags used to be computed otherwise
+        let mut old = trace.clone();
+        old.steps[3].flags = Some(Flags::ZERO);
+        assert_eq!(
+            old.replay(&mut machine(PROGRAM)),
+            Err(Error::TraceMismatch(3, "expected changes RIP=0x000E R1=0x0000 Flags(Z), got RIP=0x000E R1=0x0000 Flags(Z | C)".to_string())),
+        );
+
+        let mut moved = machine(PROGRAM);
+        moved.cpu.rsh = 0x9000;
+        assert!(matches!(trace.replay(&mut moved), Err(Error::TraceMismatch(0, _))));
+    }
+
+    #[test]
+    fn devices() {
+        let devices = |source : &str| {
+            let image = assemble(source).unwrap().image;
+            let mut map = MemoryMap::new();
+            map.map(0x0000, image.len() as u32, Rom(image)).unwrap();
+            map.map(0xFF00, Console::<Vec<u8>, std::io::Empty>::SIZE, Console::new(vec![], std::io::empty())).unwrap();
+            map.map(0xFF20, Halt::SIZE, Halt::new()).unwrap();
+            Machine::new(map)
+        };
+        let source = "
+                mov 0x0A41, r0
+                mov 0xFF00, r1
+                movrm r0, r1
+                mov 0xFF20, r1
+                movrm rb0, r1
+        ";
+
+        // What was written, which the devices don't read back
+        let trace = Trace::record(&mut devices(source), 10).unwrap();
+        let stores = trace.steps.iter().flat_map(|step| step.stores.iter().copied()).collect::<Vec<_>>();
+        assert_eq!(stores, vec![
+            Store { address: 0xFF00, width: Width::Word, value: 0x0A41 },
+            Store { address: 0xFF20, width: Width::Byte, value: 0x41 },
+        ]);
+        assert_eq!(trace.replay(&mut devices(source)), Ok(()));
+
+        // As if the console was peeked after writing
+        let mut old = trace.clone();
+        old.steps[2].stores[0].value = 0;
+        assert_eq!(
+            old.replay(&mut devices(source)),
+            Err(Error::TraceMismatch(2, "expected changes RIP=0x000A [0xFF00]=0x0000, got RIP=0x000A [0xFF00]=0x0A41".to_string())),
+        );
+    }
+}
@@ -126,10 +126,11 @@ mod test {
                 assert_eq!(bytes, vec![inst.opcode(), 8 | Register::rb0().compile_dest()]);
                 assert_eq!(bytes.len(), inst.len().into());
 
-                let inst = Instruction::$ident(Value::byte(16), Register::r0()).unwrap();
+                let inst = Instruction::$ident(Value::byte(15), Register::r0()).unwrap();
                 let bytes = inst.compile();
-                assert_eq!(bytes, vec![inst.opcode(), 16 | Register::rb0().compile_dest()]);
+                assert_eq!(bytes, vec![inst.opcode(), 15 | Register::r0().compile_dest()]);
                 assert_eq!(bytes.len(), inst.len().into());
+                assert!(Instruction::$ident(Value::byte(16), Register::r0()).is_err());
             }
         };
     }
@@ -0,0 +1,247 @@
+use super::*;
+
+impl Instruction {
+    /// Length of the instruction starting with `opcode`
+    pub fn encoded_len(opcode : u8) -> Result<usize> {
+        match opcode {
+            0x01 | 0x02 | 0x0B | 0x0C | 0x0F | 0x10 | 0x15 | 0x16 | 0x19 | 0x1A | 0x23 | 0x24 | 0x31 => Ok(4),
+            0x00..=0x36 => Ok(2),
+            _ => Err(Error::UnknownOpcode(opcode)),
+        }
+    }
+
+    /// Decodes the instruction at the start of `bytes`, returning it together
+    /// with the amount of bytes it occupies. This is the inverse of
+    /// [`Instruction::compile`], except for [`Instruction::DB`] (which is data
+    /// and never produced) and shift amounts, which are always decoded as
+    /// [`Value::byte`]. Bytes `compile` can't produce, like padding that
+    /// isn't zero, fail with [`Error::NonCanonicalEncoding`].
+    pub fn decode(bytes : &[u8]) -> Result<(Self, usize)> {
+        use Instruction::*;
+        use Width::*;
+
+        let opcode = *bytes.first().ok_or(Error::TruncatedInstruction(1, 0))?;
+        let len = Self::encoded_len(opcode)?;
+        if bytes.len() < len {
+            return Err(Error::TruncatedInstruction(len, bytes.len()));
+        }
+
+        // Only meaningful for the opcodes that come in byte/word pairs
+        let width = if opcode % 2 == 1 { Byte } else { Word };
+
+        let reg = |nibble : u8, width : Width| {
+            let reg = Register::from_src(width, nibble);
+            (reg.width() == width).then_some(reg).ok_or(Error::InvalidRegisterNibble(opcode, nibble & 0xF))
+        };
+        let src = |width| reg(bytes[1] & 0xF, width);
+        let dest = |width| reg(bytes[1] >> 4, width);
+        let value = || match width {
+            Byte => Value::byte(bytes[2]),
+            Word => Value::word(u16::from_le_bytes([bytes[2], bytes[3]])),
+        };
+        let shift = || Value::byte(bytes[1] & 0xF);
+
+        let inst = match opcode {
+            0x00 => Nop,
+
+            0x01 | 0x02 => MovC2R(value(), dest(width)?),
+            0x03 | 0x04 => MovR2R(src(width)?, dest(width)?),
+            0x05 | 0x06 => MovM2R(src(Word)?, dest(width)?),
+            0x07 | 0x08 => MovR2M(src(width)?, dest(Word)?),
+            0x09 => Push(src(Word)?),
+            0x0A => Pop(src(Word)?),
+
+            0x0B | 0x0C => AddC2R(value(), dest(width)?),
+            0x0D | 0x0E => AddR2R(src(width)?, dest(width)?),
+            0x0F | 0x10 => SubC2R(value(), dest(width)?),
+            0x11 | 0x12 => SubR2R(src(width)?, dest(width)?),
+            0x13 | 0x14 => Not(src(width)?),
+            0x15 | 0x16 => AndC2R(value(), dest(width)?),
+            0x17 | 0x18 => AndR2R(src(width)?, dest(width)?),
+            0x19 | 0x1A => OrC2R(value(), dest(width)?),
+            0x1B | 0x1C => OrR2R(src(width)?, dest(width)?),
+            0x1D | 0x1E => Shl(shift(), dest(width)?),
+            0x1F | 0x20 => Shr(shift(), dest(width)?),
+            0x21 | 0x22 => Shre(shift(), dest(width)?),
+            0x23 | 0x24 => CmpC2R(value(), dest(width)?),
+            0x25 | 0x26 => CmpR2R(src(width)?, dest(width)?),
+
+            0x27 => AJmp(src(Word)?),
+            0x28 => Jmp(src(Word)?),
+            0x29 => Jeq(src(Word)?),
+            0x2A => Jneq(src(Word)?),
+            0x2B => Jlt(src(Word)?),
+            0x2C => Jgt(src(Word)?),
+            0x2D => Jleq(src(Word)?),
+            0x2E => Jgeq(src(Word)?),
+            0x2F => Jo(src(Word)?),
+            0x30 => Jno(src(Word)?),
+            0x31 => CallC(Value::word(u16::from_le_bytes([bytes[2], bytes[3]]))),
+            0x32 => CallR(src(Word)?),
+            0x33 => Ret,
+
+            0x34 => Int(src(Word)?),
+            0x35 => Sti(src(Word)?),
+            0x36 => Cli,
+
+            _ => unreachable!(),
+        };
+
+        if !inst.is_valid() {
+            return Err(Error::OperandWidthMismatch(inst));
+        }
+        match inst.compile() == bytes[..len] {
+            true => Ok((inst, len)),
+            false => Err(Error::NonCanonicalEncoding(bytes[..len].to_vec())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn registers() -> Vec<Register> {
+        let mut regs = vec![Register::RINFO, Register::RIP, Register::RINT, Register::Flags, Register::RSB, Register::RSH];
+        for n in 0..10 {
+            regs.push(Register::R(Width::Byte, n));
+            regs.push(Register::R(Width::Word, n));
+        }
+        regs
+    }
+
+    fn roundtrip(inst : Instruction) {
+        if !inst.is_valid() {
+            return;
+        }
+
+        let bytes = inst.compile();
+        assert_eq!(Instruction::decode(&bytes), Ok((inst, bytes.len())), "{:?}", inst);
+    }
+
+    #[test]
+    fn no_operands() {
+        roundtrip(Instruction::Nop);
+        roundtrip(Instruction::Ret);
+        roundtrip(Instruction::Cli);
+    }
+
+    #[test]
+    fn one_register() {
+        for reg in registers() {
+            for inst in [
+                Instruction::Push, Instruction::Pop, Instruction::Not,
+                Instruction::AJmp, Instruction::Jmp, Instruction::Jeq, Instruction::Jneq, Instruction::Jlt, Instruction::Jgt,
+                Instruction::Jleq, Instruction::Jgeq, Instruction::Jo, Instruction::Jno, Instruction::CallR,
+                Instruction::Int, Instruction::Sti,
+            ] {
+                roundtrip(inst(reg));
+            }
+        }
+    }
+
+    #[test]
+    fn two_registers() {
+        for src in registers() {
+            for dest in registers() {
+                for inst in [
+                    Instruction::MovR2R, Instruction::MovM2R, Instruction::MovR2M,
+                    Instruction::AddR2R, Instruction::SubR2R, Instruction::AndR2R, Instruction::OrR2R, Instruction::CmpR2R,
+                ] {
+                    roundtrip(inst(src, dest));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn constant_to_register() {
+        for dest in registers() {
+            for value in [Value::byte(0), Value::byte(0xF3), Value::word(0), Value::word(0xF337)] {
+                for inst in [
+                    Instruction::MovC2R, Instruction::AddC2R, Instruction::SubC2R,
+                    Instruction::AndC2R, Instruction::OrC2R, Instruction::CmpC2R,
+                ] {
+                    roundtrip(inst(value, dest));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn shifts() {
+        for dest in registers() {
+            for shift in 0..16 {
+                for inst in [Instruction::Shl, Instruction::Shr, Instruction::Shre] {
+                    roundtrip(inst(Value::byte(shift), dest));
+                }
+            }
+        }
+
+        // Wouldn't fit the nibble
+        assert!(!Instruction::Shl(Value::byte(16), Register::r0()).is_valid());
+    }
+
+    #[test]
+    fn callc() {
+        roundtrip(Instruction::CallC(Value::word(0)));
+        roundtrip(Instruction::CallC(Value::word(0xF337)));
+    }
+
+    #[test]
+    fn trailing_bytes() {
+        let bytes = [Instruction::Ret.compile(), Instruction::Nop.compile()].concat();
+        assert_eq!(Instruction::decode(&bytes), Ok((Instruction::Ret, 2)));
+    }
+
+    #[test]
+    fn canonical() {
+        assert_eq!(Instruction::decode(&[0x33, 0xFF]), Err(Error::NonCanonicalEncoding(vec![0x33, 0xFF])));
+        assert_eq!(Instruction::decode(&[0x31, 0x01, 0x00, 0x00]), Err(Error::NonCanonicalEncoding(vec![0x31, 0x01, 0x00, 0x00])));
+        assert_eq!(Instruction::decode(&[0x09, 0x16]), Err(Error::NonCanonicalEncoding(vec![0x09, 0x16])));
+        assert_eq!(Instruction::decode(&[0x01, 0x60, 0x12, 0x34]), Err(Error::NonCanonicalEncoding(vec![0x01, 0x60, 0x12, 0x34])));
+
+        // Whatever is accepted compiles back to the same bytes
+        let values = [0x00, 0x01, 0x0F, 0x10, 0x7F, 0x80, 0xFF];
+        for opcode in 0x00..=0x36 {
+            for operands in 0x00..=0xFF {
+                for (low, high) in values.iter().flat_map(|low| values.iter().map(move |high| (*low, *high))) {
+                    let bytes = [opcode, operands, low, high];
+                    let len = Instruction::encoded_len(opcode).unwrap();
+                    if let Ok((inst, decoded)) = Instruction::decode(&bytes) {
+                        assert_eq!((inst.compile(), decoded), (bytes[..len].to_vec(), len), "{bytes:02X?}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_opcode() {
+        assert_eq!(Instruction::decode(&[0x37, 0x00]), Err(Error::UnknownOpcode(0x37)));
+        assert_eq!(Instruction::decode(&[0xFF, 0x00]), Err(Error::UnknownOpcode(0xFF)));
+    }
+
+    #[test]
+    fn truncated() {
+        assert_eq!(Instruction::decode(&[]), Err(Error::TruncatedInstruction(1, 0)));
+        assert_eq!(Instruction::decode(&[0x33]), Err(Error::TruncatedInstruction(2, 1)));
+        assert_eq!(Instruction::decode(&[0x02, 0x60, 0x34]), Err(Error::TruncatedInstruction(4, 3)));
+    }
+
+    #[test]
+    fn invalid_register_nibble() {
+        // Byte width opcode with a word only register
+        assert_eq!(Instruction::decode(&[0x03, 0x61]), Err(Error::InvalidRegisterNibble(0x03, 0x1)));
+        assert_eq!(Instruction::decode(&[0x01, 0x30, 0x00, 0x00]), Err(Error::InvalidRegisterNibble(0x01, 0x3)));
+    }
+
+    #[test]
+    fn invalid_instruction() {
+        // Destiny isn't writable
+        assert_eq!(
+            Instruction::decode(&[0x02, 0x10, 0x34, 0x12]),
+            Err(Error::OperandWidthMismatch(Instruction::MovC2R(Value::word(0x1234), Register::RIP)))
+        );
+    }
+}
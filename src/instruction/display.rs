@@ -0,0 +1,99 @@
+use super::*;
+
+impl Instruction {
+    pub fn mnemonic(&self) -> &'static str {
+        use Instruction::*;
+        match self {
+            Nop => "nop",
+            DB(_) => "db",
+
+            MovC2R(_, _) | MovR2R(_, _) => "mov",
+            MovM2R(_, _) => "movm",
+            MovR2M(_, _) => "movrm",
+            Push(_) => "push",
+            Pop(_) => "pop",
+
+            AddC2R(_, _) | AddR2R(_, _) => "add",
+            SubC2R(_, _) | SubR2R(_, _) => "sub",
+            Not(_) => "not",
+            AndC2R(_, _) | AndR2R(_, _) => "and",
+            OrC2R(_, _) | OrR2R(_, _) => "or",
+            Shl(_, _) => "shl",
+            Shr(_, _) => "shr",
+            Shre(_, _) => "shre",
+            CmpC2R(_, _) | CmpR2R(_, _) => "cmp",
+
+            AJmp(_) => "ajmp",
+            Jmp(_) => "jmp",
+            Jeq(_) => "jeq",
+            Jneq(_) => "jneq",
+            Jlt(_) => "jlt",
+            Jgt(_) => "jgt",
+            Jleq(_) => "jleq",
+            Jgeq(_) => "jgeq",
+            Jo(_) => "jo",
+            Jno(_) => "jno",
+            CallC(_) | CallR(_) => "call",
+            Ret => "ret",
+
+            Int(_) => "int",
+            Sti(_) => "sti",
+            Cli => "cli",
+        }
+    }
+}
+
+/// Register as written in assembly
+fn reg(reg : &Register) -> String {
+    reg.to_string().to_lowercase()
+}
+
+impl std::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use Instruction::*;
+
+        write!(f, "{}", self.mnemonic())?;
+        match self {
+            Nop | Ret | Cli => Ok(()),
+            DB(value) => write!(f, " {}", Value::byte(*value)),
+
+            MovC2R(value, dest) |
+            AddC2R(value, dest) | SubC2R(value, dest) | AndC2R(value, dest) | OrC2R(value, dest) | CmpC2R(value, dest) |
+            Shl(value, dest) | Shr(value, dest) | Shre(value, dest)
+                => write!(f, " {}, {}", value, reg(dest)),
+
+            MovR2R(src, dest) | MovM2R(src, dest) | MovR2M(src, dest) |
+            AddR2R(src, dest) | SubR2R(src, dest) | AndR2R(src, dest) | OrR2R(src, dest) | CmpR2R(src, dest)
+                => write!(f, " {}, {}", reg(src), reg(dest)),
+
+            Push(reg_) | Pop(reg_) |
+            Not(reg_) |
+            AJmp(reg_) | Jmp(reg_) | Jeq(reg_) | Jneq(reg_) | Jlt(reg_) | Jgt(reg_) | Jleq(reg_) | Jgeq(reg_) | Jo(reg_) | Jno(reg_) | CallR(reg_) |
+            Int(reg_) | Sti(reg_)
+                => write!(f, " {}", reg(reg_)),
+
+            CallC(value) => write!(f, " {}", value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn display() {
+        assert_eq!(Instruction::nop().to_string(), "nop");
+        assert_eq!(Instruction::db(0xF3).to_string(), "db 0xF3");
+        assert_eq!(Instruction::movc2r(Value::word(0x1234), Register::r0()).unwrap().to_string(), "mov 0x1234, r0");
+        assert_eq!(Instruction::movc2r(Value::byte(0x12), Register::rb0()).unwrap().to_string(), "mov 0x12, rb0");
+        assert_eq!(Instruction::movr2r(Register::RIP, Register::r9()).unwrap().to_string(), "mov rip, r9");
+        assert_eq!(Instruction::movm2r(Register::r1(), Register::r2()).unwrap().to_string(), "movm r1, r2");
+        assert_eq!(Instruction::movr2m(Register::rb1(), Register::r2()).unwrap().to_string(), "movrm rb1, r2");
+        assert_eq!(Instruction::shl(Value::byte(3), Register::r2()).unwrap().to_string(), "shl 0x03, r2");
+        assert_eq!(Instruction::jeq(Register::r3()).unwrap().to_string(), "jeq r3");
+        assert_eq!(Instruction::callc(Value::word(0x10)).unwrap().to_string(), "call 0x0010");
+        assert_eq!(Instruction::callr(Register::r4()).unwrap().to_string(), "call r4");
+        assert_eq!(Instruction::ret().to_string(), "ret");
+    }
+}
@@ -102,10 +102,10 @@ pub enum Instruction {
     /// Relative jump if not overflow
     Jno(Register),
 
-    /// Push RIP and to the stack and relative jump
+    /// Push RIP to the stack and absolute jump
     CallC(Value),
 
-    /// Push RIP and to the stack and relative jump
+    /// Push RIP to the stack and relative jump
     CallR(Register),
 
     /// Pop RIP from the stack
@@ -239,8 +239,9 @@ impl Instruction {
 
             Shl(shift, reg)| Shr(shift, reg) | Shre(shift, reg)
                 => match reg.width() {
-                    Width::Byte => shift.value_byte(0) <= 8,
-                    Width::Word => shift.value_byte(0) <= 16,
+                    Width::Byte => shift.value_word() <= 8,
+                    // A shift of 16 doesn't fit the nibble it's encoded in
+                    Width::Word => shift.value_word() <= 15,
                 }
         }
     }
@@ -326,6 +327,10 @@ impl Instruction {
 }
 
 mod compile;
+mod decode;
+mod display;
+mod parse;
+pub(crate) use parse::{parse_literal, Operand};
 
 #[cfg(test)]
 mod test;
@@ -0,0 +1,287 @@
+use std::str::FromStr;
+
+use super::*;
+
+const MNEMONICS : &[&str] = &[
+    "nop", "db",
+    "mov", "movm", "movrm", "push", "pop",
+    "add", "sub", "not", "and", "or", "shl", "shr", "shre", "cmp",
+    "ajmp", "jmp", "jeq", "jneq", "jlt", "jgt", "jleq", "jgeq", "jo", "jno", "call", "ret",
+    "int", "sti", "cli",
+];
+
+/// Parses an integer literal (decimal, `0x` hexadecimal or `0b` binary,
+/// optionally negative), returning it together with the width implied by its
+/// amount of digits, if any
+pub(crate) fn parse_literal(s : &str) -> Result<(i32, Option<Width>)> {
+    let invalid = || Error::InvalidValue(s.to_string());
+
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    let lower = digits.to_lowercase();
+    let (radix, digits, width) = if let Some(hex) = lower.strip_prefix("0x") {
+        (16, hex, Some(if hex.len() <= 2 { Width::Byte } else { Width::Word }))
+    } else if let Some(bin) = lower.strip_prefix("0b") {
+        (2, bin, Some(if bin.len() <= 8 { Width::Byte } else { Width::Word }))
+    } else {
+        (10, &*lower, None)
+    };
+
+    if digits.is_empty() || digits.starts_with(['+', '-']) {
+        return Err(invalid());
+    }
+    let value = i32::from_str_radix(digits, radix).map_err(|_| invalid())?;
+    if value > 0xFFFF {
+        return Err(invalid());
+    }
+
+    Ok((if negative { -value } else { value }, width))
+}
+
+/// Whether `value` can be represented with `width`, either as unsigned or as
+/// two's complement
+pub(crate) fn fits(value : i32, width : Width) -> bool {
+    match width {
+        Width::Byte => (-0x80..=0xFF).contains(&value),
+        Width::Word => (-0x8000..=0xFFFF).contains(&value),
+    }
+}
+
+/// Builds a [`Value`] for `value`, preferring `width`, then `hint`, then the
+/// narrowest width that fits. A value too wide for `width` is returned wider so
+/// the validating constructors report the mismatch.
+pub(crate) fn value_with(value : i32, width : Option<Width>, hint : Option<Width>) -> Option<Value> {
+    let width = [width, hint, Some(Width::Byte), Some(Width::Word)]
+        .into_iter()
+        .flatten()
+        .find(|width| fits(value, *width))?;
+
+    Some(match width {
+        Width::Byte => Value::byte(value as u8),
+        Width::Word => Value::word(value as u16),
+    })
+}
+
+/// Operand of an instruction once parsed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Operand {
+    Register(Register),
+
+    /// Integer together with the width implied by how it was written, if any
+    Immediate(i32, Option<Width>),
+}
+
+impl FromStr for Operand {
+    type Err = Error;
+
+    fn from_str(s : &str) -> std::result::Result<Self, Self::Err> {
+        match Register::from_str(s) {
+            Ok(reg) => Ok(Self::Register(reg)),
+            Err(_) if s.starts_with(|c : char| c.is_ascii_digit() || c == '-') => {
+                let (value, hint) = parse_literal(s)?;
+                Ok(Self::Immediate(value, hint))
+            },
+            Err(err) => Err(err),
+        }
+    }
+}
+
+fn reg(operand : &Operand) -> Result<Register> {
+    match operand {
+        Operand::Register(reg) => Ok(*reg),
+        Operand::Immediate(value, _) => Err(Error::InvalidRegister(value.to_string())),
+    }
+}
+
+fn value(operand : &Operand, width : Option<Width>) -> Result<Value> {
+    match operand {
+        Operand::Register(reg) => Err(Error::InvalidValue(reg.to_string().to_lowercase())),
+        Operand::Immediate(value, hint) => value_with(*value, width, *hint).ok_or(Error::InvalidValue(value.to_string())),
+    }
+}
+
+impl Instruction {
+    /// Builds the instruction for `mnemonic` out of already parsed operands,
+    /// `source` is only used to report invalid operands
+    pub(crate) fn from_operands(mnemonic : &str, operands : &[Operand], source : &str) -> Result<Self> {
+        use Operand::Register as R;
+
+        macro_rules! c2r_or_r2r {
+            ($c2r:ident, $r2r:ident, $src:ident, $dest:ident) => {{
+                let dest = reg($dest)?;
+                match $src {
+                    R(src) => Self::$r2r(*src, dest),
+                    src => Self::$c2r(value(src, Some(dest.width()))?, dest),
+                }
+            }};
+        }
+
+        match (&*mnemonic.to_lowercase(), operands) {
+            ("nop", []) => Ok(Self::nop()),
+            ("db", [v]) => match value(v, Some(Width::Byte))? {
+                v if v.width() == Width::Byte => Ok(Self::db(v.value_byte(0))),
+                v => Err(Error::InvalidValue(v.value_word().to_string())),
+            },
+
+            ("mov", [src, dest]) => c2r_or_r2r!(movc2r, movr2r, src, dest),
+            ("movm", [src, dest]) => Self::movm2r(reg(src)?, reg(dest)?),
+            ("movrm", [src, dest]) => Self::movr2m(reg(src)?, reg(dest)?),
+            ("push", [r]) => Self::push(reg(r)?),
+            ("pop", [r]) => Self::pop(reg(r)?),
+
+            ("add", [src, dest]) => c2r_or_r2r!(addc2r, addr2r, src, dest),
+            ("sub", [src, dest]) => c2r_or_r2r!(subc2r, subr2r, src, dest),
+            ("not", [r]) => Self::not(reg(r)?),
+            ("and", [src, dest]) => c2r_or_r2r!(andc2r, andr2r, src, dest),
+            ("or", [src, dest]) => c2r_or_r2r!(orc2r, orr2r, src, dest),
+            ("shl", [shift, dest]) => Self::shl(value(shift, None)?, reg(dest)?),
+            ("shr", [shift, dest]) => Self::shr(value(shift, None)?, reg(dest)?),
+            ("shre", [shift, dest]) => Self::shre(value(shift, None)?, reg(dest)?),
+            ("cmp", [src, dest]) => c2r_or_r2r!(cmpc2r, cmpr2r, src, dest),
+
+            ("ajmp", [r]) => Self::ajmp(reg(r)?),
+            ("jmp", [r]) => Self::jmp(reg(r)?),
+            ("jeq", [r]) => Self::jeq(reg(r)?),
+            ("jneq", [r]) => Self::jneq(reg(r)?),
+            ("jlt", [r]) => Self::jlt(reg(r)?),
+            ("jgt", [r]) => Self::jgt(reg(r)?),
+            ("jleq", [r]) => Self::jleq(reg(r)?),
+            ("jgeq", [r]) => Self::jgeq(reg(r)?),
+            ("jo", [r]) => Self::jo(reg(r)?),
+            ("jno", [r]) => Self::jno(reg(r)?),
+            ("call", [R(r)]) => Self::callr(*r),
+            ("call", [v]) => Self::callc(value(v, Some(Width::Word))?),
+            ("ret", []) => Ok(Self::ret()),
+
+            ("int", [r]) => Self::int(reg(r)?),
+            ("sti", [r]) => Self::sti(reg(r)?),
+            ("cli", []) => Ok(Self::cli()),
+
+            (mnemonic, _) if MNEMONICS.contains(&mnemonic) => Err(Error::InvalidOperands(source.to_string())),
+            (mnemonic, _) => Err(Error::UnknownMnemonic(mnemonic.to_string())),
+        }
+    }
+
+    pub fn is_mnemonic(mnemonic : &str) -> bool {
+        MNEMONICS.contains(&&*mnemonic.to_lowercase())
+    }
+}
+
+impl FromStr for Instruction {
+    type Err = Error;
+
+    fn from_str(s : &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.trim();
+        let (mnemonic, rest) = s.split_once(char::is_whitespace).unwrap_or((s, ""));
+        let operands = match rest.trim() {
+            "" => vec![],
+            rest => rest.split(',').map(|operand| Operand::from_str(operand.trim())).collect::<Result<_>>()?,
+        };
+
+        Self::from_operands(mnemonic, &operands, s)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn registers() -> Vec<Register> {
+        let mut regs = vec![Register::RINFO, Register::RIP, Register::RINT, Register::Flags, Register::RSB, Register::RSH];
+        for n in 0..10 {
+            regs.push(Register::R(Width::Byte, n));
+            regs.push(Register::R(Width::Word, n));
+        }
+        regs
+    }
+
+    fn values() -> Vec<Value> {
+        vec![Value::byte(0), Value::byte(8), Value::byte(0xF3), Value::word(0), Value::word(16), Value::word(0xF337)]
+    }
+
+    fn roundtrip(inst : Instruction) {
+        if inst.is_valid() {
+            assert_eq!(Instruction::from_str(&inst.to_string()), Ok(inst), "{}", inst);
+        }
+    }
+
+    #[test]
+    fn roundtrip_all() {
+        roundtrip(Instruction::Nop);
+        roundtrip(Instruction::Ret);
+        roundtrip(Instruction::Cli);
+        for value in 0..=0xFF {
+            roundtrip(Instruction::DB(value));
+        }
+
+        for value in values() {
+            roundtrip(Instruction::CallC(value));
+        }
+
+        for r0 in registers() {
+            for inst in [
+                Instruction::Push, Instruction::Pop, Instruction::Not,
+                Instruction::AJmp, Instruction::Jmp, Instruction::Jeq, Instruction::Jneq, Instruction::Jlt, Instruction::Jgt,
+                Instruction::Jleq, Instruction::Jgeq, Instruction::Jo, Instruction::Jno, Instruction::CallR,
+                Instruction::Int, Instruction::Sti,
+            ] {
+                roundtrip(inst(r0));
+            }
+
+            for value in values() {
+                for inst in [
+                    Instruction::MovC2R, Instruction::AddC2R, Instruction::SubC2R, Instruction::AndC2R,
+                    Instruction::OrC2R, Instruction::CmpC2R, Instruction::Shl, Instruction::Shr, Instruction::Shre,
+                ] {
+                    roundtrip(inst(value, r0));
+                }
+            }
+
+            for r1 in registers() {
+                for inst in [
+                    Instruction::MovR2R, Instruction::MovM2R, Instruction::MovR2M,
+                    Instruction::AddR2R, Instruction::SubR2R, Instruction::AndR2R, Instruction::OrR2R, Instruction::CmpR2R,
+                ] {
+                    roundtrip(inst(r0, r1));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(Instruction::from_str("mov 4660, r0"), Ok(Instruction::MovC2R(Value::word(0x1234), Register::r0())));
+        assert_eq!(Instruction::from_str("mov 0x12, r0"), Ok(Instruction::MovC2R(Value::word(0x12), Register::r0())));
+        assert_eq!(Instruction::from_str("mov 0b101, rb0"), Ok(Instruction::MovC2R(Value::byte(5), Register::rb0())));
+        assert_eq!(Instruction::from_str("mov -1, rb0"), Ok(Instruction::MovC2R(Value::byte(0xFF), Register::rb0())));
+        assert_eq!(Instruction::from_str("sub -2, r0"), Ok(Instruction::SubC2R(Value::word(0xFFFE), Register::r0())));
+        assert_eq!(Instruction::from_str("shl 3, r0"), Ok(Instruction::Shl(Value::byte(3), Register::r0())));
+    }
+
+    #[test]
+    fn whitespace_and_case() {
+        assert_eq!(Instruction::from_str("  MOVM  R1 ,r2 "), Ok(Instruction::MovM2R(Register::r1(), Register::r2())));
+        assert_eq!(Instruction::from_str("Ret"), Ok(Instruction::Ret));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(Instruction::from_str("foo r0"), Err(Error::UnknownMnemonic("foo".to_string())));
+        assert_eq!(Instruction::from_str("ret r0"), Err(Error::InvalidOperands("ret r0".to_string())));
+        assert_eq!(Instruction::from_str("mov r0"), Err(Error::InvalidOperands("mov r0".to_string())));
+        assert_eq!(Instruction::from_str("mov 0x1234, r10"), Err(Error::InvalidRegister("r10".to_string())));
+        assert_eq!(Instruction::from_str("mov 0xZZ, r0"), Err(Error::InvalidValue("0xZZ".to_string())));
+        assert_eq!(Instruction::from_str("db 0x100"), Err(Error::InvalidValue("256".to_string())));
+        assert_eq!(Instruction::from_str("mov 0x10000, r0"), Err(Error::InvalidValue("0x10000".to_string())));
+        assert_eq!(
+            Instruction::from_str("mov 0x1234, rb0"),
+            Err(Error::OperandWidthMismatch(Instruction::MovC2R(Value::word(0x1234), Register::rb0())))
+        );
+        assert_eq!(
+            Instruction::from_str("jmp rb0"),
+            Err(Error::OperandWidthMismatch(Instruction::Jmp(Register::rb0())))
+        );
+    }
+}
@@ -1,11 +1,14 @@
 use super::*;
 
+// Shadowed by the second `check_width_c2r`, which is the one the tests use
+#[allow(unused_macros)]
 macro_rules! check_width_c2r_ok {
     ($ident:ident, $width:ident, $dest:ident) => {
         assert!(Instruction::$ident(Value::$width(0), Register::$dest()).is_ok());
     };
 }
 
+#[allow(unused_macros)]
 macro_rules! check_width_c2r_err {
     ($ident:ident, $IDENT:ident, $width:ident, $dest:ident) => {
         assert_eq!(
@@ -15,6 +18,7 @@ macro_rules! check_width_c2r_err {
     };
 }
 
+#[allow(unused_macros)]
 macro_rules! check_width_c2r {
     ($name:ident, $ident:ident, $IDENT:ident) => {
         #[test]
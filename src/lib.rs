@@ -4,7 +4,22 @@ pub use register::{Register, Width};
 mod instruction;
 pub use instruction::Instruction;
 
+mod decoder;
+pub use decoder::{Decoder, DecodeMode};
+
 mod value;
 pub use value::Value;
 
+pub mod asm;
+
+pub mod object;
+
+pub mod link;
+
+pub mod emu;
+
+pub mod debug;
+
+pub mod disasm;
+
 pub mod utils;
@@ -0,0 +1,198 @@
+//! Linker combining relocatable [`Object`]s into a flat image.
+//!
+//! Sections are laid out as told by a [`Script`], every extern is resolved
+//! to the global symbol of the same name, and the relocations are applied by
+//! patching the bytes of each section.
+
+use std::collections::BTreeMap;
+
+use crate::{
+    object::{Binding, Object, RelocationKind, Section, Target},
+    utils::{Error, Result},
+};
+
+mod script;
+pub use script::{Placement, Region, Script};
+
+/// Section of an object placed at an address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Placed {
+    pub object : String,
+    pub section : Section,
+    pub address : u16,
+    pub size : u16,
+}
+
+/// Symbol of an object with its final address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Linked {
+    pub name : String,
+    pub object : String,
+    pub binding : Binding,
+    pub address : u16,
+}
+
+/// Output of [`link`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Output {
+    /// Address of the first byte of `image`, the lowest one of any region
+    pub origin : u16,
+
+    /// Text and data of every object, up to the last byte of them
+    pub image : Vec<u8>,
+    pub sections : Vec<Placed>,
+    pub symbols : Vec<Linked>,
+}
+
+impl Output {
+    /// Address of a global symbol
+    pub fn symbol(&self, name : &str) -> Option<u16> {
+        self.symbols.iter()
+            .find(|symbol| symbol.binding == Binding::Global && symbol.name == name)
+            .map(|symbol| symbol.address)
+    }
+
+    /// Listing of every section and symbol, sorted by address
+    pub fn map(&self) -> String {
+        let mut res = String::from("Sections:\n");
+        let mut sections = self.sections.iter().collect::<Vec<_>>();
+        sections.sort_by_key(|placed| placed.address);
+        for placed in sections {
+            res += &format!("  {:#06X}  {:#06X}  {:<4}  {}\n", placed.address, placed.size, placed.section, placed.object);
+        }
+
+        res += "\nSymbols:\n";
+        let mut symbols = self.symbols.iter().collect::<Vec<_>>();
+        symbols.sort_by_key(|symbol| (symbol.address, &symbol.name));
+        for symbol in symbols {
+            let binding = if symbol.binding == Binding::Global { "global" } else { "local" };
+            res += &format!("  {:#06X}  {:<6}  {}  {}\n", symbol.address, binding, symbol.name, symbol.object);
+        }
+        res
+    }
+}
+
+/// Addresses of the sections of every object
+fn layout(script : &Script, objects : &[(&str, &Object)]) -> Result<Vec<[u16; 3]>> {
+    let mut addresses = vec![[0; 3]; objects.len()];
+    let mut cursors = script.regions.iter().map(|region| (region.name.as_str(), region.start as u32)).collect::<BTreeMap<_, _>>();
+
+    for section in [Section::Text, Section::Data, Section::Bss] {
+        if !script.placements.iter().any(|placement| placement.section == section)
+            && objects.iter().any(|(_, object)| object.size(section) > 0) {
+            return Err(Error::InvalidScript(format!("no placement for the {section} section")));
+        }
+    }
+
+    for placement in script.placements.iter() {
+        let region = script.region(&placement.region).ok_or_else(|| Error::UnknownRegion(placement.region.clone()))?;
+        let cursor = cursors.entry(region.name.as_str()).or_default();
+        if let Some(base) = placement.base {
+            if !(region.start..=region.end).contains(&base) {
+                return Err(Error::RegionOverflow(region.name.clone()));
+            }
+            *cursor = base as u32;
+        }
+
+        for (idx, (_, object)) in objects.iter().enumerate() {
+            addresses[idx][placement.section as usize] = *cursor as u16;
+            *cursor += object.size(placement.section) as u32;
+            if *cursor > region.end as u32 + 1 {
+                return Err(Error::RegionOverflow(region.name.clone()));
+            }
+        }
+    }
+
+    let mut ranges = objects.iter().enumerate()
+        .flat_map(|(idx, (_, object))| [Section::Text, Section::Data, Section::Bss].map(|section| {
+            let start = addresses[idx][section as usize] as u32;
+            (start, start + object.size(section) as u32)
+        }))
+        .filter(|(start, end)| start < end)
+        .collect::<Vec<_>>();
+    ranges.sort();
+    if let Some(pair) = ranges.windows(2).find(|pair| pair[1].0 < pair[0].1) {
+        return Err(Error::Overlap(pair[1].0 as u16));
+    }
+
+    Ok(addresses)
+}
+
+/// Links `objects`, each one with a name used in the map and errors, laying
+/// them out as told by `script`. Every duplicate and undefined symbol is
+/// reported at once.
+pub fn link(script : &Script, objects : &[(&str, &Object)]) -> Result<Output> {
+    let addresses = layout(script, objects)?;
+
+    let mut errors = vec![];
+    let mut symbols = vec![];
+    let mut globals = BTreeMap::new();
+    for (idx, (name, object)) in objects.iter().enumerate() {
+        for symbol in object.symbols.iter().filter(|symbol| symbol.binding != Binding::Extern) {
+            let base = symbol.section.map_or(0, |section| addresses[idx][section as usize]);
+            let address = base.wrapping_add(symbol.value);
+            if symbol.binding == Binding::Global && globals.insert(symbol.name.as_str(), address).is_some() {
+                errors.push(Error::DuplicateSymbol(symbol.name.clone()));
+            }
+            symbols.push(Linked { name: symbol.name.clone(), object: name.to_string(), binding: symbol.binding, address });
+        }
+    }
+    for (_, object) in objects.iter() {
+        for symbol in object.symbols.iter().filter(|symbol| symbol.binding == Binding::Extern) {
+            let error = Error::UndefinedSymbol(symbol.name.clone());
+            if !globals.contains_key(symbol.name.as_str()) && !errors.contains(&error) {
+                errors.push(error);
+            }
+        }
+    }
+    if !errors.is_empty() {
+        return Err(Error::Link(errors));
+    }
+
+    let mut sections = vec![];
+    let mut memory = vec![0; 0x10000];
+    let mut end = None;
+    for (idx, (name, object)) in objects.iter().enumerate() {
+        let mut bytes = [object.text.clone(), object.data.clone()];
+        for relocation in object.relocations.iter() {
+            let target = match relocation.target {
+                Target::Section(section) => addresses[idx][section as usize],
+                Target::Symbol(index) => match object.symbols.get(index as usize) {
+                    Some(symbol) if symbol.binding == Binding::Extern => globals[symbol.name.as_str()],
+                    Some(symbol) => symbol.section.map_or(0, |section| addresses[idx][section as usize]).wrapping_add(symbol.value),
+                    None => return Err(Error::InvalidObject(format!("invalid symbol index {index}"))),
+                },
+            };
+            let field = addresses[idx][relocation.section as usize].wrapping_add(relocation.offset);
+            let value = match relocation.kind {
+                RelocationKind::Absolute => target as i32 + relocation.addend,
+                RelocationKind::Relative => target as i32 + relocation.addend - field as i32,
+            };
+            if !(-0x8000..=0xFFFF).contains(&value) {
+                return Err(Error::InvalidValue(value.to_string()));
+            }
+
+            let offset = relocation.offset as usize;
+            match bytes.get_mut(relocation.section as usize).and_then(|bytes| bytes.get_mut(offset..offset + 2)) {
+                Some(bytes) => bytes.copy_from_slice(&(value as u16).to_le_bytes()),
+                None => return Err(Error::InvalidObject(format!("relocation out of bounds at {offset:#06X}"))),
+            }
+        }
+
+        for section in [Section::Text, Section::Data, Section::Bss] {
+            let address = addresses[idx][section as usize];
+            if let Some(bytes) = bytes.get(section as usize).filter(|bytes| !bytes.is_empty()) {
+                memory[address as usize..address as usize + bytes.len()].copy_from_slice(bytes);
+                end = end.max(Some(address as usize + bytes.len()));
+            }
+            sections.push(Placed { object: name.to_string(), section, address, size: object.size(section) });
+        }
+    }
+
+    let origin = script.regions.iter().map(|region| region.start).min().unwrap_or_default();
+    let image = memory[origin as usize..end.unwrap_or_default().max(origin as usize)].to_vec();
+    Ok(Output { origin, image, sections, symbols })
+}
+
+#[cfg(test)]
+mod test;
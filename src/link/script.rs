@@ -0,0 +1,109 @@
+use std::str::FromStr;
+
+use crate::{instruction::parse_literal, object::Section, utils::{Error, Result}};
+
+/// Range of addresses sections can be placed in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub name : String,
+    pub start : u16,
+
+    /// Last address of the region
+    pub end : u16,
+}
+
+/// Where the sections of a kind go, one object after the other
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Placement {
+    pub section : Section,
+    pub region : String,
+
+    /// Address of the first object's section, or right after whatever was
+    /// placed in the region before if `None`
+    pub base : Option<u16>,
+}
+
+/// Layout of a linked image.
+///
+/// One statement per line, `;` starting a comment:
+/// - `memory name, start, end`: region from `start` to `end`, both included
+/// - `section text|data|bss, region[, base]`: place every section of that kind in `region`
+///
+/// Sections are placed in the order of their `section` statements.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Script {
+    pub regions : Vec<Region>,
+    pub placements : Vec<Placement>,
+}
+
+impl Script {
+    pub fn region(&self, name : &str) -> Option<&Region> {
+        self.regions.iter().find(|region| region.name == name)
+    }
+}
+
+impl Default for Script {
+    /// Text, data and bss one after the other from address 0
+    fn default() -> Self {
+        let placement = |section| Placement { section, region: "memory".to_string(), base: None };
+        Self {
+            regions: vec![Region { name: "memory".to_string(), start: 0, end: 0xFFFF }],
+            placements: vec![placement(Section::Text), placement(Section::Data), placement(Section::Bss)],
+        }
+    }
+}
+
+fn address(s : &str) -> Result<u16> {
+    match parse_literal(s)? {
+        (value @ 0.., _) => Ok(value as u16),
+        _ => Err(Error::InvalidValue(s.to_string())),
+    }
+}
+
+impl FromStr for Script {
+    type Err = Error;
+
+    fn from_str(s : &str) -> Result<Self> {
+        let mut script = Script { regions: vec![], placements: vec![] };
+        for line in s.lines() {
+            let line = line.split(';').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let invalid = || Error::InvalidScript(line.to_string());
+            let (keyword, rest) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+            let args = rest.split(',').map(str::trim).collect::<Vec<_>>();
+            match (&*keyword.to_lowercase(), &args[..]) {
+                ("memory", [name, start, end]) => {
+                    let region = Region { name: name.to_string(), start: address(start)?, end: address(end)? };
+                    if region.start > region.end || script.region(name).is_some() {
+                        return Err(invalid());
+                    }
+                    if let Some(other) = script.regions.iter().find(|other| other.start <= region.end && region.start <= other.end) {
+                        return Err(Error::Overlap(region.start.max(other.start)));
+                    }
+                    script.regions.push(region);
+                },
+                ("section", [section, region, base @ ..]) if base.len() <= 1 => {
+                    let section = match *section {
+                        "text" => Section::Text,
+                        "data" => Section::Data,
+                        "bss" => Section::Bss,
+                        _ => return Err(invalid()),
+                    };
+                    if script.region(region).is_none() {
+                        return Err(Error::UnknownRegion(region.to_string()));
+                    }
+                    if script.placements.iter().any(|placement| placement.section == section) {
+                        return Err(invalid());
+                    }
+                    let base = base.first().map(|base| address(base)).transpose()?;
+                    script.placements.push(Placement { section, region: region.to_string(), base });
+                },
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(script)
+    }
+}
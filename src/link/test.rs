@@ -0,0 +1,119 @@
+use super::*;
+use crate::{asm::assemble_object, Instruction, Register, Value};
+
+fn object(source : &str) -> Object {
+    assemble_object("test.s", source).unwrap()
+}
+
+fn main() -> Object {
+    object("
+        .global main
+        .extern print, counter
+        main: mov message, r0
+            call print
+            mov counter, r1
+            ret
+        .data
+        message: .asciz \"hi\"
+    ")
+}
+
+fn lib() -> Object {
+    object("
+        .global print, counter
+        print: nop
+            mov print - ., r2
+            ret
+        .bss
+        counter: .fill 2
+    ")
+}
+
+#[test]
+fn default_script() {
+    let (main, lib) = (main(), lib());
+    let output = link(&Script::default(), &[("main.o", &main), ("lib.o", &lib)]).unwrap();
+
+    // main text at 0, lib text at 0x0E, main data at 0x16, lib bss at 0x19
+    let text = [
+        Instruction::movc2r(Value::word(0x0016), Register::r0()).unwrap(),
+        Instruction::callc(Value::word(0x000E)).unwrap(),
+        Instruction::movc2r(Value::word(0x0019), Register::r1()).unwrap(),
+        Instruction::ret(),
+        Instruction::nop(),
+        Instruction::movc2r(Value::word(0xFFFE), Register::r2()).unwrap(),
+        Instruction::ret(),
+    ];
+    let mut image = text.iter().flat_map(Instruction::compile).collect::<Vec<_>>();
+    image.extend(b"hi\0");
+
+    assert_eq!(output.origin, 0);
+    assert_eq!(output.image, image);
+    assert_eq!(output.symbol("print"), Some(0x000E));
+    assert_eq!(output.symbol("counter"), Some(0x0019));
+    assert_eq!(output.symbol("message"), None);
+}
+
+#[test]
+fn script() {
+    let script = "
+        ; code in rom, everything else in ram
+        memory rom, 0x0000, 0x00FF
+        memory ram, 0x8000, 0xFFFF
+        section text, rom
+        section data, ram
+        section bss, ram, 0x9000
+    ".parse::<Script>().unwrap();
+    let (main, lib) = (main(), lib());
+    let output = link(&script, &[("main.o", &main), ("lib.o", &lib)]).unwrap();
+
+    assert_eq!(output.symbol("counter"), Some(0x9000));
+    assert_eq!(&output.image[2..4], &[0x00, 0x80]);
+    assert_eq!(&output.image[0x8000..], b"hi\0");
+    assert_eq!(output.map(), "\
+Sections:
+  0x0000  0x000E  text  main.o
+  0x000E  0x0008  text  lib.o
+  0x8000  0x0003  data  main.o
+  0x8003  0x0000  data  lib.o
+  0x9000  0x0000  bss   main.o
+  0x9000  0x0002  bss   lib.o
+
+Symbols:
+  0x0000  global  main  main.o
+  0x000E  global  print  lib.o
+  0x8000  local   message  main.o
+  0x9000  global  counter  lib.o
+");
+}
+
+#[test]
+fn script_errors() {
+    assert_eq!("memory rom 0, 1".parse::<Script>(), Err(Error::InvalidScript("memory rom 0, 1".to_string())));
+    assert_eq!("section text, rom".parse::<Script>(), Err(Error::UnknownRegion("rom".to_string())));
+    assert_eq!("memory a, 0, 0x10\nmemory b, 0x08, 0x20".parse::<Script>(), Err(Error::Overlap(0x08)));
+    assert_eq!("memory a, 0x10, 0".parse::<Script>(), Err(Error::InvalidScript("memory a, 0x10, 0".to_string())));
+
+    let (main, lib) = (main(), lib());
+    let small = "memory rom, 0, 0x0F\nsection text, rom\nsection data, rom\nsection bss, rom".parse::<Script>().unwrap();
+    assert_eq!(link(&small, &[("main.o", &main), ("lib.o", &lib)]), Err(Error::RegionOverflow("rom".to_string())));
+
+    let no_data = "memory rom, 0, 0xFF\nsection text, rom".parse::<Script>().unwrap();
+    assert_eq!(link(&no_data, &[("main.o", &main)]), Err(Error::InvalidScript("no placement for the data section".to_string())));
+
+    let overlap = "memory m, 0, 0xFF\nsection text, m\nsection data, m, 0x04".parse::<Script>().unwrap();
+    assert_eq!(link(&overlap, &[("main.o", &main)]), Err(Error::Overlap(0x04)));
+}
+
+#[test]
+fn symbol_errors() {
+    let (main, lib) = (main(), lib());
+    assert_eq!(
+        link(&Script::default(), &[("main.o", &main)]),
+        Err(Error::Link(vec![Error::UndefinedSymbol("counter".to_string()), Error::UndefinedSymbol("print".to_string())]))
+    );
+    assert_eq!(
+        link(&Script::default(), &[("main.o", &main), ("lib.o", &lib), ("again.o", &lib)]),
+        Err(Error::Link(vec![Error::DuplicateSymbol("counter".to_string()), Error::DuplicateSymbol("print".to_string())]))
+    );
+}
@@ -0,0 +1,369 @@
+//! Relocatable object files.
+//!
+//! An object holds a text, data and bss section, a symbol table and the
+//! relocations the linker has to apply once every section has an address.
+//! Every multi-byte value is little endian:
+//!
+//! ```text
+//! magic "SMPO", version: u16, text size: u16, data size: u16, bss size: u16,
+//! symbol count: u16, relocation count: u16, text bytes, data bytes,
+//! symbols:     binding: u8, section: u8, value: u16, name length: u8, name bytes
+//! relocations: section: u8, kind: u8, offset: u16, target: u8, index: u16, addend: i32
+//! ```
+//!
+//! Sections are encoded as 1 (text), 2 (data), 3 (bss), or 0 for none.
+
+use std::io::{Read, Write};
+
+use crate::utils::{Error, Reader, Result};
+
+const MAGIC : &[u8; 4] = b"SMPO";
+pub const VERSION : u16 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Section {
+    /// Code
+    Text,
+
+    /// Initialized data
+    Data,
+
+    /// Zero initialized data, which takes no space in the object
+    Bss,
+}
+
+impl std::fmt::Display for Section {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            Self::Text => "text",
+            Self::Data => "data",
+            Self::Bss => "bss",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Binding {
+    /// Only visible inside its object
+    Local,
+
+    /// Visible to every object
+    Global,
+
+    /// Defined by another object
+    Extern,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name : String,
+    pub binding : Binding,
+
+    /// Section the value is an offset into, or `None` for absolute values and externs
+    pub section : Option<Section>,
+    pub value : u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocationKind {
+    /// The 16-bit field becomes `S + A`, like the word immediates of `MovC2R` and `CallC`
+    Absolute,
+
+    /// The 16-bit field becomes `S + A - P`, `P` being the address of the field,
+    /// like a displacement loaded into a register for `Jmp` and the conditional jumps
+    Relative,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    /// Start of a section of the same object
+    Section(Section),
+
+    /// Index into the symbol table
+    Symbol(u16),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    /// Section holding the field to patch
+    pub section : Section,
+
+    /// Offset of the field inside `section`
+    pub offset : u16,
+    pub kind : RelocationKind,
+    pub target : Target,
+    pub addend : i32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Object {
+    pub text : Vec<u8>,
+    pub data : Vec<u8>,
+
+    /// Size of the bss section
+    pub bss : u16,
+    pub symbols : Vec<Symbol>,
+    pub relocations : Vec<Relocation>,
+}
+
+fn section_code(section : Option<Section>) -> u8 {
+    match section {
+        None => 0,
+        Some(Section::Text) => 1,
+        Some(Section::Data) => 2,
+        Some(Section::Bss) => 3,
+    }
+}
+
+fn section_from(code : u8) -> Result<Option<Section>> {
+    match code {
+        0 => Ok(None),
+        1 => Ok(Some(Section::Text)),
+        2 => Ok(Some(Section::Data)),
+        3 => Ok(Some(Section::Bss)),
+        _ => Err(Error::InvalidObject(format!("invalid section {code}"))),
+    }
+}
+
+/// `len` as the type of the field that holds it, if it fits
+fn length<T : TryFrom<usize>>(len : usize, what : &str) -> Result<T> {
+    T::try_from(len).map_err(|_| Error::InvalidObject(format!("{what} too long: {len}")))
+}
+
+impl Object {
+    /// Size of `section`
+    pub fn size(&self, section : Section) -> u16 {
+        match section {
+            Section::Text => self.text.len() as u16,
+            Section::Data => self.data.len() as u16,
+            Section::Bss => self.bss,
+        }
+    }
+
+    pub fn symbol(&self, name : &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    /// Fails if a section, table or name is too long for its field
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut res = MAGIC.to_vec();
+        for value in [
+            VERSION,
+            length(self.text.len(), "text")?,
+            length(self.data.len(), "data")?,
+            self.bss,
+            length(self.symbols.len(), "symbol table")?,
+            length(self.relocations.len(), "relocation table")?,
+        ] {
+            res.extend(value.to_le_bytes());
+        }
+        res.extend(&self.text);
+        res.extend(&self.data);
+
+        for (idx, symbol) in self.symbols.iter().enumerate() {
+            let binding = match symbol.binding {
+                Binding::Local => 0,
+                Binding::Global => 1,
+                Binding::Extern => 2,
+            };
+            res.extend([binding, section_code(symbol.section)]);
+            res.extend(symbol.value.to_le_bytes());
+            res.push(length(symbol.name.len(), &format!("name of symbol {idx}"))?);
+            res.extend(symbol.name.as_bytes());
+        }
+
+        for relocation in self.relocations.iter() {
+            let kind = match relocation.kind {
+                RelocationKind::Absolute => 0,
+                RelocationKind::Relative => 1,
+            };
+            res.extend([section_code(Some(relocation.section)), kind]);
+            res.extend(relocation.offset.to_le_bytes());
+            let (target, index) = match relocation.target {
+                Target::Section(section) => (0, section_code(Some(section)) as u16),
+                Target::Symbol(index) => (1, index),
+            };
+            res.push(target);
+            res.extend(index.to_le_bytes());
+            res.extend(relocation.addend.to_le_bytes());
+        }
+
+        Ok(res)
+    }
+
+    pub fn from_bytes(bytes : &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(bytes, Error::InvalidObject);
+        if reader.bytes(4)? != MAGIC {
+            return Err(Error::InvalidObject("bad magic".to_string()));
+        }
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        let text_len = reader.u16()?;
+        let data_len = reader.u16()?;
+        let bss = reader.u16()?;
+        let symbol_count = reader.u16()?;
+        let relocation_count = reader.u16()?;
+        let text = reader.bytes(text_len as usize)?.to_vec();
+        let data = reader.bytes(data_len as usize)?.to_vec();
+
+        let mut symbols = vec![];
+        for _ in 0..symbol_count {
+            let binding = match reader.u8()? {
+                0 => Binding::Local,
+                1 => Binding::Global,
+                2 => Binding::Extern,
+                binding => return Err(Error::InvalidObject(format!("invalid binding {binding}"))),
+            };
+            let section = section_from(reader.u8()?)?;
+            let value = reader.u16()?;
+            let len = reader.u8()?;
+            let name = String::from_utf8(reader.bytes(len as usize)?.to_vec())
+                .map_err(|_| Error::InvalidObject("invalid symbol name".to_string()))?;
+            symbols.push(Symbol { name, binding, section, value });
+        }
+
+        let mut relocations = vec![];
+        for _ in 0..relocation_count {
+            let section = section_from(reader.u8()?)?.ok_or(Error::InvalidObject("relocation without section".to_string()))?;
+            let kind = match reader.u8()? {
+                0 => RelocationKind::Absolute,
+                1 => RelocationKind::Relative,
+                kind => return Err(Error::InvalidObject(format!("invalid relocation kind {kind}"))),
+            };
+            let offset = reader.u16()?;
+            let target = match (reader.u8()?, reader.u16()?) {
+                (0, code) => match u8::try_from(code).map(section_from) {
+                    Ok(Ok(Some(section))) => Target::Section(section),
+                    _ => return Err(Error::InvalidObject(format!("invalid section {code}"))),
+                },
+                (1, index) if index < symbol_count => Target::Symbol(index),
+                (1, index) => return Err(Error::InvalidObject(format!("invalid symbol index {index}"))),
+                (target, _) => return Err(Error::InvalidObject(format!("invalid relocation target {target}"))),
+            };
+            let addend = reader.i32()?;
+
+            let len = match section {
+                Section::Text => text.len(),
+                Section::Data => data.len(),
+                Section::Bss => 0,
+            };
+            if offset as usize + 2 > len {
+                return Err(Error::InvalidObject(format!("relocation out of bounds at {offset:#06X}")));
+            }
+            relocations.push(Relocation { section, offset, kind, target, addend });
+        }
+
+        if !reader.is_empty() {
+            return Err(Error::InvalidObject("trailing bytes".to_string()));
+        }
+
+        Ok(Self { text, data, bss, symbols, relocations })
+    }
+
+    pub fn write(&self, mut writer : impl Write) -> Result<()> {
+        Ok(writer.write_all(&self.to_bytes()?)?)
+    }
+
+    pub fn read(mut reader : impl Read) -> Result<Self> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes)?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn object() -> Object {
+        Object {
+            text: vec![0x02, 0x60, 0x00, 0x00, 0x31, 0x00, 0x00, 0x00, 0x33, 0x00],
+            data: vec![0x00, 0x00, 0xAA],
+            bss: 16,
+            symbols: vec![
+                Symbol { name: "main".to_string(), binding: Binding::Global, section: Some(Section::Text), value: 0 },
+                Symbol { name: "table".to_string(), binding: Binding::Local, section: Some(Section::Data), value: 0 },
+                Symbol { name: "SIZE".to_string(), binding: Binding::Local, section: None, value: 3 },
+                Symbol { name: "print".to_string(), binding: Binding::Extern, section: None, value: 0 },
+            ],
+            relocations: vec![
+                Relocation { section: Section::Text, offset: 2, kind: RelocationKind::Absolute, target: Target::Section(Section::Data), addend: 0 },
+                Relocation { section: Section::Text, offset: 6, kind: RelocationKind::Absolute, target: Target::Symbol(3), addend: 0 },
+                Relocation { section: Section::Data, offset: 0, kind: RelocationKind::Relative, target: Target::Symbol(3), addend: -4 },
+            ],
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let object = object();
+        assert_eq!(Object::from_bytes(&object.to_bytes().unwrap()), Ok(object.clone()));
+
+        let mut bytes = vec![];
+        object.write(&mut bytes).unwrap();
+        assert_eq!(Object::read(&bytes[..]), Ok(object));
+    }
+
+    #[test]
+    fn header() {
+        let bytes = object().to_bytes().unwrap();
+        assert_eq!(&bytes[..4], b"SMPO");
+        assert_eq!(&bytes[4..6], &VERSION.to_le_bytes());
+        assert_eq!(Object::from_bytes(&Object::default().to_bytes().unwrap()), Ok(Object::default()));
+    }
+
+    #[test]
+    fn lookups() {
+        let object = object();
+        assert_eq!(object.size(Section::Text), 10);
+        assert_eq!(object.size(Section::Data), 3);
+        assert_eq!(object.size(Section::Bss), 16);
+        assert_eq!(object.symbol("table").map(|s| s.section), Some(Some(Section::Data)));
+        assert_eq!(object.symbol("nothing"), None);
+    }
+
+    #[test]
+    fn invalid() {
+        let bytes = object().to_bytes().unwrap();
+
+        let mut bad = bytes.clone();
+        bad[0] = b'X';
+        assert_eq!(Object::from_bytes(&bad), Err(Error::InvalidObject("bad magic".to_string())));
+
+        let mut bad = bytes.clone();
+        bad[4] = 2;
+        assert_eq!(Object::from_bytes(&bad), Err(Error::UnsupportedVersion(2)));
+
+        assert_eq!(Object::from_bytes(&bytes[..bytes.len() - 1]), Err(Error::InvalidObject("truncated".to_string())));
+
+        let mut bad = bytes.clone();
+        bad.push(0);
+        assert_eq!(Object::from_bytes(&bad), Err(Error::InvalidObject("trailing bytes".to_string())));
+
+        let mut object = object();
+        object.relocations[0].offset = 9;
+        assert_eq!(Object::from_bytes(&object.to_bytes().unwrap()), Err(Error::InvalidObject("relocation out of bounds at 0x0009".to_string())));
+
+        let mut object = self::object();
+        object.relocations[0].target = Target::Symbol(4);
+        assert_eq!(Object::from_bytes(&object.to_bytes().unwrap()), Err(Error::InvalidObject("invalid symbol index 4".to_string())));
+    }
+
+    #[test]
+    fn too_long() {
+        let mut object = object();
+        object.symbols[1].name = "x".repeat(300);
+        assert_eq!(object.to_bytes(), Err(Error::InvalidObject("name of symbol 1 too long: 300".to_string())));
+        assert_eq!(object.write(vec![]), Err(Error::InvalidObject("name of symbol 1 too long: 300".to_string())));
+        object.symbols[1].name = "x".repeat(255);
+        assert_eq!(Object::from_bytes(&object.to_bytes().unwrap()), Ok(object));
+
+        let object = Object { text: vec![0; 0x10000], ..Object::default() };
+        assert_eq!(object.to_bytes(), Err(Error::InvalidObject("text too long: 65536".to_string())));
+        let object = Object { relocations: vec![self::object().relocations[0].clone(); 0x10000], ..Object::default() };
+        assert_eq!(object.to_bytes(), Err(Error::InvalidObject("relocation table too long: 65536".to_string())));
+    }
+}
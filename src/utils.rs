@@ -1,4 +1,4 @@
-use crate::Instruction;
+use crate::{asm::Diagnostics, Instruction};
 
 #[derive(thiserror::Error, Debug, Clone, PartialEq)]
 pub enum Error {
@@ -10,5 +10,174 @@ pub enum Error {
 
     #[error("invalid register: {0:?}")]
     InvalidRegister(String),
+
+    #[error("unknown opcode: {0:#04X}")]
+    UnknownOpcode(u8),
+
+    #[error("truncated instruction, expected {0} bytes but only {1} are available")]
+    TruncatedInstruction(usize, usize),
+
+    #[error("invalid register nibble {1:#X} for opcode {0:#04X}")]
+    InvalidRegisterNibble(u8, u8),
+
+    #[error("non-canonical instruction encoding: {0:02X?}")]
+    NonCanonicalEncoding(Vec<u8>),
+
+    #[error("unknown mnemonic: {0:?}")]
+    UnknownMnemonic(String),
+
+    #[error("invalid operands: {0:?}")]
+    InvalidOperands(String),
+
+    #[error("invalid value: {0:?}")]
+    InvalidValue(String),
+
+    #[error("invalid label: {0:?}")]
+    InvalidLabel(String),
+
+    #[error("duplicate symbol: {0:?}")]
+    DuplicateSymbol(String),
+
+    #[error("undefined symbol: {0:?}")]
+    UndefinedSymbol(String),
+
+    #[error("address out of range: {0:#X}")]
+    AddressOutOfRange(u32),
+
+    #[error("invalid expression: {0:?}")]
+    InvalidExpression(String),
+
+    #[error("unknown directive: {0:?}")]
+    UnknownDirective(String),
+
+    #[error("invalid string: {0}")]
+    InvalidString(String),
+
+    #[error("origin {0:#X} is behind the current address")]
+    InvalidOrigin(u32),
+
+    #[error("invalid macro: {0:?}")]
+    InvalidMacro(String),
+
+    #[error("undefined macro parameter: {0:?}")]
+    UndefinedParameter(String),
+
+    #[error("macro expansion too deep: {0:?}")]
+    MacroRecursion(String),
+
+    #[error("unterminated block: {0:?}")]
+    UnterminatedBlock(String),
+
+    #[error("unexpected end of block: {0:?}")]
+    UnexpectedEnd(String),
+
+    #[error("value can't be relocated: {0:?}")]
+    NotRelocatable(String),
+
+    #[error("not allowed in the bss section: {0:?}")]
+    InvalidSection(String),
+
+    #[error("{0}")]
+    Assembly(Diagnostics),
+
+    #[error("invalid object: {0}")]
+    InvalidObject(String),
+
+    #[error("unsupported object version: {0}")]
+    UnsupportedVersion(u16),
+
+    #[error("invalid linker script: {0:?}")]
+    InvalidScript(String),
+
+    #[error("unknown memory region: {0:?}")]
+    UnknownRegion(String),
+
+    #[error("memory region {0:?} is full")]
+    RegionOverflow(String),
+
+    #[error("overlapping ranges at {0:#06X}")]
+    Overlap(u16),
+
+    #[error("{}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join("\n"))]
+    Link(Vec<Error>),
+
+    #[error("stack underflow, RSH is {0:#06X}")]
+    StackUnderflow(u16),
+
+    #[error("unmapped address {0:#06X}")]
+    Unmapped(u16),
+
+    #[error("write to read-only address {0:#06X}")]
+    ReadOnly(u16),
+
+    #[error("device of {1:#X} bytes mapped to {0:#X} addresses")]
+    SizeMismatch(u32, usize),
+
+    #[error("io error: {0}")]
+    Io(String),
+
+    #[error("invalid trace: {0}")]
+    InvalidTrace(String),
+
+    #[error("trace diverges at step {0}: {1}")]
+    TraceMismatch(usize, String),
+
+    #[error("invalid snapshot: {0}")]
+    InvalidSnapshot(String),
 }
 pub type Result<T> = std::result::Result<T, Error>;
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err.to_string())
+    }
+}
+
+/// Cursor over little endian binary data, failing with `invalid` once it's
+/// truncated
+pub(crate) struct Reader<'a> {
+    bytes : &'a [u8],
+    invalid : fn(String) -> Error,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes : &'a [u8], invalid : fn(String) -> Error) -> Self {
+        Self { bytes, invalid }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn bytes(&mut self, len : usize) -> Result<&'a [u8]> {
+        if self.bytes.len() < len {
+            return Err((self.invalid)("truncated".to_string()));
+        }
+        let (res, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(res)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        let bytes = self.bytes(8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn i32(&mut self) -> Result<i32> {
+        Ok(self.u32()? as i32)
+    }
+}
@@ -39,6 +39,15 @@ impl std::fmt::Debug for Value {
     }
 }
 
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Width::Byte => write!(f, "{:#04X}", self.1),
+            Width::Word => write!(f, "{:#06X}", self.1),
+        }
+    }
+}
+
 impl From<u8> for Value {
     fn from(value: u8) -> Self {
         Self::byte(value)